@@ -0,0 +1,227 @@
+// Copyright (C) 2020 Peter Mezei
+//
+// This file is part of Gardenzilla.
+//
+// Gardenzilla is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 2 of the License, or
+// (at your option) any later version.
+//
+// Gardenzilla is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
+
+use crate::auth::hash_secret;
+use crate::prelude::ServiceError::*;
+use crate::prelude::*;
+use crate::role;
+use chrono::prelude::*;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+// Every key starts with it, so leaked keys are easy to find
+const KEY_PREFIX: &str = "gzk";
+// Last use is updated only if the recorded one is older than this, in minutes
+const LAST_USED_PRECISION: i64 = 5;
+
+/// # API key
+/// Long-lived credential with limited scopes.
+/// The key itself is shown only once at creation,
+/// only the hash of its secret part is stored.
+/// Key format is gzk_<key id>_<secret>.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ApiKey {
+    id: String,
+    name: String,
+    secret_hash: String,
+    // Permissions granted by the key, wildcards allowed
+    scopes: Vec<String>,
+    date_created: DateTime<Utc>,
+    created_by: String,
+    // None means it never expires
+    expires_at: Option<DateTime<Utc>>,
+    revoked_at: Option<DateTime<Utc>>,
+    // Recorded with LAST_USED_PRECISION, to spare storage writes
+    #[serde(default)]
+    last_used: Option<DateTime<Utc>>,
+}
+
+impl ApiKey {
+    /// Create a key, returns the key and its full key string
+    pub fn generate(
+        name: String,
+        mut scopes: Vec<String>,
+        expires_at: Option<DateTime<Utc>>,
+        created_by: String,
+    ) -> ServiceResult<(Self, String)> {
+        if name.is_empty() {
+            return Err(BadRequest("A kulcs neve nem lehet üres".into()));
+        }
+        if scopes.is_empty() {
+            return Err(BadRequest(
+                "A kulcsnak legalább egy jogosultság kell".into(),
+            ));
+        }
+        for scope in &scopes {
+            role::validate_permission(scope)?;
+        }
+        scopes.sort();
+        scopes.dedup();
+        if let Some(expires_at) = expires_at {
+            if expires_at <= Utc::now() {
+                return Err(BadRequest("A lejárat nem lehet a múltban".into()));
+            }
+        }
+        let mut rng = rand::thread_rng();
+        let id = hex::encode(rng.gen::<[u8; 8]>());
+        let secret = hex::encode(rng.gen::<[u8; 32]>());
+        let key = format!("{}_{}_{}", KEY_PREFIX, id, secret);
+        let api_key = ApiKey {
+            id,
+            name,
+            secret_hash: hash_secret(&secret),
+            scopes,
+            date_created: Utc::now(),
+            created_by,
+            expires_at,
+            revoked_at: None,
+            last_used: None,
+        };
+        Ok((api_key, key))
+    }
+    pub fn get_id(&self) -> &str {
+        &self.id
+    }
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+    pub fn get_scopes(&self) -> &Vec<String> {
+        &self.scopes
+    }
+    pub fn get_date_created(&self) -> DateTime<Utc> {
+        self.date_created
+    }
+    pub fn get_created_by(&self) -> &str {
+        &self.created_by
+    }
+    pub fn get_expires_at(&self) -> Option<DateTime<Utc>> {
+        self.expires_at
+    }
+    pub fn get_revoked_at(&self) -> Option<DateTime<Utc>> {
+        self.revoked_at
+    }
+    pub fn get_last_used(&self) -> Option<DateTime<Utc>> {
+        self.last_used
+    }
+    /// Record a use of the key.
+    /// Returns true if the recorded last use has changed.
+    pub fn touch(&mut self, date: DateTime<Utc>) -> bool {
+        let outdated = self.last_used.is_none_or(|last_used| {
+            date - last_used >= chrono::Duration::minutes(LAST_USED_PRECISION)
+        });
+        if outdated {
+            self.last_used = Some(date);
+        }
+        outdated
+    }
+    /// Not revoked and not expired
+    pub fn is_active(&self) -> bool {
+        self.revoked_at.is_none() && self.expires_at.is_none_or(|e| e > Utc::now())
+    }
+    pub fn revoke(&mut self) -> ServiceResult<()> {
+        if self.revoked_at.is_some() {
+            return Err(FailedPrecondition("A kulcs már vissza van vonva".into()));
+        }
+        self.revoked_at = Some(Utc::now());
+        Ok(())
+    }
+    /// Does the secret part of a key string belong to this key
+    pub fn verify(&self, secret: &str) -> bool {
+        hash_secret(secret) == self.secret_hash
+    }
+    /// Does the key grant the permission
+    pub fn grants(&self, permission: &str) -> bool {
+        self.scopes
+            .iter()
+            .any(|scope| role::permission_matches(scope, permission))
+    }
+}
+
+/// Split a key string into key ID and secret
+pub fn parse(key: &str) -> Option<(&str, &str)> {
+    let mut parts = key.splitn(3, '_');
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(KEY_PREFIX), Some(id), Some(secret)) if !id.is_empty() && !secret.is_empty() => {
+            Some((id, secret))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generate() {
+        let (key, key_string) = ApiKey::generate(
+            "invoice".into(),
+            vec!["user:read".into()],
+            None,
+            "admin".into(),
+        )
+        .unwrap();
+        let (id, secret) = parse(&key_string).unwrap();
+        assert_eq!(id, key.get_id());
+        assert_eq!(key.verify(secret), true);
+        assert_eq!(key.verify("wrong"), false);
+        // Only the hash is stored
+        assert_eq!(serde_yaml::to_string(&key).unwrap().contains(secret), false);
+        assert_eq!(key.grants("user:read"), true);
+        assert_eq!(key.grants("user:write"), false);
+    }
+
+    #[test]
+    fn test_validation() {
+        assert_eq!(
+            ApiKey::generate("x".into(), vec![], None, "".into()).is_err(),
+            true
+        ); // should be err
+        assert_eq!(
+            ApiKey::generate("x".into(), vec!["bad".into()], None, "".into()).is_err(),
+            true
+        ); // should be err
+        let past = Utc::now() - chrono::Duration::hours(1);
+        assert_eq!(
+            ApiKey::generate("x".into(), vec!["user:read".into()], Some(past), "".into()).is_err(),
+            true
+        ); // should be err
+        assert_eq!(parse("gzk_only"), None);
+        assert_eq!(parse("abc_1_2"), None);
+    }
+
+    #[test]
+    fn test_revoke() {
+        let (mut key, _) =
+            ApiKey::generate("x".into(), vec!["user:*".into()], None, "".into()).unwrap();
+        assert_eq!(key.is_active(), true);
+        assert_eq!(key.revoke().is_ok(), true); // should be ok
+        assert_eq!(key.is_active(), false);
+        assert_eq!(key.revoke().is_err(), true); // should be err
+    }
+
+    #[test]
+    fn test_touch() {
+        let (mut key, _) =
+            ApiKey::generate("x".into(), vec!["user:*".into()], None, "".into()).unwrap();
+        let now = Utc::now();
+        assert_eq!(key.touch(now), true);
+        assert_eq!(key.touch(now + chrono::Duration::minutes(1)), false);
+        assert_eq!(key.get_last_used(), Some(now));
+        assert_eq!(key.touch(now + chrono::Duration::minutes(10)), true);
+    }
+}
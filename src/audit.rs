@@ -0,0 +1,698 @@
+// Copyright (C) 2020 Peter Mezei
+//
+// This file is part of Gardenzilla.
+//
+// Gardenzilla is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 2 of the License, or
+// (at your option) any later version.
+//
+// Gardenzilla is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
+
+use crate::prelude::ServiceError::*;
+use crate::prelude::*;
+use chrono::prelude::*;
+use futures::FutureExt;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use tokio::sync::{mpsc, oneshot};
+
+// Previous hash of the very first entry
+const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    Login,
+    LoginFailed,
+    LoginCodeRequested,
+    PasswordChanged,
+    PasswordResetRequested,
+    PasswordReset,
+    UserCreated,
+    UserUpdated,
+    UserDeleted,
+    UserInvited,
+    InvitationAccepted,
+    UserRegistered,
+    EmailVerified,
+    RegistrationApproved,
+    RegistrationRejected,
+    // Sensitive user data was read
+    UserRead,
+    RoleChanged,
+    ServiceAccountChanged,
+    WebhookChanged,
+}
+
+impl AuditAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditAction::Login => "login",
+            AuditAction::LoginFailed => "login_failed",
+            AuditAction::LoginCodeRequested => "login_code_requested",
+            AuditAction::PasswordChanged => "password_changed",
+            AuditAction::PasswordResetRequested => "password_reset_requested",
+            AuditAction::PasswordReset => "password_reset",
+            AuditAction::UserCreated => "user_created",
+            AuditAction::UserUpdated => "user_updated",
+            AuditAction::UserDeleted => "user_deleted",
+            AuditAction::UserInvited => "user_invited",
+            AuditAction::InvitationAccepted => "invitation_accepted",
+            AuditAction::UserRegistered => "user_registered",
+            AuditAction::EmailVerified => "email_verified",
+            AuditAction::RegistrationApproved => "registration_approved",
+            AuditAction::RegistrationRejected => "registration_rejected",
+            AuditAction::UserRead => "user_read",
+            AuditAction::RoleChanged => "role_changed",
+            AuditAction::ServiceAccountChanged => "service_account_changed",
+            AuditAction::WebhookChanged => "webhook_changed",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct AuditEntry {
+    pub seq: u64,
+    pub date: DateTime<Utc>,
+    pub actor: String,
+    pub action: AuditAction,
+    // User the action was made on, empty if none
+    pub user_id: String,
+    pub details: String,
+    pub prev_hash: String,
+    // sha256 of prev_hash and every other field
+    pub hash: String,
+}
+
+impl AuditEntry {
+    fn compute_hash(&self) -> ServiceResult<String> {
+        let content = serde_json::to_string(&(
+            self.seq,
+            self.date,
+            &self.actor,
+            self.action,
+            &self.user_id,
+            &self.details,
+        ))
+        .map_err(|e| InternalError(format!("Error while serializing audit entry: {}", e)))?;
+        let mut hasher = Sha256::new();
+        hasher.update(self.prev_hash.as_bytes());
+        hasher.update(content.as_bytes());
+        Ok(hex::encode(hasher.finalize()))
+    }
+}
+
+#[derive(Default, Debug)]
+pub struct AuditFilter {
+    pub from: Option<DateTime<Utc>>,
+    pub till: Option<DateTime<Utc>>,
+    pub actor: Option<String>,
+    pub user_id: Option<String>,
+    pub action: Option<String>,
+    // 0 means no limit
+    pub limit: usize,
+}
+
+impl AuditFilter {
+    fn matches(&self, entry: &AuditEntry) -> bool {
+        self.from.is_none_or(|from| entry.date >= from)
+            && self.till.is_none_or(|till| entry.date < till)
+            && self.actor.as_ref().is_none_or(|a| *a == entry.actor)
+            && self.user_id.as_ref().is_none_or(|u| *u == entry.user_id)
+            && self
+                .action
+                .as_ref()
+                .is_none_or(|a| a == entry.action.as_str())
+    }
+}
+
+/// # Head
+/// Sequence number and hash of the last entry. It is kept next to the
+/// log file, so cutting entries off the end of the log is detected too.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct AuditHead {
+    pub seq: u64,
+    pub hash: String,
+}
+
+fn head_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(".head");
+    PathBuf::from(name)
+}
+
+fn read_head(path: &Path) -> ServiceResult<Option<AuditHead>> {
+    match std::fs::read_to_string(head_path(path)) {
+        Ok(content) => serde_json::from_str(&content)
+            .map(Some)
+            .map_err(|e| InternalError(format!("Malformed audit log head: {}", e))),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// Replaced at once, so a crash leaves the old or the new head
+fn write_head(path: &Path, head: &AuditHead) -> ServiceResult<()> {
+    let head_path = head_path(path);
+    let temp = head_path.with_extension("head.tmp");
+    let content = serde_json::to_string(head)
+        .map_err(|e| InternalError(format!("Error while serializing audit log head: {}", e)))?;
+    std::fs::write(&temp, content)?;
+    std::fs::rename(&temp, &head_path)?;
+    Ok(())
+}
+
+/// # Audit log
+/// Append-only log file, one JSON entry per line.
+/// Every entry contains the hash of the previous one,
+/// so any modification or removal breaks the chain.
+pub struct AuditLog {
+    path: PathBuf,
+    file: File,
+    // Length of the complete entries in the file
+    len: u64,
+    seq: u64,
+    last_hash: String,
+    // Offset of every entry, with the latest date up to it,
+    // so queries can skip the entries before their time range
+    index: Vec<(DateTime<Utc>, u64)>,
+}
+
+impl AuditLog {
+    /// Open or create the log file, and continue its chain.
+    /// An incomplete last entry, left by a crash while it was
+    /// written, is dropped. A log not reaching its head is refused.
+    pub fn open(path: impl AsRef<Path>) -> ServiceResult<Self> {
+        let path = path.as_ref().to_path_buf();
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let head = read_head(&path)?;
+        let mut log = Self {
+            path,
+            file,
+            len: 0,
+            seq: 0,
+            last_hash: GENESIS_HASH.to_string(),
+            index: Vec::new(),
+        };
+        let mut reader = BufReader::new(File::open(&log.path)?);
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            let read = reader.read_until(b'\n', &mut line)?;
+            if read == 0 {
+                break;
+            }
+            let entry = match line.last() == Some(&b'\n') {
+                true => serde_json::from_slice::<AuditEntry>(&line).ok(),
+                false => None,
+            };
+            match entry {
+                Some(entry) => log.push(&entry, read as u64),
+                None if reader.fill_buf()?.is_empty() => {
+                    eprintln!(
+                        "Dropping the incomplete last audit log entry at byte {}",
+                        log.len
+                    );
+                    log.file.set_len(log.len)?;
+                    break;
+                }
+                None => {
+                    return Err(InternalError(format!(
+                        "Malformed audit entry at byte {}",
+                        log.len
+                    )))
+                }
+            }
+        }
+        if let Some(head) = head {
+            if log.seq < head.seq {
+                return Err(InternalError(format!(
+                    "Audit log is truncated, it ends at entry {} but its head is {}",
+                    log.seq, head.seq
+                )));
+            }
+        }
+        Ok(log)
+    }
+    fn push(&mut self, entry: &AuditEntry, len: u64) {
+        let date = match self.index.last() {
+            Some((latest, _)) if *latest > entry.date => *latest,
+            _ => entry.date,
+        };
+        self.index.push((date, self.len));
+        self.len += len;
+        self.seq = entry.seq;
+        self.last_hash = entry.hash.clone();
+    }
+    /// Last entry of the chain. Record it elsewhere as well,
+    /// to detect a later rewrite of the whole log.
+    pub fn head(&self) -> AuditHead {
+        AuditHead {
+            seq: self.seq,
+            hash: self.last_hash.clone(),
+        }
+    }
+    /// Append an entry and flush it to disk
+    pub fn append(
+        &mut self,
+        actor: &str,
+        action: AuditAction,
+        user_id: &str,
+        details: &str,
+    ) -> ServiceResult<AuditEntry> {
+        let record = Record {
+            actor: actor.to_string(),
+            action,
+            user_id: user_id.to_string(),
+            details: details.to_string(),
+        };
+        Ok(self.append_all(vec![record])?.remove(0))
+    }
+    // Append the entries with a single write and flush.
+    // The chain is only continued once they are on disk.
+    fn append_all(&mut self, records: Vec<Record>) -> ServiceResult<Vec<AuditEntry>> {
+        let mut entries = Vec::new();
+        let mut content = String::new();
+        let (mut seq, mut prev_hash) = (self.seq, self.last_hash.clone());
+        for record in records {
+            let mut entry = AuditEntry {
+                seq: seq + 1,
+                date: Utc::now(),
+                actor: record.actor,
+                action: record.action,
+                user_id: record.user_id,
+                details: record.details,
+                prev_hash,
+                hash: String::new(),
+            };
+            entry.hash = entry.compute_hash()?;
+            let line = serde_json::to_string(&entry).map_err(|e| {
+                InternalError(format!("Error while serializing audit entry: {}", e))
+            })?;
+            content.push_str(&line);
+            content.push('\n');
+            seq = entry.seq;
+            prev_hash = entry.hash.clone();
+            entries.push((entry, line.len() as u64 + 1));
+        }
+        self.file.write_all(content.as_bytes())?;
+        self.file.sync_data()?;
+        for (entry, len) in &entries {
+            self.push(entry, *len);
+        }
+        write_head(&self.path, &self.head())?;
+        Ok(entries.into_iter().map(|(entry, _)| entry).collect())
+    }
+    /// Entries matching filter, oldest first.
+    /// Only the entries from the start of the time range are read,
+    /// and only the latest `limit` matching ones are kept.
+    pub fn query(&self, filter: &AuditFilter) -> ServiceResult<Vec<AuditEntry>> {
+        // Every entry before `start` is older than `from`
+        let start = match filter.from {
+            Some(from) => self
+                .index
+                .binary_search_by(|(date, _)| match *date < from {
+                    true => std::cmp::Ordering::Less,
+                    false => std::cmp::Ordering::Greater,
+                })
+                .unwrap_or_else(|index| index),
+            None => 0,
+        };
+        let offset = match self.index.get(start) {
+            Some((_, offset)) => *offset,
+            None => return Ok(Vec::new()),
+        };
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut result = VecDeque::new();
+        for line in BufReader::new(file.take(self.len - offset)).lines() {
+            let entry: AuditEntry = serde_json::from_str(&line?)
+                .map_err(|e| InternalError(format!("Malformed audit entry: {}", e)))?;
+            if !filter.matches(&entry) {
+                continue;
+            }
+            if filter.limit > 0 && result.len() == filter.limit {
+                // Keep the latest ones
+                result.pop_front();
+            }
+            result.push_back(entry);
+        }
+        Ok(result.into_iter().collect())
+    }
+}
+
+// Entry to append, without its place in the chain
+struct Record {
+    actor: String,
+    action: AuditAction,
+    user_id: String,
+    details: String,
+}
+
+enum Request {
+    Append(Record, oneshot::Sender<ServiceResult<AuditEntry>>),
+    Query(
+        AuditFilter,
+        oneshot::Sender<ServiceResult<(Vec<AuditEntry>, AuditHead)>>,
+    ),
+}
+
+/// # Audit writer
+/// Handle of an audit log kept by its own thread, so the async
+/// executor never waits for the disk. Entries sent while the
+/// previous ones are written are appended together, with a
+/// single flush. Callers get the answer once their entry is
+/// on disk.
+#[derive(Clone)]
+pub struct AuditWriter {
+    sender: mpsc::UnboundedSender<Request>,
+}
+
+impl AuditWriter {
+    /// Start the writer thread of the log
+    pub fn start(mut log: AuditLog) -> Self {
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        std::thread::spawn(move || {
+            while let Some(request) = futures::executor::block_on(receiver.recv()) {
+                let mut requests = vec![request];
+                while let Some(Some(request)) = receiver.recv().now_or_never() {
+                    requests.push(request);
+                }
+                serve(&mut log, requests);
+            }
+        });
+        Self { sender }
+    }
+    /// Append an entry, returns once it is on disk
+    pub async fn append(
+        &self,
+        actor: &str,
+        action: AuditAction,
+        user_id: &str,
+        details: &str,
+    ) -> ServiceResult<AuditEntry> {
+        let record = Record {
+            actor: actor.to_string(),
+            action,
+            user_id: user_id.to_string(),
+            details: details.to_string(),
+        };
+        let (reply, answer) = oneshot::channel();
+        self.sender
+            .send(Request::Append(record, reply))
+            .map_err(|_| InternalError("Audit log is closed".into()))?;
+        answer
+            .await
+            .map_err(|_| InternalError("Audit log is closed".into()))?
+    }
+    /// Entries matching filter, with the head of the log
+    pub async fn query(&self, filter: AuditFilter) -> ServiceResult<(Vec<AuditEntry>, AuditHead)> {
+        let (reply, answer) = oneshot::channel();
+        self.sender
+            .send(Request::Query(filter, reply))
+            .map_err(|_| InternalError("Audit log is closed".into()))?;
+        answer
+            .await
+            .map_err(|_| InternalError("Audit log is closed".into()))?
+    }
+}
+
+// Answer the requests in order, appending the waiting entries
+// together. A query sees every entry sent before it.
+fn serve(log: &mut AuditLog, requests: Vec<Request>) {
+    let mut appends = Vec::new();
+    for request in requests {
+        match request {
+            Request::Append(record, reply) => appends.push((record, reply)),
+            Request::Query(filter, reply) => {
+                flush(log, &mut appends);
+                let _ = reply.send(log.query(&filter).map(|entries| (entries, log.head())));
+            }
+        }
+    }
+    flush(log, &mut appends);
+}
+
+fn flush(
+    log: &mut AuditLog,
+    appends: &mut Vec<(Record, oneshot::Sender<ServiceResult<AuditEntry>>)>,
+) {
+    if appends.is_empty() {
+        return;
+    }
+    let (records, replies): (Vec<Record>, Vec<_>) = appends.drain(..).unzip();
+    match log.append_all(records) {
+        Ok(entries) => {
+            for (entry, reply) in entries.into_iter().zip(replies) {
+                let _ = reply.send(Ok(entry));
+            }
+        }
+        Err(err) => {
+            let error = err.to_string();
+            for reply in replies {
+                let _ = reply.send(Err(InternalError(error.clone())));
+            }
+        }
+    }
+}
+
+/// # Verify
+/// Check the whole hash chain of a log file, and that it
+/// reaches its head. Returns the number of verified entries,
+/// or an error pointing to the first broken line.
+pub fn verify(path: impl AsRef<Path>) -> ServiceResult<u64> {
+    let head = read_head(path.as_ref())?;
+    let mut prev_hash = GENESIS_HASH.to_string();
+    let mut seq = 0;
+    for (index, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line_number = index + 1;
+        let entry: AuditEntry = serde_json::from_str(&line?).map_err(|e| {
+            InternalError(format!(
+                "Line {}: malformed audit entry: {}",
+                line_number, e
+            ))
+        })?;
+        if entry.seq != seq + 1 {
+            return Err(InternalError(format!(
+                "Line {}: sequence gap, expected {} got {}",
+                line_number,
+                seq + 1,
+                entry.seq
+            )));
+        }
+        if entry.prev_hash != prev_hash {
+            return Err(InternalError(format!(
+                "Line {}: previous hash mismatch",
+                line_number
+            )));
+        }
+        if entry.compute_hash()? != entry.hash {
+            return Err(InternalError(format!(
+                "Line {}: entry hash mismatch",
+                line_number
+            )));
+        }
+        if let Some(head) = &head {
+            if entry.seq == head.seq && entry.hash != head.hash {
+                return Err(InternalError(format!(
+                    "Line {}: hash does not match the head",
+                    line_number
+                )));
+            }
+        }
+        seq = entry.seq;
+        prev_hash = entry.hash;
+    }
+    if let Some(head) = head {
+        if seq < head.seq {
+            return Err(InternalError(format!(
+                "Log is truncated, it ends at entry {} but its head is {}",
+                seq, head.seq
+            )));
+        }
+    }
+    Ok(seq)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_log(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "user_microservice_{}_{}.log",
+            name,
+            std::process::id()
+        ));
+        remove_log(&path);
+        path
+    }
+
+    fn remove_log(path: &Path) {
+        let _ = std::fs::remove_file(path);
+        let _ = std::fs::remove_file(head_path(path));
+    }
+
+    #[test]
+    fn test_chain() {
+        let path = temp_log("chain");
+        let mut log = AuditLog::open(&path).unwrap();
+        log.append("admin", AuditAction::UserCreated, "demo", "")
+            .unwrap();
+        log.append("admin", AuditAction::UserRead, "demo", "")
+            .unwrap();
+        // Reopen continues the chain
+        let mut log = AuditLog::open(&path).unwrap();
+        let entry = log.append("demo", AuditAction::Login, "demo", "").unwrap();
+        assert_eq!(entry.seq, 3);
+        assert_eq!(verify(&path).unwrap(), 3);
+        remove_log(&path);
+    }
+
+    #[test]
+    fn test_tamper() {
+        let path = temp_log("tamper");
+        let mut log = AuditLog::open(&path).unwrap();
+        log.append("admin", AuditAction::UserCreated, "demo", "")
+            .unwrap();
+        log.append("admin", AuditAction::UserDeleted, "demo", "")
+            .unwrap();
+        let content = std::fs::read_to_string(&path).unwrap();
+        // Modify an entry
+        std::fs::write(&path, content.replacen("admin", "nobody", 1)).unwrap();
+        assert_eq!(verify(&path).is_err(), true);
+        // Remove an entry
+        let second = content.lines().nth(1).unwrap();
+        std::fs::write(&path, format!("{}\n", second)).unwrap();
+        assert_eq!(verify(&path).is_err(), true);
+        remove_log(&path);
+    }
+
+    #[test]
+    fn test_query() {
+        let path = temp_log("query");
+        let mut log = AuditLog::open(&path).unwrap();
+        log.append("admin", AuditAction::UserCreated, "demo", "")
+            .unwrap();
+        log.append("demo", AuditAction::LoginFailed, "demo", "")
+            .unwrap();
+        log.append("admin", AuditAction::UserRead, "other", "")
+            .unwrap();
+        let by_actor = AuditFilter {
+            actor: Some("admin".into()),
+            ..AuditFilter::default()
+        };
+        assert_eq!(log.query(&by_actor).unwrap().len(), 2);
+        let by_user = AuditFilter {
+            user_id: Some("demo".into()),
+            action: Some("login_failed".into()),
+            ..AuditFilter::default()
+        };
+        assert_eq!(log.query(&by_user).unwrap().len(), 1);
+        let limited = AuditFilter {
+            limit: 1,
+            ..AuditFilter::default()
+        };
+        assert_eq!(log.query(&limited).unwrap()[0].seq, 3);
+        let future = AuditFilter {
+            from: Some(Utc::now() + chrono::Duration::hours(1)),
+            ..AuditFilter::default()
+        };
+        assert_eq!(log.query(&future).unwrap().len(), 0);
+        let past = AuditFilter {
+            from: Some(Utc::now() - chrono::Duration::hours(1)),
+            limit: 2,
+            ..AuditFilter::default()
+        };
+        let entries = log.query(&past).unwrap();
+        assert_eq!(
+            entries.iter().map(|e| e.seq).collect::<Vec<u64>>(),
+            vec![2, 3]
+        );
+        remove_log(&path);
+    }
+
+    #[test]
+    fn test_torn_last_entry() {
+        let path = temp_log("torn");
+        let mut log = AuditLog::open(&path).unwrap();
+        log.append("admin", AuditAction::UserCreated, "demo", "")
+            .unwrap();
+        log.append("admin", AuditAction::UserRead, "demo", "")
+            .unwrap();
+        drop(log);
+        let content = std::fs::read_to_string(&path).unwrap();
+        // Crash while writing the third entry
+        std::fs::write(&path, format!("{}{{\"seq\":3,\"da", content)).unwrap();
+        let mut log = AuditLog::open(&path).unwrap();
+        assert_eq!(log.head().seq, 2);
+        assert_eq!(log.query(&AuditFilter::default()).unwrap().len(), 2);
+        let entry = log.append("demo", AuditAction::Login, "demo", "").unwrap();
+        assert_eq!(entry.seq, 3);
+        assert_eq!(verify(&path).unwrap(), 3);
+        // Broken in the middle is not repaired
+        let content = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, content.replacen("}\n", "}garbage\n", 1)).unwrap();
+        assert_eq!(AuditLog::open(&path).is_err(), true); // should be err
+        remove_log(&path);
+    }
+
+    #[test]
+    fn test_truncated() {
+        let path = temp_log("truncated");
+        let mut log = AuditLog::open(&path).unwrap();
+        for _ in 0..3 {
+            log.append("admin", AuditAction::UserRead, "demo", "")
+                .unwrap();
+        }
+        assert_eq!(log.head().seq, 3);
+        drop(log);
+        // Cut the last entry off, the chain itself stays valid
+        let content = std::fs::read_to_string(&path).unwrap();
+        let kept = content.lines().take(2).collect::<Vec<&str>>().join("\n");
+        std::fs::write(&path, format!("{}\n", kept)).unwrap();
+        assert_eq!(verify(&path).is_err(), true); // should be err
+        assert_eq!(AuditLog::open(&path).is_err(), true); // should be err
+        remove_log(&path);
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn test_writer() {
+        let path = temp_log("writer");
+        let writer = AuditWriter::start(AuditLog::open(&path).unwrap());
+        let appends = (0..50)
+            .map(|n| {
+                let writer = writer.clone();
+                tokio::spawn(async move {
+                    writer
+                        .append("admin", AuditAction::UserRead, &format!("user_{}", n), "")
+                        .await
+                        .unwrap()
+                })
+            })
+            .collect::<Vec<_>>();
+        for append in appends {
+            append.await.unwrap();
+        }
+        // Every entry is chained once, whatever batches they were written in
+        let (entries, head) = writer.query(AuditFilter::default()).await.unwrap();
+        assert_eq!(entries.len(), 50);
+        assert_eq!(head.seq, 50);
+        assert_eq!(verify(&path).unwrap(), 50);
+        remove_log(&path);
+    }
+}
@@ -0,0 +1,581 @@
+// Copyright (C) 2020 Peter Mezei
+//
+// This file is part of Gardenzilla.
+//
+// Gardenzilla is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 2 of the License, or
+// (at your option) any later version.
+//
+// Gardenzilla is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
+
+use crate::api_key::{self, ApiKey};
+use crate::prelude::ServiceError::*;
+use crate::prelude::*;
+use crate::role;
+use chrono::prelude::*;
+use rand::Rng;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tonic::metadata::MetadataMap;
+use tonic::{Request, Status};
+
+// Bearer session token or personal access token of a user
+pub const AUTHORIZATION_METADATA_KEY: &str = "authorization";
+// Shared secret of a trusted internal service
+pub const SERVICE_TOKEN_METADATA_KEY: &str = "x-service-token";
+// API key of a service account
+pub const API_KEY_METADATA_KEY: &str = "x-api-key";
+// Set by the interceptor only, holds the authenticated caller
+pub const PRINCIPAL_METADATA_KEY: &str = "x-principal";
+// Service credentials as name=secret pairs, separated by comma
+pub const SERVICE_CREDENTIALS_ENV: &str = "USER_SERVICE_CREDENTIALS";
+// Session lifetime in hours
+const SESSION_TTL_HOURS: i64 = 12;
+
+/// # Principal
+/// Authenticated caller of an RPC
+#[derive(Clone, Debug, PartialEq)]
+pub enum Principal {
+    // Logged in user with its user ID
+    User(String),
+    // Internal service with its credential name
+    Service(String),
+    // Service account calling with one of its API keys
+    ServiceAccount { id: String, key_id: String },
+    // User calling with one of its personal access tokens
+    UserToken { id: String, key_id: String },
+}
+
+/// Owner of an API key or personal access token
+#[derive(Clone, Debug, PartialEq)]
+pub enum KeyOwner {
+    ServiceAccount(String),
+    User(String),
+}
+
+impl Principal {
+    /// Principal as recorded in history and audit log
+    pub fn get_id(&self) -> String {
+        match self {
+            Principal::User(id) => id.to_string(),
+            Principal::Service(name) => format!("service:{}", name),
+            Principal::ServiceAccount { id, .. } => format!("account:{}", id),
+            Principal::UserToken { id, .. } => id.to_string(),
+        }
+    }
+    fn encode(&self) -> String {
+        match self {
+            Principal::User(id) => format!("user:{}", id),
+            Principal::Service(name) => format!("service:{}", name),
+            Principal::ServiceAccount { id, key_id } => format!("account:{}:{}", id, key_id),
+            Principal::UserToken { id, key_id } => format!("token:{}:{}", id, key_id),
+        }
+    }
+    // Names may contain ':', so the kind is split off the front,
+    // and the key ID, which is hex, off the end
+    fn decode(value: &str) -> Option<Self> {
+        let mut parts = value.splitn(2, ':');
+        let (kind, rest) = (parts.next()?, parts.next()?);
+        let with_key = || {
+            let mut parts = rest.rsplitn(2, ':');
+            let (key_id, id) = (parts.next()?, parts.next()?);
+            Some((id.to_string(), key_id.to_string()))
+        };
+        match kind {
+            "user" => Some(Principal::User(rest.to_string())),
+            "service" => Some(Principal::Service(rest.to_string())),
+            "account" => with_key().map(|(id, key_id)| Principal::ServiceAccount { id, key_id }),
+            "token" => with_key().map(|(id, key_id)| Principal::UserToken { id, key_id }),
+            _ => None,
+        }
+    }
+}
+
+/// Principal set by the interceptor, None for anonymous callers
+pub fn principal<T>(request: &Request<T>) -> Option<Principal> {
+    request
+        .metadata()
+        .get(PRINCIPAL_METADATA_KEY)
+        .and_then(|value| value.to_str().ok())
+        .and_then(Principal::decode)
+}
+
+/// # Policy
+/// What a caller needs to call an RPC
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Policy {
+    // Anyone, even anonymous callers
+    Public,
+    // Any authenticated caller
+    Authenticated,
+    // Caller must have the permission
+    Permission(&'static str),
+    // Caller acts on itself, or has the permission
+    SelfOrPermission(&'static str),
+}
+
+// Per-method policy table. Methods missing from here
+// are admin only, so a new RPC is never open by mistake.
+const POLICIES: &[(&str, Policy)] = &[
+    ("login", Policy::Public),
+    ("accept_invitation", Policy::Public),
+    ("register", Policy::Public),
+    ("verify_email", Policy::Public),
+    ("resend_verification", Policy::Public),
+    ("request_login_code", Policy::Public),
+    ("login_with_code", Policy::Public),
+    ("login_with_link", Policy::Public),
+    (
+        "list_pending_registrations",
+        Policy::Permission(role::USER_ADMIN),
+    ),
+    ("approve_registration", Policy::Permission(role::USER_ADMIN)),
+    ("reject_registration", Policy::Permission(role::USER_ADMIN)),
+    ("list_outbox_messages", Policy::Permission(role::USER_ADMIN)),
+    (
+        "requeue_outbox_message",
+        Policy::Permission(role::USER_ADMIN),
+    ),
+    ("create_webhook", Policy::Permission(role::USER_ADMIN)),
+    ("list_webhooks", Policy::Permission(role::USER_ADMIN)),
+    ("delete_webhook", Policy::Permission(role::USER_ADMIN)),
+    (
+        "list_webhook_deliveries",
+        Policy::Permission(role::USER_ADMIN),
+    ),
+    ("invite_user", Policy::Permission(role::USER_ADMIN)),
+    ("resend_invitation", Policy::Permission(role::USER_ADMIN)),
+    ("reset_password", Policy::Public),
+    ("complete_password_reset", Policy::Public),
+    ("create_new", Policy::Permission(role::USER_ADMIN)),
+    ("get_all", Policy::Permission(role::USER_READ)),
+    ("get_by_id", Policy::SelfOrPermission(role::USER_READ)),
+    ("batch_get_users", Policy::Permission(role::USER_READ)),
+    (
+        "get_user_history",
+        Policy::SelfOrPermission(role::USER_READ),
+    ),
+    ("update_by_id", Policy::SelfOrPermission(role::USER_WRITE)),
+    ("set_password", Policy::SelfOrPermission(role::USER_WRITE)),
+    ("is_user", Policy::Authenticated),
+    ("search_users", Policy::Permission(role::USER_READ)),
+    ("delete_by_id", Policy::Permission(role::USER_ADMIN)),
+    ("watch_users", Policy::Permission(role::USER_READ)),
+    ("query_audit_log", Policy::Permission(role::USER_ADMIN)),
+    ("list_roles", Policy::Authenticated),
+    ("save_role", Policy::Permission(role::USER_ADMIN)),
+    ("assign_role", Policy::Permission(role::USER_ADMIN)),
+    ("revoke_role", Policy::Permission(role::USER_ADMIN)),
+    ("has_permission", Policy::Authenticated),
+    ("add_customer", Policy::Permission(role::USER_ADMIN)),
+    ("remove_customer", Policy::Permission(role::USER_ADMIN)),
+    ("set_customer_role", Policy::Permission(role::USER_ADMIN)),
+    (
+        "list_users_by_customer",
+        Policy::Permission(role::USER_READ),
+    ),
+    (
+        "create_service_account",
+        Policy::Permission(role::USER_ADMIN),
+    ),
+    (
+        "list_service_accounts",
+        Policy::Permission(role::USER_ADMIN),
+    ),
+    ("create_api_key", Policy::Permission(role::USER_ADMIN)),
+    ("revoke_api_key", Policy::Permission(role::USER_ADMIN)),
+    (
+        "create_personal_access_token",
+        Policy::SelfOrPermission(role::USER_ADMIN),
+    ),
+    (
+        "list_personal_access_tokens",
+        Policy::SelfOrPermission(role::USER_ADMIN),
+    ),
+    (
+        "revoke_personal_access_token",
+        Policy::SelfOrPermission(role::USER_ADMIN),
+    ),
+];
+
+/// Policy of an RPC by its method name, e.g. update_by_id
+pub fn policy(method: &str) -> Policy {
+    POLICIES
+        .iter()
+        .find(|(name, _)| *name == method)
+        .map(|(_, policy)| *policy)
+        .unwrap_or(Policy::Permission(role::USER_ADMIN))
+}
+
+struct Session {
+    user_id: String,
+    expires_at: DateTime<Utc>,
+}
+
+/// # Authenticator
+/// Issues user sessions, and resolves the caller of every
+/// request from its bearer token, service credential or API key.
+/// Only hashes of tokens and secrets are kept in memory.
+pub struct Authenticator {
+    // Token hash -> session
+    sessions: Mutex<HashMap<String, Session>>,
+    // Secret hash -> service name
+    services: HashMap<String, String>,
+    // Key ID -> (owner, key)
+    api_keys: Mutex<HashMap<String, (KeyOwner, ApiKey)>>,
+}
+
+impl Authenticator {
+    /// Credentials are (service name, secret) pairs
+    pub fn new(service_credentials: Vec<(String, String)>) -> Self {
+        Self {
+            sessions: Mutex::new(HashMap::new()),
+            services: service_credentials
+                .into_iter()
+                .map(|(name, secret)| (hash_secret(&secret), name))
+                .collect(),
+            api_keys: Mutex::new(HashMap::new()),
+        }
+    }
+    /// Load service credentials from USER_SERVICE_CREDENTIALS,
+    /// e.g. USER_SERVICE_CREDENTIALS=invoice=secret1,cash=secret2
+    pub fn from_env() -> ServiceResult<Self> {
+        let value = std::env::var(SERVICE_CREDENTIALS_ENV).unwrap_or_default();
+        let mut credentials = Vec::new();
+        for pair in value.split(',').filter(|pair| !pair.trim().is_empty()) {
+            let mut parts = pair.trim().splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(name), Some(secret)) if !name.is_empty() && !secret.is_empty() => {
+                    credentials.push((name.to_string(), secret.to_string()))
+                }
+                _ => {
+                    return Err(BadRequest(format!(
+                        "{} must contain name=secret pairs",
+                        SERVICE_CREDENTIALS_ENV
+                    )))
+                }
+            }
+        }
+        Ok(Self::new(credentials))
+    }
+    /// Start a session for the user.
+    /// Returns the bearer token and its expiration.
+    pub fn create_session(&self, user_id: &str) -> ServiceResult<(String, DateTime<Utc>)> {
+        let token = generate_token();
+        let expires_at = Utc::now() + chrono::Duration::hours(SESSION_TTL_HOURS);
+        let mut sessions = self
+            .sessions
+            .lock()
+            .map_err(|_| ServiceError::internal_error("Lock error"))?;
+        // Forget the expired ones
+        let now = Utc::now();
+        sessions.retain(|_, session| session.expires_at > now);
+        sessions.insert(
+            hash_secret(&token),
+            Session {
+                user_id: user_id.to_string(),
+                expires_at,
+            },
+        );
+        Ok((token, expires_at))
+    }
+    /// End every session of the user, e.g. after password change
+    pub fn revoke_sessions(&self, user_id: &str) -> ServiceResult<()> {
+        self.sessions
+            .lock()
+            .map_err(|_| ServiceError::internal_error("Lock error"))?
+            .retain(|_, session| session.user_id != user_id);
+        Ok(())
+    }
+    /// Add or refresh an API key or personal access token,
+    /// e.g. after it is created or revoked
+    pub fn register_api_key(&self, owner: KeyOwner, api_key: &ApiKey) -> ServiceResult<()> {
+        self.api_keys
+            .lock()
+            .map_err(|_| ServiceError::internal_error("Lock error"))?
+            .insert(api_key.get_id().to_string(), (owner, api_key.clone()));
+        Ok(())
+    }
+    /// Record a use of the key. Returns the date
+    /// if the last use should be stored as well.
+    pub fn touch_api_key(&self, key_id: &str) -> ServiceResult<Option<DateTime<Utc>>> {
+        let now = Utc::now();
+        let mut api_keys = self
+            .api_keys
+            .lock()
+            .map_err(|_| ServiceError::internal_error("Lock error"))?;
+        let touched = match api_keys.get_mut(key_id) {
+            Some((_, api_key)) => api_key.touch(now),
+            None => false,
+        };
+        match touched {
+            true => Ok(Some(now)),
+            false => Ok(None),
+        }
+    }
+    /// Does the API key grant the permission.
+    /// False if it is unknown, revoked or expired meanwhile.
+    pub fn api_key_grants(&self, key_id: &str, permission: &str) -> ServiceResult<bool> {
+        Ok(self
+            .api_keys
+            .lock()
+            .map_err(|_| ServiceError::internal_error("Lock error"))?
+            .get(key_id)
+            .is_some_and(|(_, api_key)| api_key.is_active() && api_key.grants(permission)))
+    }
+    /// # Authenticate
+    /// Resolve the caller from the request metadata.
+    /// Returns None if there is no credential at all, and
+    /// error if there is one but it is invalid or expired.
+    pub fn authenticate(&self, metadata: &MetadataMap) -> ServiceResult<Option<Principal>> {
+        let read = |key: &str| -> ServiceResult<Option<String>> {
+            match metadata.get(key) {
+                Some(value) => value
+                    .to_str()
+                    .map(|value| Some(value.to_string()))
+                    .map_err(|_| Unauthenticated("Malformed credential".into())),
+                None => Ok(None),
+            }
+        };
+        if let Some(value) = read(AUTHORIZATION_METADATA_KEY)? {
+            let token = value
+                .strip_prefix("Bearer ")
+                .ok_or_else(|| Unauthenticated("Authorization must be a Bearer token".into()))?;
+            // Personal access token
+            if api_key::parse(token).is_some() {
+                return self.authenticate_key(token).map(Some);
+            }
+            let sessions = self
+                .sessions
+                .lock()
+                .map_err(|_| ServiceError::internal_error("Lock error"))?;
+            return match sessions.get(&hash_secret(token)) {
+                Some(session) if session.expires_at > Utc::now() => {
+                    Ok(Some(Principal::User(session.user_id.to_string())))
+                }
+                _ => Err(Unauthenticated("Invalid or expired token".into())),
+            };
+        }
+        if let Some(secret) = read(SERVICE_TOKEN_METADATA_KEY)? {
+            return match self.services.get(&hash_secret(&secret)) {
+                Some(name) => Ok(Some(Principal::Service(name.to_string()))),
+                None => Err(Unauthenticated("Invalid service credential".into())),
+            };
+        }
+        if let Some(key) = read(API_KEY_METADATA_KEY)? {
+            return self.authenticate_key(&key).map(Some);
+        }
+        Ok(None)
+    }
+    fn authenticate_key(&self, key: &str) -> ServiceResult<Principal> {
+        let (key_id, secret) =
+            api_key::parse(key).ok_or_else(|| Unauthenticated("Malformed API key".into()))?;
+        let api_keys = self
+            .api_keys
+            .lock()
+            .map_err(|_| ServiceError::internal_error("Lock error"))?;
+        let owner = match api_keys.get(key_id) {
+            Some((owner, api_key)) if api_key.is_active() && api_key.verify(secret) => owner,
+            _ => {
+                return Err(Unauthenticated(
+                    "Invalid, revoked or expired API key".into(),
+                ))
+            }
+        };
+        Ok(match owner {
+            KeyOwner::ServiceAccount(id) => Principal::ServiceAccount {
+                id: id.to_string(),
+                key_id: key_id.to_string(),
+            },
+            KeyOwner::User(id) => Principal::UserToken {
+                id: id.to_string(),
+                key_id: key_id.to_string(),
+            },
+        })
+    }
+    /// # Intercept
+    /// gRPC interceptor: replaces the credentials with the trusted
+    /// principal metadata, so the handlers can rely on it.
+    // The signature is the one tonic expects from an interceptor
+    #[allow(clippy::result_large_err)]
+    pub fn intercept(&self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        // Never trust a principal sent by the client
+        request.metadata_mut().remove(PRINCIPAL_METADATA_KEY);
+        if let Some(principal) = self.authenticate(request.metadata())? {
+            let value = principal
+                .encode()
+                .parse()
+                .map_err(|_| Status::unauthenticated("Malformed principal"))?;
+            request.metadata_mut().insert(PRINCIPAL_METADATA_KEY, value);
+        }
+        Ok(request)
+    }
+}
+
+/// Random token, hex encoded
+pub fn generate_token() -> String {
+    hex::encode(rand::thread_rng().gen::<[u8; 32]>())
+}
+
+/// Hash of a secret or token as stored
+pub fn hash_secret(secret: &str) -> String {
+    hex::encode(Sha256::digest(secret.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(key: &'static str, value: &str) -> Request<()> {
+        let mut request = Request::new(());
+        request.metadata_mut().insert(key, value.parse().unwrap());
+        request
+    }
+
+    fn request_with_token(token: &str) -> Request<()> {
+        request(AUTHORIZATION_METADATA_KEY, &format!("Bearer {}", token))
+    }
+
+    #[test]
+    fn test_session() {
+        let auth = Authenticator::new(vec![]);
+        let (token, _) = auth.create_session("demo").unwrap();
+        let request = auth.intercept(request_with_token(&token)).unwrap();
+        assert_eq!(principal(&request), Some(Principal::User("demo".into())));
+        // Revoked
+        auth.revoke_sessions("demo").unwrap();
+        assert_eq!(auth.intercept(request_with_token(&token)).is_err(), true); // should be err
+        assert_eq!(auth.intercept(request_with_token("wrong")).is_err(), true); // should be err
+    }
+
+    #[test]
+    fn test_service_credential() {
+        let auth = Authenticator::new(vec![("invoice".into(), "secret".into())]);
+        let request_ok = auth
+            .intercept(request(SERVICE_TOKEN_METADATA_KEY, "secret"))
+            .unwrap();
+        assert_eq!(
+            principal(&request_ok),
+            Some(Principal::Service("invoice".into()))
+        );
+        assert_eq!(
+            auth.intercept(request(SERVICE_TOKEN_METADATA_KEY, "wrong"))
+                .is_err(),
+            true
+        ); // should be err
+    }
+
+    #[test]
+    fn test_api_key() {
+        let auth = Authenticator::new(vec![]);
+        let (mut api_key, key) = ApiKey::generate(
+            "main".into(),
+            vec!["user:read".into()],
+            None,
+            "admin".into(),
+        )
+        .unwrap();
+        auth.register_api_key(KeyOwner::ServiceAccount("invoice".into()), &api_key)
+            .unwrap();
+        let request_ok = auth.intercept(request(API_KEY_METADATA_KEY, &key)).unwrap();
+        let expected = Principal::ServiceAccount {
+            id: "invoice".into(),
+            key_id: api_key.get_id().into(),
+        };
+        assert_eq!(principal(&request_ok), Some(expected));
+        assert_eq!(
+            auth.api_key_grants(api_key.get_id(), "user:read").unwrap(),
+            true
+        );
+        assert_eq!(
+            auth.api_key_grants(api_key.get_id(), "user:admin").unwrap(),
+            false
+        );
+        // Revoked
+        api_key.revoke().unwrap();
+        auth.register_api_key(KeyOwner::ServiceAccount("invoice".into()), &api_key)
+            .unwrap();
+        assert_eq!(
+            auth.intercept(request(API_KEY_METADATA_KEY, &key)).is_err(),
+            true
+        ); // should be err
+        assert_eq!(
+            auth.api_key_grants(api_key.get_id(), "user:read").unwrap(),
+            false
+        );
+    }
+
+    #[test]
+    fn test_personal_access_token() {
+        let auth = Authenticator::new(vec![]);
+        let (token, key) = ApiKey::generate(
+            "script".into(),
+            vec!["user:read".into()],
+            None,
+            "demo".into(),
+        )
+        .unwrap();
+        auth.register_api_key(KeyOwner::User("demo".into()), &token)
+            .unwrap();
+        let request = auth.intercept(request_with_token(&key)).unwrap();
+        let expected = Principal::UserToken {
+            id: "demo".into(),
+            key_id: token.get_id().into(),
+        };
+        assert_eq!(principal(&request), Some(expected));
+        // First use is stored, the next one only after a while
+        assert_eq!(auth.touch_api_key(token.get_id()).unwrap().is_some(), true);
+        assert_eq!(auth.touch_api_key(token.get_id()).unwrap().is_some(), false);
+    }
+
+    #[test]
+    fn test_principal_encoding() {
+        let principals = vec![
+            Principal::User("demo".into()),
+            Principal::Service("billing:eu".into()),
+            Principal::ServiceAccount {
+                id: "invoice:main".into(),
+                key_id: "0a1b".into(),
+            },
+            Principal::UserToken {
+                id: "demo".into(),
+                key_id: "0a1b".into(),
+            },
+        ];
+        for principal in principals {
+            assert_eq!(Principal::decode(&principal.encode()), Some(principal));
+        }
+        assert_eq!(Principal::decode("account:invoice"), None);
+        assert_eq!(Principal::decode("unknown:demo"), None);
+    }
+
+    #[test]
+    fn test_spoofed_principal() {
+        let auth = Authenticator::new(vec![]);
+        // Anonymous request cannot claim to be an admin
+        let request = auth
+            .intercept(request(PRINCIPAL_METADATA_KEY, "user:admin"))
+            .unwrap();
+        assert_eq!(principal(&request), None);
+    }
+
+    #[test]
+    fn test_policy() {
+        assert_eq!(policy("login"), Policy::Public);
+        assert_eq!(policy("create_new"), Policy::Permission(role::USER_ADMIN));
+        assert_eq!(
+            policy("update_by_id"),
+            Policy::SelfOrPermission(role::USER_WRITE)
+        );
+        // Unknown methods are admin only
+        assert_eq!(policy("unknown"), Policy::Permission(role::USER_ADMIN));
+    }
+}
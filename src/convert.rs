@@ -1,5 +1,17 @@
+use crate::api_key;
+use crate::audit;
+use crate::outbox;
+use crate::role;
+use crate::search;
+use crate::service_account;
 use crate::user;
-use crate::UserObj;
+use crate::watch;
+use crate::webhook;
+use crate::{
+    AccountStatus, ApiKeyObj, AuditEntry, CustomerMembership, EventKind, FieldChange, Highlight,
+    HighlightSpan, OutboxMessageObj, OutboxMessageStatus, RoleObj, ServiceAccountObj, UserEvent,
+    UserHistoryEntry, UserObj, WebhookDeliveryObj, WebhookDeliveryStatus, WebhookObj,
+};
 
 impl From<&user::User> for UserObj {
     fn from(user: &user::User) -> Self {
@@ -8,9 +20,228 @@ impl From<&user::User> for UserObj {
             name: user.get_user_name().to_string(),
             email: user.get_user_email().to_string(),
             phone: user.get_user_phone().to_string(),
-            customers: user.get_customers().to_owned(),
+            customers: user.get_customer_ids(),
             created_by: user.get_created_by().to_string(),
             created_at: user.get_date_created().to_string(),
+            version: user.get_version(),
+            updated_at: user
+                .get_date_updated()
+                .map(|d| d.to_string())
+                .unwrap_or_default(),
+            updated_by: user.get_updated_by().unwrap_or_default().to_string(),
+            roles: user.get_roles().to_owned(),
+            memberships: user
+                .get_customers()
+                .iter()
+                .map(|m| CustomerMembership {
+                    customer_id: m.customer_id.to_string(),
+                    role: m.role.to_string(),
+                    joined_at: m.date_joined.map(|d| d.to_string()).unwrap_or_default(),
+                })
+                .collect(),
+            default_customer: user.get_default_customer().unwrap_or_default().to_string(),
+            status: match user.get_status() {
+                user::UserStatus::Invited => AccountStatus::Invited,
+                user::UserStatus::PendingApproval => AccountStatus::PendingApproval,
+                user::UserStatus::PendingVerification => AccountStatus::PendingVerification,
+                // Deleted users are never sent
+                _ => AccountStatus::Active,
+            } as i32,
+        }
+    }
+}
+
+impl From<&user::HistoryEntry> for UserHistoryEntry {
+    fn from(entry: &user::HistoryEntry) -> Self {
+        UserHistoryEntry {
+            version: entry.version,
+            actor: entry.actor.to_string(),
+            created_at: entry.date.to_string(),
+            changes: entry
+                .changes
+                .iter()
+                .map(|change| FieldChange {
+                    field: change.field.to_string(),
+                    old_value: change.old_value.to_string(),
+                    new_value: change.new_value.to_string(),
+                })
+                .collect(),
+        }
+    }
+}
+
+impl From<search::Highlight> for Highlight {
+    fn from(highlight: search::Highlight) -> Self {
+        Highlight {
+            field: highlight.field.as_str().to_string(),
+            value: highlight.value,
+            spans: highlight
+                .spans
+                .into_iter()
+                .map(|(start, end)| HighlightSpan {
+                    start: start as u32,
+                    end: end as u32,
+                })
+                .collect(),
+        }
+    }
+}
+
+impl From<&watch::ChangeEvent> for UserEvent {
+    fn from(event: &watch::ChangeEvent) -> Self {
+        let kind = match event.kind {
+            watch::ChangeKind::Created => EventKind::Created,
+            watch::ChangeKind::Updated => EventKind::Updated,
+            watch::ChangeKind::Deleted => EventKind::Deleted,
+        };
+        UserEvent {
+            revision: event.revision,
+            epoch: event.epoch.clone(),
+            kind: kind as i32,
+            user_id: event.user.get_user_id().to_string(),
+            user: Some((&event.user).into()),
+            created_at: event.date_created.to_string(),
+        }
+    }
+}
+
+impl From<audit::AuditEntry> for AuditEntry {
+    fn from(entry: audit::AuditEntry) -> Self {
+        AuditEntry {
+            seq: entry.seq,
+            created_at: entry.date.to_rfc3339(),
+            actor: entry.actor,
+            action: entry.action.as_str().to_string(),
+            userid: entry.user_id,
+            details: entry.details,
+            prev_hash: entry.prev_hash,
+            hash: entry.hash,
+        }
+    }
+}
+
+impl From<&api_key::ApiKey> for ApiKeyObj {
+    fn from(api_key: &api_key::ApiKey) -> Self {
+        ApiKeyObj {
+            id: api_key.get_id().to_string(),
+            name: api_key.get_name().to_string(),
+            scopes: api_key.get_scopes().to_owned(),
+            created_by: api_key.get_created_by().to_string(),
+            created_at: api_key.get_date_created().to_string(),
+            expires_at: api_key
+                .get_expires_at()
+                .map(|d| d.to_string())
+                .unwrap_or_default(),
+            revoked_at: api_key
+                .get_revoked_at()
+                .map(|d| d.to_string())
+                .unwrap_or_default(),
+            last_used_at: api_key
+                .get_last_used()
+                .map(|d| d.to_string())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+impl From<&service_account::ServiceAccount> for ServiceAccountObj {
+    fn from(account: &service_account::ServiceAccount) -> Self {
+        ServiceAccountObj {
+            id: account.get_id().to_string(),
+            name: account.get_name().to_string(),
+            created_by: account.get_created_by().to_string(),
+            created_at: account.get_date_created().to_string(),
+            api_keys: account.get_api_keys().iter().map(|k| k.into()).collect(),
+        }
+    }
+}
+
+impl From<outbox::OutboxStatus> for OutboxMessageStatus {
+    fn from(status: outbox::OutboxStatus) -> Self {
+        match status {
+            outbox::OutboxStatus::Pending => OutboxMessageStatus::Pending,
+            outbox::OutboxStatus::Delivered => OutboxMessageStatus::Delivered,
+            outbox::OutboxStatus::Dead => OutboxMessageStatus::Dead,
+        }
+    }
+}
+
+impl From<OutboxMessageStatus> for outbox::OutboxStatus {
+    fn from(status: OutboxMessageStatus) -> Self {
+        match status {
+            OutboxMessageStatus::Pending => outbox::OutboxStatus::Pending,
+            OutboxMessageStatus::Delivered => outbox::OutboxStatus::Delivered,
+            OutboxMessageStatus::Dead => outbox::OutboxStatus::Dead,
+        }
+    }
+}
+
+// Body is never sent, as it may hold secrets
+impl From<&outbox::OutboxMessage> for OutboxMessageObj {
+    fn from(message: &outbox::OutboxMessage) -> Self {
+        OutboxMessageObj {
+            id: message.get_id().to_string(),
+            to: message.get_message().to.clone(),
+            subject: message.get_message().subject.clone(),
+            status: OutboxMessageStatus::from(message.get_status()) as i32,
+            attempts: message.get_attempts(),
+            created_at: message.get_date_created().to_string(),
+            next_attempt_at: message.get_next_attempt_at().to_string(),
+            delivered_at: message
+                .get_delivered_at()
+                .map(|d| d.to_string())
+                .unwrap_or_default(),
+            last_error: message.get_last_error().unwrap_or_default().to_string(),
+        }
+    }
+}
+
+// Secret is never sent, only once at creation
+impl From<&webhook::Webhook> for WebhookObj {
+    fn from(hook: &webhook::Webhook) -> Self {
+        WebhookObj {
+            id: hook.get_id().to_string(),
+            url: hook.get_url().to_string(),
+            events: hook.get_events().to_owned(),
+            created_by: hook.get_created_by().to_string(),
+            created_at: hook.get_date_created().to_string(),
+        }
+    }
+}
+
+impl From<&webhook::Delivery> for WebhookDeliveryObj {
+    fn from(delivery: &webhook::Delivery) -> Self {
+        WebhookDeliveryObj {
+            id: delivery.get_id().to_string(),
+            webhook_id: delivery.get_webhook_id().to_string(),
+            event: delivery.get_event().to_string(),
+            payload: delivery.get_payload().to_string(),
+            status: match delivery.get_status() {
+                webhook::DeliveryStatus::Pending => WebhookDeliveryStatus::DeliveryPending,
+                webhook::DeliveryStatus::Delivered => WebhookDeliveryStatus::DeliverySucceeded,
+                webhook::DeliveryStatus::Failed => WebhookDeliveryStatus::DeliveryFailed,
+            } as i32,
+            attempts: delivery.get_attempts(),
+            created_at: delivery.get_date_created().to_string(),
+            next_attempt_at: delivery.get_next_attempt_at().to_string(),
+            delivered_at: delivery
+                .get_delivered_at()
+                .map(|d| d.to_string())
+                .unwrap_or_default(),
+            last_status_code: delivery.get_last_status_code().unwrap_or_default() as u32,
+            last_error: delivery.get_last_error().unwrap_or_default().to_string(),
+        }
+    }
+}
+
+impl From<&role::Role> for RoleObj {
+    fn from(role: &role::Role) -> Self {
+        RoleObj {
+            id: role.get_id().to_string(),
+            name: role.get_name().to_string(),
+            permissions: role.get_permissions().to_owned(),
+            created_by: role.get_created_by().to_string(),
+            created_at: role.get_date_created().to_string(),
         }
     }
 }
@@ -0,0 +1,175 @@
+// Copyright (C) 2020 Peter Mezei
+//
+// This file is part of Gardenzilla.
+//
+// Gardenzilla is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 2 of the License, or
+// (at your option) any later version.
+//
+// Gardenzilla is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
+
+use crate::prelude::*;
+use crate::protos::user::UserLifecycleEvent;
+use crate::user;
+use crate::webhook;
+use chrono::prelude::*;
+use rand::Rng;
+use std::sync::{Arc, Mutex};
+
+// NATS server URL, e.g. nats://localhost:4222
+pub const NATS_URL_ENV: &str = "USER_NATS_URL";
+// Version of UserLifecycleEvent, increased by breaking changes only
+pub const EVENT_SCHEMA_VERSION: u32 = 1;
+// Subjects are like gardenzilla.v1.user.created
+const SUBJECT_PREFIX: &str = "gardenzilla";
+
+/// # Event publisher
+/// Sends encoded events to a message broker
+pub trait EventPublisher: Send + Sync {
+    fn publish(&self, subject: &str, payload: &[u8]) -> ServiceResult<()>;
+}
+
+/// # NATS publisher
+/// Publishes to a NATS server. The client buffers messages
+/// and reconnects by itself, so publish does not wait for the server.
+pub struct NatsPublisher {
+    connection: nats::Connection,
+}
+
+impl NatsPublisher {
+    pub fn connect(url: &str) -> ServiceResult<Self> {
+        let connection = nats::connect(url)
+            .map_err(|e| ServiceError::internal_error(&format!("Cannot connect to NATS: {}", e)))?;
+        Ok(Self { connection })
+    }
+}
+
+impl EventPublisher for NatsPublisher {
+    fn publish(&self, subject: &str, payload: &[u8]) -> ServiceResult<()> {
+        self.connection.publish(subject, payload).map_err(|e| {
+            ServiceError::internal_error(&format!("Error while publishing event: {}", e))
+        })
+    }
+}
+
+/// # Noop publisher
+/// Drops every event, used without a broker
+pub struct NoopPublisher;
+
+impl EventPublisher for NoopPublisher {
+    fn publish(&self, _subject: &str, _payload: &[u8]) -> ServiceResult<()> {
+        Ok(())
+    }
+}
+
+/// # Memory publisher
+/// Keeps the published events in memory, for tests
+#[derive(Default)]
+pub struct MemoryPublisher {
+    published: Mutex<Vec<(String, Vec<u8>)>>,
+}
+
+impl MemoryPublisher {
+    /// (subject, payload) pairs, oldest first
+    pub fn published(&self) -> Vec<(String, Vec<u8>)> {
+        self.published.lock().unwrap().clone()
+    }
+}
+
+impl EventPublisher for MemoryPublisher {
+    fn publish(&self, subject: &str, payload: &[u8]) -> ServiceResult<()> {
+        self.published
+            .lock()
+            .map_err(|_| ServiceError::internal_error("Lock error"))?
+            .push((subject.to_string(), payload.to_vec()));
+        Ok(())
+    }
+}
+
+/// Subject of the event, e.g. gardenzilla.v1.user.created
+pub fn subject(event: &str) -> String {
+    format!("{}.v{}.{}", SUBJECT_PREFIX, EVENT_SCHEMA_VERSION, event)
+}
+
+/// Event of a change made on the user, event is one of webhook::EVENTS
+pub fn user_event(event: &str, user: &user::User, actor: &str) -> UserLifecycleEvent {
+    UserLifecycleEvent {
+        schema_version: EVENT_SCHEMA_VERSION,
+        id: hex::encode(rand::thread_rng().gen::<[u8; 16]>()),
+        event_type: event.to_string(),
+        user_id: user.get_user_id().to_string(),
+        user_version: user.get_version(),
+        actor: actor.to_string(),
+        occurred_at: Utc::now().to_rfc3339(),
+        // Deleted users are never sent
+        user: match event {
+            webhook::USER_DELETED => None,
+            _ => Some(user.into()),
+        },
+    }
+}
+
+/// Encode and publish the event of a change made on the user
+pub fn publish_user_event(
+    publisher: &dyn EventPublisher,
+    event: &str,
+    user: &user::User,
+    actor: &str,
+) -> ServiceResult<()> {
+    let mut payload = Vec::new();
+    prost::Message::encode(&user_event(event, user, actor), &mut payload)
+        .map_err(|e| ServiceError::internal_error(&format!("Error while encoding event: {}", e)))?;
+    publisher.publish(&subject(event), &payload)
+}
+
+/// # From env
+/// NATS publisher if USER_NATS_URL is set,
+/// otherwise events are dropped
+pub fn from_env() -> ServiceResult<Arc<dyn EventPublisher>> {
+    match std::env::var(NATS_URL_ENV) {
+        Ok(url) if !url.is_empty() => Ok(Arc::new(NatsPublisher::connect(&url)?)),
+        _ => Ok(Arc::new(NoopPublisher)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user() -> user::User {
+        user::User::new(
+            "demo".into(),
+            "user".into(),
+            "demo@user.com".into(),
+            "".into(),
+            "admin".into(),
+            None,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_publish_user_event() {
+        let publisher = MemoryPublisher::default();
+        publish_user_event(&publisher, webhook::USER_CREATED, &user(), "admin").unwrap();
+        publish_user_event(&publisher, webhook::USER_DELETED, &user(), "admin").unwrap();
+        let published = publisher.published();
+        assert_eq!(published.len(), 2);
+        assert_eq!(published[0].0, "gardenzilla.v1.user.created");
+        let event: UserLifecycleEvent = prost::Message::decode(&published[0].1[..]).unwrap();
+        assert_eq!(event.schema_version, EVENT_SCHEMA_VERSION);
+        assert_eq!(event.event_type, webhook::USER_CREATED);
+        assert_eq!(event.user_id, "demo");
+        assert_eq!(event.actor, "admin");
+        assert_eq!(event.user.unwrap().email, "demo@user.com");
+        let deleted: UserLifecycleEvent = prost::Message::decode(&published[1].1[..]).unwrap();
+        assert_eq!(deleted.user, None);
+    }
+}
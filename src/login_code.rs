@@ -0,0 +1,308 @@
+// Copyright (C) 2020 Peter Mezei
+//
+// This file is part of Gardenzilla.
+//
+// Gardenzilla is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 2 of the License, or
+// (at your option) any later version.
+//
+// Gardenzilla is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
+
+use crate::auth::{generate_token, hash_secret};
+use crate::prelude::*;
+use chrono::prelude::*;
+use rand::Rng;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+// Base URL of the login link sent beside the code,
+// the link token is appended as ?token=<token>
+pub const LOGIN_LINK_URL_ENV: &str = "USER_LOGIN_LINK_URL";
+// Lifetime of a code and link in minutes
+const LOGIN_CODE_TTL_MINUTES: i64 = 10;
+// A new code is not sent within this, in seconds
+const LOGIN_CODE_RESEND_SECONDS: i64 = 60;
+// Wrong codes tried before the code becomes invalid
+const LOGIN_CODE_MAX_ATTEMPTS: u32 = 5;
+// Number of digits of the code
+const LOGIN_CODE_DIGITS: u32 = 6;
+// Wrong codes and issued codes allowed per user within
+// this many hours, whatever number of codes they are spread on
+const LOGIN_CODE_BUDGET_HOURS: i64 = 24;
+const LOGIN_CODE_MAX_FAILURES: u32 = 10;
+const LOGIN_CODE_MAX_ISSUED: u32 = 10;
+
+struct LoginCode {
+    code_hash: String,
+    link_hash: String,
+    date_created: DateTime<Utc>,
+    expires_at: DateTime<Utc>,
+    attempts_left: u32,
+}
+
+// Codes used by a user within the budget window
+struct Budget {
+    window_start: DateTime<Utc>,
+    failures: u32,
+    issued: u32,
+}
+
+impl Budget {
+    fn is_locked(&self) -> bool {
+        self.failures >= LOGIN_CODE_MAX_FAILURES
+    }
+}
+
+// Budget of the user, a new one if the window is over
+fn budget<'a>(
+    budgets: &'a mut HashMap<String, Budget>,
+    user_id: &str,
+    now: DateTime<Utc>,
+) -> &'a mut Budget {
+    let window_start = now - chrono::Duration::hours(LOGIN_CODE_BUDGET_HOURS);
+    budgets.retain(|_, budget| budget.window_start > window_start);
+    budgets.entry(user_id.to_string()).or_insert(Budget {
+        window_start: now,
+        failures: 0,
+        issued: 0,
+    })
+}
+
+/// Code and link token to send to the user
+#[derive(Clone, Debug)]
+pub struct IssuedCode {
+    pub code: String,
+    pub link_token: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// # Login codes
+/// Short-lived, single-use codes for passwordless login.
+/// A user has one open code at a time, usable either typed in
+/// or through the login link. Only hashes are kept, in memory,
+/// so codes do not survive a restart.
+/// Wrong and issued codes are counted per user across codes, so
+/// guessing is not renewed by a new code, and a user is not flooded
+/// with emails. Once the budget is spent, codes are refused till
+/// the window is over.
+#[derive(Default)]
+pub struct LoginCodes {
+    // User ID -> open code
+    codes: Mutex<HashMap<String, LoginCode>>,
+    // User ID -> codes used in the window
+    budgets: Mutex<HashMap<String, Budget>>,
+}
+
+impl LoginCodes {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// New code for the user, the previous one becomes invalid.
+    /// Returns None if a code was issued just recently,
+    /// or the budget of the user is spent.
+    pub fn issue(&self, user_id: &str) -> ServiceResult<Option<IssuedCode>> {
+        let now = Utc::now();
+        let mut codes = self
+            .codes
+            .lock()
+            .map_err(|_| ServiceError::internal_error("Lock error"))?;
+        // Forget the expired ones
+        codes.retain(|_, code| code.expires_at > now);
+        if let Some(code) = codes.get(user_id) {
+            if now - code.date_created < chrono::Duration::seconds(LOGIN_CODE_RESEND_SECONDS) {
+                return Ok(None);
+            }
+        }
+        let mut budgets = self
+            .budgets
+            .lock()
+            .map_err(|_| ServiceError::internal_error("Lock error"))?;
+        let budget = budget(&mut budgets, user_id, now);
+        if budget.is_locked() || budget.issued >= LOGIN_CODE_MAX_ISSUED {
+            return Ok(None);
+        }
+        budget.issued += 1;
+        let code = format!(
+            "{:0width$}",
+            rand::thread_rng().gen_range(0, 10u32.pow(LOGIN_CODE_DIGITS)),
+            width = LOGIN_CODE_DIGITS as usize
+        );
+        let link_token = generate_token();
+        let expires_at = now + chrono::Duration::minutes(LOGIN_CODE_TTL_MINUTES);
+        codes.insert(
+            user_id.to_string(),
+            LoginCode {
+                code_hash: hash_secret(&code),
+                link_hash: hash_secret(&link_token),
+                date_created: now,
+                expires_at,
+                attempts_left: LOGIN_CODE_MAX_ATTEMPTS,
+            },
+        );
+        Ok(Some(IssuedCode {
+            code,
+            link_token,
+            expires_at,
+        }))
+    }
+    /// Use the code typed in by the user.
+    /// Returns true once for a valid code.
+    pub fn redeem_code(&self, user_id: &str, code: &str) -> ServiceResult<bool> {
+        let now = Utc::now();
+        let mut codes = self
+            .codes
+            .lock()
+            .map_err(|_| ServiceError::internal_error("Lock error"))?;
+        let mut budgets = self
+            .budgets
+            .lock()
+            .map_err(|_| ServiceError::internal_error("Lock error"))?;
+        let budget = budget(&mut budgets, user_id, now);
+        let valid = match codes.get_mut(user_id) {
+            // Even the right code is refused once the budget is spent
+            Some(_) if budget.is_locked() => false,
+            Some(open) if open.expires_at > now => {
+                if open.code_hash == hash_secret(code.trim()) {
+                    true
+                } else {
+                    budget.failures += 1;
+                    open.attempts_left -= 1;
+                    if open.attempts_left > 0 && !budget.is_locked() {
+                        return Ok(false);
+                    }
+                    // Too many wrong codes
+                    false
+                }
+            }
+            Some(_) => false,
+            None => return Ok(false),
+        };
+        codes.remove(user_id);
+        Ok(valid)
+    }
+    /// Use the token of a login link.
+    /// Returns the user ID once for a valid token.
+    pub fn redeem_link(&self, token: &str) -> ServiceResult<Option<String>> {
+        let now = Utc::now();
+        let mut codes = self
+            .codes
+            .lock()
+            .map_err(|_| ServiceError::internal_error("Lock error"))?;
+        let link_hash = hash_secret(token);
+        let user_id = codes
+            .iter()
+            .find(|(_, code)| code.link_hash == link_hash && code.expires_at > now)
+            .map(|(user_id, _)| user_id.to_string());
+        if let Some(user_id) = &user_id {
+            codes.remove(user_id);
+        }
+        Ok(user_id)
+    }
+    /// Forget the open code of the user, e.g. after password login
+    pub fn revoke(&self, user_id: &str) -> ServiceResult<()> {
+        self.codes
+            .lock()
+            .map_err(|_| ServiceError::internal_error("Lock error"))?
+            .remove(user_id);
+        Ok(())
+    }
+}
+
+/// Login link for the token, if USER_LOGIN_LINK_URL is set
+pub fn login_link(link_token: &str) -> Option<String> {
+    std::env::var(LOGIN_LINK_URL_ENV)
+        .ok()
+        .filter(|url| !url.is_empty())
+        .map(|url| format!("{}?token={}", url, link_token))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redeem_code() {
+        let codes = LoginCodes::new();
+        let issued = codes.issue("demo").unwrap().unwrap();
+        assert_eq!(issued.code.len(), LOGIN_CODE_DIGITS as usize);
+        // Not resent right away
+        assert_eq!(codes.issue("demo").unwrap().is_none(), true);
+        assert_eq!(codes.redeem_code("other", &issued.code).unwrap(), false);
+        assert_eq!(codes.redeem_code("demo", &issued.code).unwrap(), true);
+        // Single use
+        assert_eq!(codes.redeem_code("demo", &issued.code).unwrap(), false);
+        assert_eq!(codes.redeem_link(&issued.link_token).unwrap(), None);
+    }
+
+    #[test]
+    fn test_redeem_link() {
+        let codes = LoginCodes::new();
+        let issued = codes.issue("demo").unwrap().unwrap();
+        assert_eq!(codes.redeem_link("wrong").unwrap(), None);
+        assert_eq!(
+            codes.redeem_link(&issued.link_token).unwrap(),
+            Some("demo".to_string())
+        );
+        assert_eq!(codes.redeem_link(&issued.link_token).unwrap(), None);
+        assert_eq!(codes.redeem_code("demo", &issued.code).unwrap(), false);
+    }
+
+    #[test]
+    fn test_max_attempts() {
+        let codes = LoginCodes::new();
+        let issued = codes.issue("demo").unwrap().unwrap();
+        let wrong = if issued.code == "000000" {
+            "000001"
+        } else {
+            "000000"
+        };
+        for _ in 0..LOGIN_CODE_MAX_ATTEMPTS {
+            assert_eq!(codes.redeem_code("demo", wrong).unwrap(), false);
+        }
+        // Invalid after too many wrong codes
+        assert_eq!(codes.redeem_code("demo", &issued.code).unwrap(), false);
+    }
+
+    // New code, as if the previous one was sent a while ago
+    fn issue_later(codes: &LoginCodes, user_id: &str) -> Option<IssuedCode> {
+        if let Some(open) = codes.codes.lock().unwrap().get_mut(user_id) {
+            open.date_created -= chrono::Duration::minutes(2);
+        }
+        codes.issue(user_id).unwrap()
+    }
+
+    #[test]
+    fn test_budget() {
+        let codes = LoginCodes::new();
+        // Wrong codes add up across codes
+        for _ in 0..LOGIN_CODE_MAX_FAILURES / LOGIN_CODE_MAX_ATTEMPTS {
+            let issued = issue_later(&codes, "demo").unwrap();
+            let wrong = if issued.code == "000000" {
+                "000001"
+            } else {
+                "000000"
+            };
+            for _ in 0..LOGIN_CODE_MAX_ATTEMPTS {
+                assert_eq!(codes.redeem_code("demo", wrong).unwrap(), false);
+            }
+        }
+        // Locked, no new code is sent
+        assert_eq!(issue_later(&codes, "demo").is_none(), true);
+        // Other users are not affected
+        let other = codes.issue("other").unwrap().unwrap();
+        assert_eq!(codes.redeem_code("other", &other.code).unwrap(), true);
+        // Issued codes are limited as well
+        let codes = LoginCodes::new();
+        let sent = (0..LOGIN_CODE_MAX_ISSUED + 5)
+            .filter_map(|_| issue_later(&codes, "demo"))
+            .count();
+        assert_eq!(sent, LOGIN_CODE_MAX_ISSUED as usize);
+    }
+}
@@ -1,32 +1,657 @@
+// Tests assert flags as `assert_eq!(x, true)`
+#![cfg_attr(test, allow(clippy::bool_assert_comparison))]
+
+use audit::AuditAction;
+use auth::{KeyOwner, Policy, Principal};
+use chrono::prelude::*;
 use prelude::*;
 use protos::user::user_server::*;
 use protos::user::*;
-use std::{path::PathBuf, sync::Mutex};
+use repository::UserQuery;
+use std::{
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
 use storaget::*;
+use template::Template;
+use tokio::sync::{broadcast, mpsc};
 use tonic::{transport::Server, Request, Response, Status};
+use watch::ChangeKind;
 
+pub mod api_key;
+pub mod audit;
+pub mod auth;
 pub mod convert;
+pub mod events;
+pub mod login_code;
+pub mod migrate;
+pub mod notifier;
+pub mod outbox;
 pub mod password;
+pub mod postgres;
 pub mod prelude;
+pub mod registration;
+pub mod repository;
+pub mod role;
+pub mod schema;
+pub mod search;
+pub mod service_account;
+pub mod sqlite;
+pub mod store;
+pub mod template;
 pub mod user;
+pub mod watch;
+pub mod webhook;
+
+pub mod protos {
+    pub mod user {
+        tonic::include_proto!("user");
+    }
+}
+
+// Default number of search hits
+const SEARCH_LIMIT: usize = 20;
+// Number of change events kept for resuming watchers
+const CHANGE_LOG_CAPACITY: usize = 1024;
+// Append-only audit log file
+const AUDIT_LOG_PATH: &str = "data/audit.log";
+// Actor of public calls without credentials
+const ANONYMOUS: &str = "anonymous";
+// Deliveries returned by ListWebhookDeliveries if no limit is given
+const WEBHOOK_DELIVERIES_DEFAULT_LIMIT: usize = 100;
+// A new verification code is not sent within this, in seconds
+const VERIFICATION_RESEND_SECONDS: i64 = 60;
+// A new password reset code is not sent within this, in seconds
+const PASSWORD_RESET_RESEND_SECONDS: i64 = 60;
+
+// Message to the user, with its name and ID filled in as well
+fn render(
+    templates: &template::Templates,
+    user: &user::User,
+    template: Template,
+    values: &[(&str, &str)],
+) -> notifier::Message {
+    let mut values = values.to_vec();
+    values.push(("name", user.get_user_name()));
+    values.push(("userid", user.get_user_id()));
+    templates.render(template, user.get_user_email(), &values)
+}
+
+// Send a password reset code to every active user of the address.
+// Run in the background by ResetPassword, so its answer takes the
+// same time whether the address is known or not.
+async fn send_password_resets(
+    users: &store::UserStore,
+    outbox: &outbox::Outbox,
+    audit_log: &audit::AuditWriter,
+    templates: &template::Templates,
+    email: String,
+) -> ServiceResult<()> {
+    let active_by_email = UserQuery {
+        status: Some(user::UserStatus::Active),
+        email: Some(email),
+        ..UserQuery::default()
+    };
+    // No new code is sent right after the previous one
+    let resend_after = Utc::now() - chrono::Duration::seconds(PASSWORD_RESET_RESEND_SECONDS);
+    let mut resets = Vec::new();
+    {
+        let writer = users.writer().await;
+        for mut user in writer.query(active_by_email).await? {
+            if user
+                .get_password_reset()
+                .is_some_and(|r| r.date_created > resend_after)
+            {
+                continue;
+            }
+            let token = user.request_password_reset()?;
+            // Not a user change, so no new version or history entry
+            writer.update(user.clone()).await?;
+            resets.push((user, token));
+        }
+    }
+    for (user, token) in resets {
+        audit_log
+            .append(
+                ANONYMOUS,
+                AuditAction::PasswordResetRequested,
+                user.get_user_id(),
+                "",
+            )
+            .await?;
+        let expires_at = user
+            .get_password_reset()
+            .map(|r| r.expires_at.to_rfc3339())
+            .unwrap_or_default();
+        let message = render(
+            templates,
+            &user,
+            Template::PasswordReset,
+            &[
+                ("token", token.as_str()),
+                ("expires_at", expires_at.as_str()),
+            ],
+        );
+        // Delivered by the outbox, retried if the email service fails
+        outbox.enqueue(message).await?;
+    }
+    Ok(())
+}
+
+fn invitation_expires_at(user: &user::User) -> String {
+    user.get_invitation()
+        .map(|i| i.expires_at.to_rfc3339())
+        .unwrap_or_default()
+}
+
+// Expiration of keys and tokens, empty means never
+fn parse_expiration(value: &str) -> ServiceResult<Option<DateTime<Utc>>> {
+    match value.is_empty() {
+        true => Ok(None),
+        false => DateTime::parse_from_rfc3339(value)
+            .map(|date| Some(date.with_timezone(&Utc)))
+            .map_err(|_| ServiceError::bad_request("Dates must be RFC 3339 formatted")),
+    }
+}
 
 pub struct UserService {
-    users: Mutex<VecPack<user::User>>,
+    users: Arc<store::UserStore>,
+    // Written on the blocking thread pool
+    roles: Arc<Mutex<VecPack<role::Role>>>,
+    service_accounts: Arc<Mutex<VecPack<service_account::ServiceAccount>>>,
+    search_index: Mutex<search::SearchIndex>,
+    changes: Mutex<watch::ChangeLog>,
+    audit_log: audit::AuditWriter,
+    auth: Arc<auth::Authenticator>,
+    outbox: Arc<outbox::Outbox>,
+    webhooks: Arc<webhook::Webhooks>,
+    events: Arc<dyn events::EventPublisher>,
+    registration: registration::RegistrationRules,
+    login_codes: login_code::LoginCodes,
+    templates: template::Templates,
 }
 
 impl UserService {
-    fn new(users: Mutex<VecPack<user::User>>) -> Self {
-        Self { users }
+    // Store the last use of an API key or personal access token.
+    // The authenticator tells when it is worth a storage write.
+    fn record_key_use(&self, principal: &Principal) -> ServiceResult<()> {
+        match principal {
+            Principal::ServiceAccount { id, key_id } => {
+                if let Some(date) = self.auth.touch_api_key(key_id)? {
+                    // Stored in the background, the request does not wait for it
+                    let (service_accounts, id, key_id) =
+                        (self.service_accounts.clone(), id.clone(), key_id.clone());
+                    tokio::spawn(async move {
+                        let result = blocking(&service_accounts, move |service_accounts| {
+                            if let Ok(account) = service_accounts.find_id_mut(&id) {
+                                account.update(|a| {
+                                    a.touch_api_key(&key_id, date);
+                                })?;
+                            }
+                            Ok(())
+                        })
+                        .await;
+                        if let Err(err) = result {
+                            eprintln!("Error while storing API key use: {}", err);
+                        }
+                    });
+                }
+            }
+            Principal::UserToken { id, key_id } => {
+                if let Some(date) = self.auth.touch_api_key(key_id)? {
+                    // Not a user change, so no new version or history entry.
+                    // Stored in the background, the request does not wait for it.
+                    let (users, id, key_id) = (self.users.clone(), id.clone(), key_id.clone());
+                    tokio::spawn(async move {
+                        let writer = users.writer().await;
+                        if let Ok(Some(mut user)) = writer.fresh(&id).await {
+                            user.touch_token(&key_id, date);
+                            if let Err(err) = writer.update(user).await {
+                                eprintln!("Error while storing token use: {}", err);
+                            }
+                        }
+                    });
+                }
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+    // Every storage and integration of the service is wired in here
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        users: store::UserStore,
+        roles: VecPack<role::Role>,
+        mut service_accounts: VecPack<service_account::ServiceAccount>,
+        audit_log: audit::AuditLog,
+        auth: Arc<auth::Authenticator>,
+        outbox: Arc<outbox::Outbox>,
+        webhooks: Arc<webhook::Webhooks>,
+        events: Arc<dyn events::EventPublisher>,
+        registration: registration::RegistrationRules,
+        templates: template::Templates,
+    ) -> ServiceResult<Self> {
+        let mut search_index = search::SearchIndex::new();
+        for user in users.snapshot().query(&UserQuery::default()) {
+            search_index.insert(user);
+            for token in user.get_tokens() {
+                auth.register_api_key(KeyOwner::User(user.get_user_id().to_string()), token)?;
+            }
+        }
+        for account in service_accounts.into_iter() {
+            let account = account.unpack();
+            for api_key in account.get_api_keys() {
+                auth.register_api_key(
+                    KeyOwner::ServiceAccount(account.get_id().to_string()),
+                    api_key,
+                )?;
+            }
+        }
+        Ok(Self {
+            users: Arc::new(users),
+            roles: Arc::new(Mutex::new(roles)),
+            service_accounts: Arc::new(Mutex::new(service_accounts)),
+            search_index: Mutex::new(search_index),
+            changes: Mutex::new(watch::ChangeLog::new(CHANGE_LOG_CAPACITY)),
+            audit_log: audit::AuditWriter::start(audit_log),
+            auth,
+            outbox,
+            webhooks,
+            events,
+            registration,
+            login_codes: login_code::LoginCodes::new(),
+            templates,
+        })
+    }
+    // Check the caller against the policy of the method.
+    // Target is the user the request acts on, if any.
+    // Returns the caller ID, recorded in the history and audit log.
+    fn authorize<T>(
+        &self,
+        request: &Request<T>,
+        method: &str,
+        target: Option<&str>,
+    ) -> ServiceResult<String> {
+        let (policy, principal) = match (auth::policy(method), auth::principal(request)) {
+            (Policy::Public, principal) => {
+                return Ok(principal
+                    .map(|p| p.get_id())
+                    .unwrap_or_else(|| ANONYMOUS.to_string()))
+            }
+            (_, None) => return Err(ServiceError::unauthenticated("Authentication required")),
+            (policy, Some(principal)) => (policy, principal),
+        };
+        // API keys and personal access tokens grant their scopes only
+        if let Principal::ServiceAccount { key_id, .. } | Principal::UserToken { key_id, .. } =
+            &principal
+        {
+            let permitted = match policy {
+                Policy::Public | Policy::Authenticated => true,
+                Policy::Permission(permission) => self.auth.api_key_grants(key_id, permission)?,
+                // Service accounts have no user to act as, so
+                // acting on an admin needs the admin scope
+                Policy::SelfOrPermission(permission) => {
+                    self.auth.api_key_grants(key_id, permission)?
+                        && (matches!(principal, Principal::UserToken { .. })
+                            || !self.is_admin(target)
+                            || self.auth.api_key_grants(key_id, role::USER_ADMIN)?)
+                }
+            };
+            if !permitted {
+                return Err(ServiceError::permission_denied(&format!(
+                    "API key is not permitted to call {}",
+                    method
+                )));
+            }
+            self.record_key_use(&principal)?;
+        }
+        let user_id = match &principal {
+            // Internal services are trusted with every permission
+            Principal::Service(_) | Principal::ServiceAccount { .. } => {
+                return Ok(principal.get_id())
+            }
+            // Tokens act with the permissions of their user as well
+            Principal::User(id) | Principal::UserToken { id, .. } => id,
+        };
+        let is_active = self
+            .users
+            .snapshot()
+            .get(user_id)
+            .map(|u| u.is_active())
+            .unwrap_or(false);
+        if !is_active {
+            return Err(ServiceError::unauthenticated("User not found"));
+        }
+        let permitted = match policy {
+            Policy::Public | Policy::Authenticated => true,
+            Policy::Permission(permission) => self.check_permission(user_id, permission, None)?,
+            Policy::SelfOrPermission(permission) => {
+                target == Some(user_id.as_str())
+                    || (self.check_permission(user_id, permission, None)?
+                        && self.may_act_on(user_id, target)?)
+            }
+        };
+        match permitted {
+            true => Ok(principal.get_id()),
+            false => Err(ServiceError::permission_denied(&format!(
+                "{} is not permitted to call {}",
+                user_id, method
+            ))),
+        }
+    }
+    // Acting on an admin needs admin permission, so e.g. user:write
+    // is not enough to take over an admin account
+    fn may_act_on(&self, user_id: &str, target: Option<&str>) -> ServiceResult<bool> {
+        match self.is_admin(target) {
+            true => self.check_permission(user_id, role::USER_ADMIN, None),
+            false => Ok(true),
+        }
+    }
+    fn is_admin(&self, target: Option<&str>) -> bool {
+        match target {
+            Some(target) => self
+                .check_permission(target, role::USER_ADMIN, None)
+                .unwrap_or(false),
+            None => false,
+        }
+    }
+    async fn audit(
+        &self,
+        actor: &str,
+        action: AuditAction,
+        user_id: &str,
+        details: &str,
+    ) -> ServiceResult<()> {
+        self.audit_log
+            .append(actor, action, user_id, details)
+            .await?;
+        Ok(())
+    }
+    async fn create_new_user(&self, u: CreateNewRequest, actor: &str) -> ServiceResult<UserObj> {
+        let customer_id = match u.customer_id.is_empty() {
+            true => None,
+            false => Some(u.customer_id),
+        };
+        let new_user = user::User::new(
+            u.username,
+            u.name,
+            u.email,
+            u.phone,
+            actor.to_string(),
+            customer_id,
+        )?;
+        self.insert_user(new_user, actor, AuditAction::UserCreated, false)
+            .await
+    }
+    async fn create_invited_user(
+        &self,
+        r: InviteUserRequest,
+        actor: &str,
+    ) -> ServiceResult<InviteUserResponse> {
+        let customer_id = match r.customer_id.is_empty() {
+            true => None,
+            false => Some(r.customer_id),
+        };
+        let mut new_user = user::User::new(
+            r.username,
+            r.name,
+            r.email,
+            r.phone,
+            actor.to_string(),
+            customer_id,
+        )?;
+        let token = new_user.invite(actor)?;
+        let user = self
+            .insert_user(new_user.clone(), actor, AuditAction::UserInvited, false)
+            .await?;
+        self.send_invitation(&new_user, &token).await?;
+        Ok(InviteUserResponse {
+            user: Some(user),
+            invitation_expires_at: invitation_expires_at(&new_user),
+        })
     }
-    fn create_new_user(&self, u: CreateNewRequest) -> ServiceResult<UserObj> {
-        if let Ok(_) = self.users.lock().unwrap().find_id(&u.username) {
+    // Unique email means no other user, deleted ones aside,
+    // can have the same email address
+    async fn insert_user(
+        &self,
+        new_user: user::User,
+        actor: &str,
+        action: AuditAction,
+        unique_email: bool,
+    ) -> ServiceResult<UserObj> {
+        // Keep the writer till the change is published,
+        // so revisions follow the storage order
+        let writer = self.users.writer().await;
+        if writer.fresh(new_user.get_user_id()).await?.is_some() {
             return Err(ServiceError::already_exist("User exist!"));
         }
-        let new_user = user::User::new(u.username, u.name, u.email, u.phone, u.created_by)?;
+        let by_email = UserQuery {
+            email: Some(new_user.get_user_email().to_string()),
+            ..UserQuery::default()
+        };
+        if unique_email && !writer.query(by_email).await?.is_empty() {
+            return Err(ServiceError::already_exist("Email address is already used"));
+        }
         let user_obj: UserObj = (&new_user).into();
-        self.users.lock().unwrap().insert(new_user)?;
+        writer.insert(new_user.clone()).await?;
+        self.search_index.lock().unwrap().insert(&new_user);
+        self.changes
+            .lock()
+            .unwrap()
+            .publish(ChangeKind::Created, &new_user);
+        self.emit(webhook::USER_CREATED, &new_user, actor).await?;
+        self.audit(actor, action, new_user.get_user_id(), "")
+            .await?;
         Ok(user_obj)
     }
+    async fn send_invitation(&self, user: &user::User, token: &str) -> ServiceResult<()> {
+        let expires_at = invitation_expires_at(user);
+        let message = self.render(
+            user,
+            Template::Invitation,
+            &[("token", token), ("expires_at", expires_at.as_str())],
+        );
+        self.outbox.enqueue(message).await?;
+        Ok(())
+    }
+    async fn send_verification(&self, user: &user::User, token: &str) -> ServiceResult<()> {
+        let expires_at = user
+            .get_verification()
+            .map(|v| v.expires_at.to_rfc3339())
+            .unwrap_or_default();
+        let message = self.render(
+            user,
+            Template::EmailVerification,
+            &[("token", token), ("expires_at", expires_at.as_str())],
+        );
+        self.outbox.enqueue(message).await?;
+        Ok(())
+    }
+    // Active user able to log in, if any
+    fn find_active_user(&self, userid: &str) -> ServiceResult<Option<user::User>> {
+        Ok(self
+            .users
+            .snapshot()
+            .get(userid)
+            .filter(|u| u.is_active())
+            .cloned())
+    }
+    // Session for an authenticated user, the same for every login method
+    async fn open_session(&self, user: &user::User, method: &str) -> ServiceResult<LoginResponse> {
+        let userid = user.get_user_id();
+        let (token, expires_at) = self.auth.create_session(userid)?;
+        // An open login code is not needed anymore
+        self.login_codes.revoke(userid)?;
+        self.audit(userid, AuditAction::Login, userid, method)
+            .await?;
+        Ok(LoginResponse {
+            token,
+            expires_at: expires_at.to_rfc3339(),
+            user: Some(user.into()),
+        })
+    }
+    async fn set_user_role(
+        &self,
+        userid: &str,
+        role_id: &str,
+        assign: bool,
+        actor: &str,
+    ) -> ServiceResult<UserObj> {
+        self.ensure_role(role_id)?;
+        let user = self
+            .modify_user(userid, actor, |u| match assign {
+                true => u.add_role(role_id),
+                false => u.remove_role(role_id),
+            })
+            .await?;
+        let details = match assign {
+            true => format!("role assigned: {}", role_id),
+            false => format!("role revoked: {}", role_id),
+        };
+        self.audit(actor, AuditAction::UserUpdated, userid, &details)
+            .await?;
+        Ok(user.into())
+    }
+    fn ensure_role(&self, role_id: &str) -> ServiceResult<()> {
+        match self.roles.lock().unwrap().find_id(role_id) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(ServiceError::not_found("Role not found")),
+        }
+    }
+    // Apply change on a copy of an existing user, then store it,
+    // reindex it and notify the watchers. Returns the updated user.
+    async fn modify_user<F>(
+        &self,
+        userid: &str,
+        actor: &str,
+        change: F,
+    ) -> ServiceResult<user::User>
+    where
+        F: FnOnce(&mut user::User) -> ServiceResult<()>,
+    {
+        let writer = self.users.writer().await;
+        let user = writer
+            .fresh(userid)
+            .await?
+            .filter(|u| !u.is_deleted())
+            .ok_or_else(|| ServiceError::not_found("User not found"))?;
+        let mut updated = user.clone();
+        change(&mut updated)?;
+        let user = user::commit(&user, updated, actor);
+        writer.update(user.clone()).await?;
+        self.search_index.lock().unwrap().insert(&user);
+        self.changes
+            .lock()
+            .unwrap()
+            .publish(ChangeKind::Updated, &user);
+        self.emit(webhook::USER_UPDATED, &user, actor).await?;
+        Ok(user)
+    }
+    // Customer is optional; when set, the user's role
+    // at the customer is checked as well
+    fn check_permission(
+        &self,
+        userid: &str,
+        permission: &str,
+        customer_id: Option<&str>,
+    ) -> ServiceResult<bool> {
+        role::validate_permission(permission)?;
+        let user_roles = {
+            let users = self.users.snapshot();
+            let user = users
+                .get(userid)
+                .ok_or_else(|| ServiceError::not_found("User not found"))?;
+            // Deleted users have no permission at all
+            if user.is_deleted() {
+                return Ok(false);
+            }
+            let mut user_roles = user.get_roles().clone();
+            if let Some(membership) = customer_id.and_then(|c| user.get_membership(c)) {
+                user_roles.push(membership.role.to_string());
+            }
+            user_roles
+        };
+        let roles = self.roles.lock().unwrap();
+        Ok(user_roles
+            .iter()
+            .filter_map(|role_id| roles.find_id(role_id).ok())
+            .any(|role| role.unpack().grants(permission)))
+    }
+    // Delete is the change marking the user as deleted,
+    // e.g. a plain delete or a rejected registration
+    async fn delete_user<F>(
+        &self,
+        userid: &str,
+        actor: &str,
+        delete: F,
+    ) -> ServiceResult<user::User>
+    where
+        F: FnOnce(&mut user::User) -> ServiceResult<()>,
+    {
+        let writer = self.users.writer().await;
+        let user = writer
+            .fresh(userid)
+            .await?
+            .ok_or_else(|| ServiceError::not_found("User not found"))?;
+        let mut deleted = user.clone();
+        delete(&mut deleted)?;
+        let user = user::commit(&user, deleted, actor);
+        writer.update(user.clone()).await?;
+        self.search_index.lock().unwrap().remove(userid);
+        self.changes
+            .lock()
+            .unwrap()
+            .publish(ChangeKind::Deleted, &user);
+        self.emit(webhook::USER_DELETED, &user, actor).await?;
+        Ok(user)
+    }
+    // Webhook deliveries and broker event of a user lifecycle event.
+    // The change is stored already, so a broker failure is only logged.
+    // Publishing may wait for the broker, so it runs on the blocking thread pool
+    async fn emit(&self, event: &str, user: &user::User, actor: &str) -> ServiceResult<()> {
+        let (publisher, name, published, published_by) = (
+            self.events.clone(),
+            event.to_string(),
+            user.clone(),
+            actor.to_string(),
+        );
+        let result = tokio::task::spawn_blocking(move || {
+            events::publish_user_event(publisher.as_ref(), &name, &published, &published_by)
+        })
+        .await
+        .map_err(|e| ServiceError::internal_error(&format!("Publish task failed: {}", e)))
+        .and_then(|result| result);
+        if let Err(err) = result {
+            eprintln!("Error while publishing {} event: {}", event, err);
+        }
+        self.webhooks
+            .emit(
+                event,
+                serde_json::json!({
+                    "user_id": user.get_user_id(),
+                    "version": user.get_version(),
+                    "actor": actor,
+                }),
+            )
+            .await
+    }
+    // Notify the user when it is not essential for the request,
+    // so a failure to queue the message is only logged
+    async fn notify_user(&self, user: &user::User, template: Template, values: &[(&str, &str)]) {
+        let message = self.render(user, template, values);
+        if let Err(err) = self.outbox.enqueue(message).await {
+            eprintln!("Error while notifying {}: {}", user.get_user_id(), err);
+        }
+    }
+    // Message to the user, name and user ID are always filled in
+    fn render(
+        &self,
+        user: &user::User,
+        template: Template,
+        values: &[(&str, &str)],
+    ) -> notifier::Message {
+        render(&self.templates, user, template, values)
+    }
 }
 
 #[tonic::async_trait]
@@ -35,61 +660,174 @@ impl User for UserService {
         &self,
         request: Request<CreateNewRequest>,
     ) -> Result<Response<CreateNewResponse>, Status> {
+        let actor = self.authorize(&request, "create_new", None)?;
         Ok(Response::new(CreateNewResponse {
-            user: Some(self.create_new_user(request.into_inner())?),
+            user: Some(self.create_new_user(request.into_inner(), &actor).await?),
         }))
     }
-    async fn get_all(&self, _request: Request<()>) -> Result<Response<GetAllResponse>, Status> {
-        println!("New get all");
+    async fn get_all(&self, request: Request<()>) -> Result<Response<GetAllResponse>, Status> {
+        let actor = self.authorize(&request, "get_all", None)?;
+        self.audit(&actor, AuditAction::UserRead, "", "get_all")
+            .await?;
+        // Changes are published after they are stored, so the users
+        // read after the revision have every change up to it
+        let (revision, epoch) = {
+            let changes = self
+                .changes
+                .lock()
+                .map_err(|_| Status::internal("Lock error"))?;
+            (changes.get_revision(), changes.get_epoch().to_string())
+        };
         let users = self
             .users
-            .lock()
-            .map_err(|_| Status::internal("Lock error"))?
+            .snapshot()
+            .query(&UserQuery::default())
             .into_iter()
-            .map(|i: &mut Pack<user::User>| i.unpack().into())
+            .map(|u| u.into())
             .collect::<Vec<UserObj>>();
-        let response = GetAllResponse { users: users };
+        let response = GetAllResponse {
+            users,
+            revision,
+            epoch,
+        };
         return Ok(Response::new(response));
     }
     async fn get_by_id(
         &self,
         request: Request<GetByIdRequest>,
     ) -> Result<Response<GetByIdResponse>, Status> {
+        let actor = self.authorize(
+            &request,
+            "get_by_id",
+            Some(request.get_ref().userid.as_str()),
+        )?;
+        let userid = request.into_inner().userid;
+        self.audit(&actor, AuditAction::UserRead, &userid, "get_by_id")
+            .await?;
         let user: UserObj = self
             .users
-            .lock()
-            .map_err(|_| Status::internal("lock error"))?
-            .find_id(&request.into_inner().userid)
-            .map_err(|_| Status::not_found("User not found"))?
-            .unpack()
+            .snapshot()
+            .get(&userid)
+            .filter(|u| !u.is_deleted())
+            .ok_or_else(|| Status::not_found("User not found"))?
             .into();
         let response = GetByIdResponse { user: Some(user) };
         return Ok(Response::new(response));
     }
+    async fn batch_get_users(
+        &self,
+        request: Request<BatchGetUsersRequest>,
+    ) -> Result<Response<BatchGetUsersResponse>, Status> {
+        let actor = self.authorize(&request, "batch_get_users", None)?;
+        let mut userids = request.into_inner().userids;
+        // Remove duplicates but keep the request order
+        let mut seen = std::collections::HashSet::new();
+        userids.retain(|id| seen.insert(id.clone()));
+        let mut users: Vec<UserObj> = Vec::with_capacity(userids.len());
+        let mut missing_ids: Vec<String> = Vec::new();
+        // The same snapshot for the whole batch
+        let snapshot = self.users.snapshot();
+        for userid in userids {
+            match snapshot.get(&userid) {
+                Some(u) if !u.is_deleted() => users.push(u.into()),
+                _ => missing_ids.push(userid),
+            }
+        }
+        // One entry for the whole batch
+        if !users.is_empty() {
+            let userids = users
+                .iter()
+                .map(|u| u.id.as_str())
+                .collect::<Vec<&str>>()
+                .join(", ");
+            self.audit(
+                &actor,
+                AuditAction::UserRead,
+                "",
+                &format!("batch_get_users: {}", userids),
+            )
+            .await?;
+        }
+        let response = BatchGetUsersResponse { users, missing_ids };
+        return Ok(Response::new(response));
+    }
+    async fn get_user_history(
+        &self,
+        request: Request<GetUserHistoryRequest>,
+    ) -> Result<Response<GetUserHistoryResponse>, Status> {
+        let actor = self.authorize(
+            &request,
+            "get_user_history",
+            Some(request.get_ref().userid.as_str()),
+        )?;
+        let userid = request.into_inner().userid;
+        self.audit(&actor, AuditAction::UserRead, &userid, "get_user_history")
+            .await?;
+        // Deleted users have history as well
+        let entries = self
+            .users
+            .snapshot()
+            .get(&userid)
+            .ok_or_else(|| Status::not_found("User not found"))?
+            .get_history()
+            .iter()
+            .map(|entry| entry.into())
+            .collect::<Vec<UserHistoryEntry>>();
+        let response = GetUserHistoryResponse { entries };
+        return Ok(Response::new(response));
+    }
     async fn update_by_id(
         &self,
         request: Request<UpdateByIdRequest>,
     ) -> Result<Response<UpdateByIdResponse>, Status> {
-        let _user: UserObj = match request.into_inner().user {
+        let actor = self.authorize(
+            &request,
+            "update_by_id",
+            request.get_ref().user.as_ref().map(|u| u.id.as_str()),
+        )?;
+        let UpdateByIdRequest { user, update_mask } = request.into_inner();
+        let _user: UserObj = match user {
             Some(u) => u,
             None => return Err(Status::internal("Request has an empty user object")),
         };
-        let mut lock = self
-            .users
+        let paths = update_mask.map(|mask| mask.paths).unwrap_or_default();
+        let writer = self.users.writer().await;
+        let user = writer
+            .fresh(&_user.id)
+            .await?
+            .filter(|u| !u.is_deleted())
+            .ok_or_else(|| Status::not_found("User not found"))?;
+        user.check_version(_user.version)?;
+        // Validate on a copy, so a wrong field cannot leave
+        // the stored user half updated
+        let mut updated = user.clone();
+        updated.update_fields(&_user, &paths)?;
+        let changed_fields = user
+            .diff(&updated)
+            .into_iter()
+            .map(|change| change.field)
+            .collect::<Vec<String>>()
+            .join(", ");
+        let user = user::commit(&user, updated, &actor);
+        writer.update(user.clone()).await?;
+        self.search_index
             .lock()
-            .map_err(|_| Status::internal("Mutex lock error"))?;
-        let user = match lock.find_id_mut(&_user.id) {
-            Ok(u) => u,
-            Err(err) => return Err(Status::not_found(format!("{}", err))),
-        };
-        user.update(|u| {
-            u.set_user_name(_user.name.to_string()).unwrap();
-            u.set_user_email(_user.email.to_string()).unwrap();
-            u.set_user_phone(_user.phone.to_string()).unwrap();
-        })
-        .map_err(|_| Status::internal("Error while updating user object"))?;
+            .map_err(|_| Status::internal("Mutex lock error"))?
+            .insert(&user);
+        self.changes
+            .lock()
+            .map_err(|_| Status::internal("Mutex lock error"))?
+            .publish(ChangeKind::Updated, &user);
+        self.emit(webhook::USER_UPDATED, &user, &actor).await?;
+        self.audit(
+            &actor,
+            AuditAction::UserUpdated,
+            &_user.id,
+            &format!("fields: {}", changed_fields),
+        )
+        .await?;
         let response = UpdateByIdResponse {
-            user: Some(user.unpack().into()),
+            user: Some(user.into()),
         };
         return Ok(Response::new(response));
     }
@@ -97,44 +835,1387 @@ impl User for UserService {
         &self,
         request: Request<IsUserRequest>,
     ) -> Result<Response<IsUserResponse>, Status> {
-        let is_user = match self
-            .users
-            .lock()
-            .map_err(|_| Status::internal("Error while locking"))?
-            .find_id(&request.into_inner().userid)
-        {
-            Ok(_) => true,
-            Err(_) => false,
+        self.authorize(&request, "is_user", None)?;
+        let is_user = match self.users.snapshot().get(&request.into_inner().userid) {
+            Some(u) => !u.is_deleted(),
+            None => false,
         };
         let response = IsUserResponse {
             user_exist: is_user,
         };
         return Ok(Response::new(response));
     }
+    async fn login(
+        &self,
+        request: Request<LoginRequest>,
+    ) -> Result<Response<LoginResponse>, Status> {
+        self.authorize(&request, "login", None)?;
+        let LoginRequest { userid, password } = request.into_inner();
+        let user = self.find_active_user(&userid)?;
+        // Same answer for unknown user and wrong password
+        let user = match user {
+            Some(user)
+                if password::verify_password_from_hash(&password, user.get_password_hash())
+                    .unwrap_or(false) =>
+            {
+                user
+            }
+            _ => {
+                self.audit(&userid, AuditAction::LoginFailed, &userid, "")
+                    .await?;
+                return Err(Status::unauthenticated("Wrong user ID or password"));
+            }
+        };
+        let response = self.open_session(&user, "password").await?;
+        return Ok(Response::new(response));
+    }
+    async fn request_login_code(
+        &self,
+        request: Request<RequestLoginCodeRequest>,
+    ) -> Result<Response<RequestLoginCodeResponse>, Status> {
+        self.authorize(&request, "request_login_code", None)?;
+        let userid = request.into_inner().userid;
+        // Same answer for unknown users, so users cannot be probed
+        let user = match self.find_active_user(&userid)? {
+            Some(user) => user,
+            None => return Ok(Response::new(RequestLoginCodeResponse {})),
+        };
+        // None if a code was just sent
+        if let Some(issued) = self.login_codes.issue(&userid)? {
+            let expires_at = issued.expires_at.to_rfc3339();
+            let link = login_code::login_link(&issued.link_token);
+            let mut values = vec![
+                ("code", issued.code.as_str()),
+                ("expires_at", expires_at.as_str()),
+            ];
+            let template = match &link {
+                Some(link) => {
+                    values.push(("link", link.as_str()));
+                    Template::LoginLink
+                }
+                None => Template::LoginCode,
+            };
+            let message = self.render(&user, template, &values);
+            self.outbox.enqueue(message).await?;
+            self.audit(&userid, AuditAction::LoginCodeRequested, &userid, "")
+                .await?;
+        }
+        Ok(Response::new(RequestLoginCodeResponse {}))
+    }
+    async fn login_with_code(
+        &self,
+        request: Request<LoginWithCodeRequest>,
+    ) -> Result<Response<LoginResponse>, Status> {
+        self.authorize(&request, "login_with_code", None)?;
+        let LoginWithCodeRequest { userid, code } = request.into_inner();
+        let user = match self.find_active_user(&userid)? {
+            Some(user) if self.login_codes.redeem_code(&userid, &code)? => user,
+            _ => {
+                self.audit(&userid, AuditAction::LoginFailed, &userid, "code")
+                    .await?;
+                return Err(Status::unauthenticated("Wrong user ID or login code"));
+            }
+        };
+        let response = self.open_session(&user, "code").await?;
+        return Ok(Response::new(response));
+    }
+    async fn login_with_link(
+        &self,
+        request: Request<LoginWithLinkRequest>,
+    ) -> Result<Response<LoginResponse>, Status> {
+        self.authorize(&request, "login_with_link", None)?;
+        let token = request.into_inner().token;
+        let user = match self.login_codes.redeem_link(&token)? {
+            Some(userid) => self.find_active_user(&userid)?,
+            None => None,
+        };
+        let user = match user {
+            Some(user) => user,
+            None => {
+                self.audit(ANONYMOUS, AuditAction::LoginFailed, "", "link")
+                    .await?;
+                return Err(Status::unauthenticated("Invalid or expired login link"));
+            }
+        };
+        let response = self.open_session(&user, "link").await?;
+        return Ok(Response::new(response));
+    }
+    async fn set_password(
+        &self,
+        request: Request<SetPasswordRequest>,
+    ) -> Result<Response<SetPasswordResponse>, Status> {
+        let actor = self.authorize(
+            &request,
+            "set_password",
+            Some(request.get_ref().userid.as_str()),
+        )?;
+        let SetPasswordRequest {
+            userid,
+            password,
+            current_password,
+        } = request.into_inner();
+        // A session or token alone is not enough to take over the account
+        let user = self
+            .modify_user(&userid, &actor, |u| match actor == userid {
+                true => u.change_password(&current_password, password),
+                false => u.set_password(password),
+            })
+            .await;
+        if let Err(ServiceError::PermissionDenied(_)) = &user {
+            self.audit(&actor, AuditAction::LoginFailed, &userid, "set_password")
+                .await?;
+        }
+        let user = user?;
+        // Log out everywhere with the old password
+        self.auth.revoke_sessions(&userid)?;
+        self.emit(webhook::PASSWORD_CHANGED, &user, &actor).await?;
+        self.audit(&actor, AuditAction::PasswordChanged, &userid, "")
+            .await?;
+        Ok(Response::new(SetPasswordResponse {}))
+    }
     async fn reset_password(
         &self,
-        _request: Request<ReserPasswordRequest>,
+        request: Request<ReserPasswordRequest>,
     ) -> Result<Response<ReserPasswordResponse>, Status> {
-        todo!()
+        self.authorize(&request, "reset_password", None)?;
+        let email = request.into_inner().email.trim().to_lowercase();
+        // Same answer right away for every address, so users cannot be
+        // probed by the answer or its time. The code is sent in the
+        // background, and the password changes only when it is used.
+        if !email.is_empty() {
+            let (users, outbox, audit_log, templates) = (
+                self.users.clone(),
+                self.outbox.clone(),
+                self.audit_log.clone(),
+                self.templates.clone(),
+            );
+            tokio::spawn(async move {
+                if let Err(err) =
+                    send_password_resets(&users, &outbox, &audit_log, &templates, email).await
+                {
+                    eprintln!("Error while sending password reset: {}", err);
+                }
+            });
+        }
+        Ok(Response::new(ReserPasswordResponse {}))
+    }
+    async fn complete_password_reset(
+        &self,
+        request: Request<CompletePasswordResetRequest>,
+    ) -> Result<Response<CompletePasswordResetResponse>, Status> {
+        self.authorize(&request, "complete_password_reset", None)?;
+        let r = request.into_inner();
+        let userid = r.userid.to_lowercase();
+        // The same error for unknown users and wrong codes
+        let user = self
+            .modify_user(&userid, &userid, |u| u.reset_password(&r.token, r.password))
+            .await
+            .map_err(|err| match err {
+                ServiceError::NotFound(_) => {
+                    ServiceError::not_found("A jelszó visszaállító kód nem található")
+                }
+                err => err,
+            })?;
+        // Log out everywhere with the old password
+        self.auth.revoke_sessions(&userid)?;
+        self.emit(webhook::PASSWORD_CHANGED, &user, &userid).await?;
+        self.audit(&userid, AuditAction::PasswordReset, &userid, "")
+            .await?;
+        Ok(Response::new(CompletePasswordResetResponse {}))
+    }
+    async fn search_users(
+        &self,
+        request: Request<SearchUsersRequest>,
+    ) -> Result<Response<SearchUsersResponse>, Status> {
+        let actor = self.authorize(&request, "search_users", None)?;
+        let SearchUsersRequest { query, limit } = request.into_inner();
+        self.audit(
+            &actor,
+            AuditAction::UserRead,
+            "",
+            &format!("search_users: {}", query),
+        )
+        .await?;
+        let limit = match limit {
+            0 => SEARCH_LIMIT,
+            limit => limit as usize,
+        };
+        // Do not hold the index lock while looking up users
+        let hits = self
+            .search_index
+            .lock()
+            .map_err(|_| Status::internal("Lock error"))?
+            .search(&query, limit);
+        let users = self.users.snapshot();
+        let hits = hits
+            .into_iter()
+            .filter_map(|hit: search::SearchHit| {
+                let user: UserObj = users.get(&hit.user_id)?.into();
+                Some(SearchHit {
+                    user: Some(user),
+                    score: hit.score,
+                    highlights: hit.highlights.into_iter().map(|h| h.into()).collect(),
+                })
+            })
+            .collect::<Vec<SearchHit>>();
+        let response = SearchUsersResponse { hits };
+        return Ok(Response::new(response));
+    }
+    async fn query_audit_log(
+        &self,
+        request: Request<QueryAuditLogRequest>,
+    ) -> Result<Response<QueryAuditLogResponse>, Status> {
+        self.authorize(&request, "query_audit_log", None)?;
+        let r = request.into_inner();
+        // Empty string means no filter
+        let non_empty = |value: String| match value.is_empty() {
+            true => None,
+            false => Some(value),
+        };
+        let parse_date = |value: String| -> ServiceResult<Option<DateTime<Utc>>> {
+            match non_empty(value) {
+                Some(value) => DateTime::parse_from_rfc3339(&value)
+                    .map(|date| Some(date.with_timezone(&Utc)))
+                    .map_err(|_| ServiceError::bad_request("Dates must be RFC 3339 formatted")),
+                None => Ok(None),
+            }
+        };
+        let filter = audit::AuditFilter {
+            from: parse_date(r.from)?,
+            till: parse_date(r.till)?,
+            actor: non_empty(r.actor),
+            user_id: non_empty(r.userid),
+            action: non_empty(r.action),
+            limit: r.limit as usize,
+        };
+        let (entries, head) = self.audit_log.query(filter).await?;
+        let entries = entries
+            .into_iter()
+            .map(|entry| entry.into())
+            .collect::<Vec<AuditEntry>>();
+        let response = QueryAuditLogResponse {
+            entries,
+            head_seq: head.seq,
+            head_hash: head.hash,
+        };
+        return Ok(Response::new(response));
+    }
+    async fn list_roles(
+        &self,
+        request: Request<()>,
+    ) -> Result<Response<ListRolesResponse>, Status> {
+        self.authorize(&request, "list_roles", None)?;
+        let roles = self
+            .roles
+            .lock()
+            .map_err(|_| Status::internal("Lock error"))?
+            .into_iter()
+            .map(|i: &mut Pack<role::Role>| i.unpack().into())
+            .collect::<Vec<RoleObj>>();
+        let response = ListRolesResponse { roles };
+        return Ok(Response::new(response));
+    }
+    async fn save_role(
+        &self,
+        request: Request<SaveRoleRequest>,
+    ) -> Result<Response<SaveRoleResponse>, Status> {
+        let actor = self.authorize(&request, "save_role", None)?;
+        let r: RoleObj = match request.into_inner().role {
+            Some(r) => r,
+            None => return Err(Status::invalid_argument("Request has an empty role object")),
+        };
+        let created_by = actor.clone();
+        let role: RoleObj = blocking(&self.roles, move |roles| match roles.find_id_mut(&r.id) {
+            Ok(role) => {
+                let mut updated = role.unpack().clone();
+                updated.set_name(r.name)?;
+                updated.set_permissions(r.permissions)?;
+                role.update(|role| *role = updated.clone()).map_err(|_| {
+                    ServiceError::internal_error("Error while updating role object")
+                })?;
+                Ok(role.unpack().into())
+            }
+            Err(_) => {
+                let new_role = role::Role::new(r.id, r.name, r.permissions, created_by)?;
+                let role_obj: RoleObj = (&new_role).into();
+                roles.insert(new_role)?;
+                Ok(role_obj)
+            }
+        })
+        .await?;
+        self.audit(
+            &actor,
+            AuditAction::RoleChanged,
+            "",
+            &format!(
+                "role: {}, permissions: {}",
+                role.id,
+                role.permissions.join(", ")
+            ),
+        )
+        .await?;
+        let response = SaveRoleResponse { role: Some(role) };
+        return Ok(Response::new(response));
+    }
+    async fn assign_role(
+        &self,
+        request: Request<AssignRoleRequest>,
+    ) -> Result<Response<AssignRoleResponse>, Status> {
+        let actor = self.authorize(&request, "assign_role", None)?;
+        let r = request.into_inner();
+        let user = self
+            .set_user_role(&r.userid, &r.role_id, true, &actor)
+            .await?;
+        let response = AssignRoleResponse { user: Some(user) };
+        return Ok(Response::new(response));
+    }
+    async fn revoke_role(
+        &self,
+        request: Request<RevokeRoleRequest>,
+    ) -> Result<Response<RevokeRoleResponse>, Status> {
+        let actor = self.authorize(&request, "revoke_role", None)?;
+        let r = request.into_inner();
+        let user = self
+            .set_user_role(&r.userid, &r.role_id, false, &actor)
+            .await?;
+        let response = RevokeRoleResponse { user: Some(user) };
+        return Ok(Response::new(response));
+    }
+    async fn has_permission(
+        &self,
+        request: Request<HasPermissionRequest>,
+    ) -> Result<Response<HasPermissionResponse>, Status> {
+        self.authorize(&request, "has_permission", None)?;
+        let r = request.into_inner();
+        let customer_id = match r.customer_id.is_empty() {
+            true => None,
+            false => Some(r.customer_id.as_str()),
+        };
+        let response = HasPermissionResponse {
+            has_permission: self.check_permission(&r.userid, &r.permission, customer_id)?,
+        };
+        return Ok(Response::new(response));
+    }
+    async fn add_customer(
+        &self,
+        request: Request<AddCustomerRequest>,
+    ) -> Result<Response<AddCustomerResponse>, Status> {
+        let actor = self.authorize(&request, "add_customer", None)?;
+        let r = request.into_inner();
+        let role = match r.role.is_empty() {
+            true => role::ROLE_USER.to_string(),
+            false => r.role.clone(),
+        };
+        self.ensure_role(&role)?;
+        let user = self
+            .modify_user(&r.userid, &actor, |u| {
+                u.add_customer(r.customer_id.to_string(), role.to_string())?;
+                if r.set_default {
+                    u.set_default_customer(&r.customer_id)?;
+                }
+                Ok(())
+            })
+            .await?;
+        self.audit(
+            &actor,
+            AuditAction::UserUpdated,
+            &r.userid,
+            &format!("customer added: {} ({})", r.customer_id, role),
+        )
+        .await?;
+        let response = AddCustomerResponse {
+            user: Some(user.into()),
+        };
+        return Ok(Response::new(response));
+    }
+    async fn remove_customer(
+        &self,
+        request: Request<RemoveCustomerRequest>,
+    ) -> Result<Response<RemoveCustomerResponse>, Status> {
+        let actor = self.authorize(&request, "remove_customer", None)?;
+        let r = request.into_inner();
+        let user = self
+            .modify_user(&r.userid, &actor, |u| u.remove_customer(&r.customer_id))
+            .await?;
+        self.audit(
+            &actor,
+            AuditAction::UserUpdated,
+            &r.userid,
+            &format!("customer removed: {}", r.customer_id),
+        )
+        .await?;
+        let response = RemoveCustomerResponse {
+            user: Some(user.into()),
+        };
+        return Ok(Response::new(response));
+    }
+    async fn set_customer_role(
+        &self,
+        request: Request<SetCustomerRoleRequest>,
+    ) -> Result<Response<SetCustomerRoleResponse>, Status> {
+        let actor = self.authorize(&request, "set_customer_role", None)?;
+        let r = request.into_inner();
+        self.ensure_role(&r.role)?;
+        let user = self
+            .modify_user(&r.userid, &actor, |u| {
+                u.set_customer_role(&r.customer_id, r.role.to_string())
+            })
+            .await?;
+        self.audit(
+            &actor,
+            AuditAction::UserUpdated,
+            &r.userid,
+            &format!("customer role set: {} ({})", r.customer_id, r.role),
+        )
+        .await?;
+        let response = SetCustomerRoleResponse {
+            user: Some(user.into()),
+        };
+        return Ok(Response::new(response));
+    }
+    async fn list_users_by_customer(
+        &self,
+        request: Request<ListUsersByCustomerRequest>,
+    ) -> Result<Response<ListUsersByCustomerResponse>, Status> {
+        let actor = self.authorize(&request, "list_users_by_customer", None)?;
+        let customer_id = request.into_inner().customer_id;
+        self.audit(
+            &actor,
+            AuditAction::UserRead,
+            "",
+            &format!("list_users_by_customer: {}", customer_id),
+        )
+        .await?;
+        let by_customer = UserQuery {
+            customer_id: Some(customer_id),
+            ..UserQuery::default()
+        };
+        let users = self
+            .users
+            .snapshot()
+            .query(&by_customer)
+            .into_iter()
+            .map(|u| u.into())
+            .collect::<Vec<UserObj>>();
+        let response = ListUsersByCustomerResponse { users };
+        return Ok(Response::new(response));
+    }
+    async fn create_service_account(
+        &self,
+        request: Request<CreateServiceAccountRequest>,
+    ) -> Result<Response<CreateServiceAccountResponse>, Status> {
+        let actor = self.authorize(&request, "create_service_account", None)?;
+        let r = request.into_inner();
+        let account = service_account::ServiceAccount::new(r.id, r.name, actor.clone())?;
+        let account_obj: ServiceAccountObj = (&account).into();
+        blocking(&self.service_accounts, move |service_accounts| {
+            if service_accounts.find_id(account.get_id()).is_ok() {
+                return Err(ServiceError::already_exist("Service account exists"));
+            }
+            service_accounts.insert(account)?;
+            Ok(())
+        })
+        .await?;
+        self.audit(
+            &actor,
+            AuditAction::ServiceAccountChanged,
+            "",
+            &format!("service account created: {}", account_obj.id),
+        )
+        .await?;
+        let response = CreateServiceAccountResponse {
+            service_account: Some(account_obj),
+        };
+        return Ok(Response::new(response));
+    }
+    async fn list_service_accounts(
+        &self,
+        request: Request<()>,
+    ) -> Result<Response<ListServiceAccountsResponse>, Status> {
+        self.authorize(&request, "list_service_accounts", None)?;
+        let service_accounts = self
+            .service_accounts
+            .lock()
+            .map_err(|_| Status::internal("Lock error"))?
+            .into_iter()
+            .map(|i: &mut Pack<service_account::ServiceAccount>| i.unpack().into())
+            .collect::<Vec<ServiceAccountObj>>();
+        let response = ListServiceAccountsResponse { service_accounts };
+        return Ok(Response::new(response));
+    }
+    async fn create_api_key(
+        &self,
+        request: Request<CreateApiKeyRequest>,
+    ) -> Result<Response<CreateApiKeyResponse>, Status> {
+        let actor = self.authorize(&request, "create_api_key", None)?;
+        let r = request.into_inner();
+        let expires_at = parse_expiration(&r.expires_at)?;
+        let (api_key, key) =
+            api_key::ApiKey::generate(r.name, r.scopes, expires_at, actor.clone())?;
+        let (account_id, stored_key) = (r.service_account_id.clone(), api_key.clone());
+        blocking(&self.service_accounts, move |service_accounts| {
+            let account = service_accounts
+                .find_id_mut(&account_id)
+                .map_err(|_| ServiceError::not_found("Service account not found"))?;
+            account.update(|a| a.add_api_key(stored_key.clone()))?;
+            Ok(())
+        })
+        .await?;
+        self.auth.register_api_key(
+            KeyOwner::ServiceAccount(r.service_account_id.clone()),
+            &api_key,
+        )?;
+        self.audit(
+            &actor,
+            AuditAction::ServiceAccountChanged,
+            "",
+            &format!(
+                "api key created: {}/{}, scopes: {}",
+                r.service_account_id,
+                api_key.get_id(),
+                api_key.get_scopes().join(", ")
+            ),
+        )
+        .await?;
+        let response = CreateApiKeyResponse {
+            api_key: key,
+            key: Some((&api_key).into()),
+        };
+        return Ok(Response::new(response));
+    }
+    async fn revoke_api_key(
+        &self,
+        request: Request<RevokeApiKeyRequest>,
+    ) -> Result<Response<RevokeApiKeyResponse>, Status> {
+        let actor = self.authorize(&request, "revoke_api_key", None)?;
+        let r = request.into_inner();
+        let (account_id, key_id) = (r.service_account_id.clone(), r.key_id.clone());
+        let account = blocking(&self.service_accounts, move |service_accounts| {
+            let account = service_accounts
+                .find_id_mut(&account_id)
+                .map_err(|_| ServiceError::not_found("Service account not found"))?;
+            let mut updated = account.unpack().clone();
+            updated.revoke_api_key(&key_id)?;
+            account.update(|a| *a = updated.clone())?;
+            Ok(updated)
+        })
+        .await?;
+        if let Some(api_key) = account.get_api_key(&r.key_id) {
+            self.auth.register_api_key(
+                KeyOwner::ServiceAccount(account.get_id().to_string()),
+                api_key,
+            )?;
+        }
+        self.audit(
+            &actor,
+            AuditAction::ServiceAccountChanged,
+            "",
+            &format!("api key revoked: {}/{}", r.service_account_id, r.key_id),
+        )
+        .await?;
+        let response = RevokeApiKeyResponse {
+            service_account: Some((&account).into()),
+        };
+        return Ok(Response::new(response));
+    }
+    async fn create_personal_access_token(
+        &self,
+        request: Request<CreatePersonalAccessTokenRequest>,
+    ) -> Result<Response<CreatePersonalAccessTokenResponse>, Status> {
+        let actor = self.authorize(
+            &request,
+            "create_personal_access_token",
+            Some(request.get_ref().userid.as_str()),
+        )?;
+        let r = request.into_inner();
+        let expires_at = parse_expiration(&r.expires_at)?;
+        let (token, key) = api_key::ApiKey::generate(r.name, r.scopes, expires_at, actor.clone())?;
+        self.modify_user(&r.userid, &actor, |u| {
+            u.add_token(token.clone());
+            Ok(())
+        })
+        .await?;
+        self.auth
+            .register_api_key(KeyOwner::User(r.userid.to_string()), &token)?;
+        self.audit(
+            &actor,
+            AuditAction::UserUpdated,
+            &r.userid,
+            &format!(
+                "token created: {}, scopes: {}",
+                token.get_id(),
+                token.get_scopes().join(", ")
+            ),
+        )
+        .await?;
+        let response = CreatePersonalAccessTokenResponse {
+            token: key,
+            personal_access_token: Some((&token).into()),
+        };
+        return Ok(Response::new(response));
+    }
+    async fn list_personal_access_tokens(
+        &self,
+        request: Request<ListPersonalAccessTokensRequest>,
+    ) -> Result<Response<ListPersonalAccessTokensResponse>, Status> {
+        self.authorize(
+            &request,
+            "list_personal_access_tokens",
+            Some(request.get_ref().userid.as_str()),
+        )?;
+        let userid = request.into_inner().userid;
+        let personal_access_tokens = self
+            .users
+            .snapshot()
+            .get(&userid)
+            .filter(|u| !u.is_deleted())
+            .ok_or_else(|| Status::not_found("User not found"))?
+            .get_tokens()
+            .iter()
+            .map(|token| token.into())
+            .collect::<Vec<ApiKeyObj>>();
+        let response = ListPersonalAccessTokensResponse {
+            personal_access_tokens,
+        };
+        return Ok(Response::new(response));
+    }
+    async fn revoke_personal_access_token(
+        &self,
+        request: Request<RevokePersonalAccessTokenRequest>,
+    ) -> Result<Response<RevokePersonalAccessTokenResponse>, Status> {
+        let actor = self.authorize(
+            &request,
+            "revoke_personal_access_token",
+            Some(request.get_ref().userid.as_str()),
+        )?;
+        let r = request.into_inner();
+        let user = self
+            .modify_user(&r.userid, &actor, |u| u.revoke_token(&r.token_id))
+            .await?;
+        if let Some(token) = user.get_token(&r.token_id) {
+            self.auth
+                .register_api_key(KeyOwner::User(r.userid.to_string()), token)?;
+        }
+        self.audit(
+            &actor,
+            AuditAction::UserUpdated,
+            &r.userid,
+            &format!("token revoked: {}", r.token_id),
+        )
+        .await?;
+        Ok(Response::new(RevokePersonalAccessTokenResponse {}))
+    }
+    async fn invite_user(
+        &self,
+        request: Request<InviteUserRequest>,
+    ) -> Result<Response<InviteUserResponse>, Status> {
+        let actor = self.authorize(&request, "invite_user", None)?;
+        Ok(Response::new(
+            self.create_invited_user(request.into_inner(), &actor)
+                .await?,
+        ))
+    }
+    async fn resend_invitation(
+        &self,
+        request: Request<ResendInvitationRequest>,
+    ) -> Result<Response<ResendInvitationResponse>, Status> {
+        let actor = self.authorize(&request, "resend_invitation", None)?;
+        let userid = request.into_inner().userid;
+        let mut token = String::new();
+        let user = self
+            .modify_user(&userid, &actor, |u| {
+                token = u.renew_invitation(&actor)?;
+                Ok(())
+            })
+            .await?;
+        self.audit(
+            &actor,
+            AuditAction::UserInvited,
+            &userid,
+            "invitation renewed",
+        )
+        .await?;
+        self.send_invitation(&user, &token).await?;
+        let response = ResendInvitationResponse {
+            invitation_expires_at: invitation_expires_at(&user),
+        };
+        return Ok(Response::new(response));
+    }
+    async fn accept_invitation(
+        &self,
+        request: Request<AcceptInvitationRequest>,
+    ) -> Result<Response<AcceptInvitationResponse>, Status> {
+        self.authorize(&request, "accept_invitation", None)?;
+        let r = request.into_inner();
+        let writer = self.users.writer().await;
+        let userid = writer
+            .snapshot()
+            .find_by_token(&r.token)
+            .map(|u| u.get_user_id().to_string());
+        let user = match userid {
+            Some(userid) => writer.fresh(&userid).await?,
+            None => None,
+        }
+        .filter(|u| u.has_invitation(&r.token))
+        .ok_or_else(|| Status::not_found("Invitation not found"))?;
+        let mut updated = user.clone();
+        updated.accept_invitation(&r.token, r.password)?;
+        if !r.name.is_empty() {
+            updated.set_user_name(r.name)?;
+        }
+        if !r.phone.is_empty() {
+            updated.set_user_phone(r.phone)?;
+        }
+        // The invited user is the actor from now on
+        let userid = updated.get_user_id().to_string();
+        let user = user::commit(&user, updated, &userid);
+        writer.update(user.clone()).await?;
+        self.search_index
+            .lock()
+            .map_err(|_| Status::internal("Lock error"))?
+            .insert(&user);
+        self.changes
+            .lock()
+            .map_err(|_| Status::internal("Lock error"))?
+            .publish(ChangeKind::Updated, &user);
+        self.emit(webhook::USER_UPDATED, &user, &userid).await?;
+        self.audit(&userid, AuditAction::InvitationAccepted, &userid, "")
+            .await?;
+        let response = AcceptInvitationResponse {
+            user: Some(user.into()),
+        };
+        return Ok(Response::new(response));
+    }
+    async fn delete_by_id(
+        &self,
+        request: Request<DeleteByIdRequest>,
+    ) -> Result<Response<DeleteByIdResponse>, Status> {
+        let actor = self.authorize(&request, "delete_by_id", None)?;
+        let userid = request.into_inner().userid;
+        self.delete_user(&userid, &actor, |u| u.delete()).await?;
+        self.audit(&actor, AuditAction::UserDeleted, &userid, "")
+            .await?;
+        Ok(Response::new(DeleteByIdResponse {}))
+    }
+    async fn register(
+        &self,
+        request: Request<RegisterRequest>,
+    ) -> Result<Response<RegisterResponse>, Status> {
+        self.authorize(&request, "register", None)?;
+        let r = request.into_inner();
+        // Self-registered users are created by themselves
+        let actor = r.username.to_lowercase();
+        let mut new_user =
+            user::User::new(r.username, r.name, r.email, r.phone, actor.clone(), None)?;
+        // Approved by its email domain only once the address is verified
+        let token = new_user.register(r.password)?;
+        let user = self
+            .insert_user(new_user.clone(), &actor, AuditAction::UserRegistered, true)
+            .await?;
+        self.send_verification(&new_user, &token).await?;
+        let response = RegisterResponse {
+            user: Some(user),
+            pending_approval: !self
+                .registration
+                .is_auto_approved(new_user.get_user_email()),
+        };
+        return Ok(Response::new(response));
+    }
+    async fn verify_email(
+        &self,
+        request: Request<VerifyEmailRequest>,
+    ) -> Result<Response<VerifyEmailResponse>, Status> {
+        self.authorize(&request, "verify_email", None)?;
+        let token = request.into_inner().token;
+        let writer = self.users.writer().await;
+        let userid = writer
+            .snapshot()
+            .find_by_token(&token)
+            .map(|u| u.get_user_id().to_string());
+        let user = match userid {
+            Some(userid) => writer.fresh(&userid).await?,
+            None => None,
+        }
+        .filter(|u| u.has_verification(&token))
+        .ok_or_else(|| Status::not_found("Verification not found"))?;
+        let approved = self.registration.is_auto_approved(user.get_user_email());
+        let mut updated = user.clone();
+        updated.verify_email(&token, approved)?;
+        let userid = updated.get_user_id().to_string();
+        let user = user::commit(&user, updated, &userid);
+        writer.update(user.clone()).await?;
+        self.search_index
+            .lock()
+            .map_err(|_| Status::internal("Lock error"))?
+            .insert(&user);
+        self.changes
+            .lock()
+            .map_err(|_| Status::internal("Lock error"))?
+            .publish(ChangeKind::Updated, &user);
+        self.emit(webhook::USER_UPDATED, &user, &userid).await?;
+        self.audit(&userid, AuditAction::EmailVerified, &userid, "")
+            .await?;
+        let response = VerifyEmailResponse {
+            user: Some(user.into()),
+            pending_approval: !approved,
+        };
+        return Ok(Response::new(response));
+    }
+    async fn resend_verification(
+        &self,
+        request: Request<ResendVerificationRequest>,
+    ) -> Result<Response<ResendVerificationResponse>, Status> {
+        self.authorize(&request, "resend_verification", None)?;
+        let userid = request.into_inner().userid.to_lowercase();
+        // Same answer for every user, so users cannot be probed,
+        // and no new code is sent right after the previous one
+        let resend_after = Utc::now() - chrono::Duration::seconds(VERIFICATION_RESEND_SECONDS);
+        let is_due = self
+            .users
+            .snapshot()
+            .get(&userid)
+            .filter(|u| u.is_pending_verification())
+            .is_some_and(|u| {
+                u.get_verification()
+                    .is_none_or(|v| v.date_created <= resend_after)
+            });
+        if !is_due {
+            return Ok(Response::new(ResendVerificationResponse {}));
+        }
+        let mut token = String::new();
+        let user = self
+            .modify_user(&userid, &userid, |u| {
+                token = u.renew_verification()?;
+                Ok(())
+            })
+            .await?;
+        self.send_verification(&user, &token).await?;
+        Ok(Response::new(ResendVerificationResponse {}))
+    }
+    async fn list_pending_registrations(
+        &self,
+        request: Request<()>,
+    ) -> Result<Response<ListPendingRegistrationsResponse>, Status> {
+        let actor = self.authorize(&request, "list_pending_registrations", None)?;
+        self.audit(
+            &actor,
+            AuditAction::UserRead,
+            "",
+            "list_pending_registrations",
+        )
+        .await?;
+        let pending = UserQuery {
+            status: Some(user::UserStatus::PendingApproval),
+            ..UserQuery::default()
+        };
+        let users = self
+            .users
+            .snapshot()
+            .query(&pending)
+            .into_iter()
+            .map(|u| u.into())
+            .collect::<Vec<UserObj>>();
+        let response = ListPendingRegistrationsResponse { users };
+        return Ok(Response::new(response));
+    }
+    async fn approve_registration(
+        &self,
+        request: Request<ApproveRegistrationRequest>,
+    ) -> Result<Response<ApproveRegistrationResponse>, Status> {
+        let actor = self.authorize(&request, "approve_registration", None)?;
+        let userid = request.into_inner().userid;
+        let user = self.modify_user(&userid, &actor, |u| u.approve()).await?;
+        self.audit(&actor, AuditAction::RegistrationApproved, &userid, "")
+            .await?;
+        self.notify_user(&user, Template::RegistrationApproved, &[])
+            .await;
+        let response = ApproveRegistrationResponse {
+            user: Some(user.into()),
+        };
+        return Ok(Response::new(response));
+    }
+    async fn reject_registration(
+        &self,
+        request: Request<RejectRegistrationRequest>,
+    ) -> Result<Response<RejectRegistrationResponse>, Status> {
+        let actor = self.authorize(&request, "reject_registration", None)?;
+        let RejectRegistrationRequest { userid, reason } = request.into_inner();
+        let user = self.delete_user(&userid, &actor, |u| u.reject()).await?;
+        self.audit(
+            &actor,
+            AuditAction::RegistrationRejected,
+            &userid,
+            &format!("reason: {}", reason),
+        )
+        .await?;
+        self.notify_user(
+            &user,
+            Template::RegistrationRejected,
+            &[("reason", reason.as_str())],
+        )
+        .await;
+        Ok(Response::new(RejectRegistrationResponse {}))
+    }
+
+    async fn list_outbox_messages(
+        &self,
+        request: Request<ListOutboxMessagesRequest>,
+    ) -> Result<Response<ListOutboxMessagesResponse>, Status> {
+        self.authorize(&request, "list_outbox_messages", None)?;
+        let mut statuses: Vec<outbox::OutboxStatus> = Vec::new();
+        for status in request.into_inner().statuses {
+            let status = OutboxMessageStatus::from_i32(status)
+                .ok_or_else(|| Status::invalid_argument("Unknown outbox message status"))?;
+            statuses.push(status.into());
+        }
+        let messages = self
+            .outbox
+            .list(&statuses)?
+            .iter()
+            .map(|m| m.into())
+            .collect::<Vec<OutboxMessageObj>>();
+        let response = ListOutboxMessagesResponse { messages };
+        return Ok(Response::new(response));
+    }
+    async fn requeue_outbox_message(
+        &self,
+        request: Request<RequeueOutboxMessageRequest>,
+    ) -> Result<Response<RequeueOutboxMessageResponse>, Status> {
+        self.authorize(&request, "requeue_outbox_message", None)?;
+        let message = self.outbox.requeue(&request.into_inner().id).await?;
+        let response = RequeueOutboxMessageResponse {
+            message: Some((&message).into()),
+        };
+        return Ok(Response::new(response));
+    }
+
+    async fn create_webhook(
+        &self,
+        request: Request<CreateWebhookRequest>,
+    ) -> Result<Response<CreateWebhookResponse>, Status> {
+        let actor = self.authorize(&request, "create_webhook", None)?;
+        let CreateWebhookRequest { url, events } = request.into_inner();
+        let hook = webhook::Webhook::new(url, events, actor.clone())?;
+        let webhook_id = hook.get_id().to_string();
+        let response = CreateWebhookResponse {
+            webhook: Some((&hook).into()),
+            secret: hook.get_secret().to_string(),
+        };
+        self.webhooks.create(hook).await?;
+        self.audit(
+            &actor,
+            AuditAction::WebhookChanged,
+            "",
+            &format!("webhook created: {}", webhook_id),
+        )
+        .await?;
+        return Ok(Response::new(response));
+    }
+    async fn list_webhooks(
+        &self,
+        request: Request<()>,
+    ) -> Result<Response<ListWebhooksResponse>, Status> {
+        self.authorize(&request, "list_webhooks", None)?;
+        let webhooks = self
+            .webhooks
+            .list()
+            .await?
+            .iter()
+            .map(|h| h.into())
+            .collect::<Vec<WebhookObj>>();
+        return Ok(Response::new(ListWebhooksResponse { webhooks }));
+    }
+    async fn delete_webhook(
+        &self,
+        request: Request<DeleteWebhookRequest>,
+    ) -> Result<Response<DeleteWebhookResponse>, Status> {
+        let actor = self.authorize(&request, "delete_webhook", None)?;
+        let id = request.into_inner().id;
+        self.webhooks.delete(&id).await?;
+        self.audit(
+            &actor,
+            AuditAction::WebhookChanged,
+            "",
+            &format!("webhook deleted: {}", id),
+        )
+        .await?;
+        Ok(Response::new(DeleteWebhookResponse {}))
+    }
+    async fn list_webhook_deliveries(
+        &self,
+        request: Request<ListWebhookDeliveriesRequest>,
+    ) -> Result<Response<ListWebhookDeliveriesResponse>, Status> {
+        self.authorize(&request, "list_webhook_deliveries", None)?;
+        let r = request.into_inner();
+        let limit = match r.limit {
+            0 => WEBHOOK_DELIVERIES_DEFAULT_LIMIT,
+            limit => limit as usize,
+        };
+        let deliveries = self
+            .webhooks
+            .deliveries(&r.webhook_id, limit)
+            .await?
+            .iter()
+            .map(|d| d.into())
+            .collect::<Vec<WebhookDeliveryObj>>();
+        let response = ListWebhookDeliveriesResponse { deliveries };
+        return Ok(Response::new(response));
+    }
+
+    type WatchUsersStream = mpsc::Receiver<Result<UserEvent, Status>>;
+
+    async fn watch_users(
+        &self,
+        request: Request<WatchUsersRequest>,
+    ) -> Result<Response<Self::WatchUsersStream>, Status> {
+        let actor = self.authorize(&request, "watch_users", None)?;
+        self.audit(&actor, AuditAction::UserRead, "", "watch_users")
+            .await?;
+        let WatchUsersRequest {
+            from_revision,
+            epoch,
+        } = request.into_inner();
+        let (backlog, mut changes) = self
+            .changes
+            .lock()
+            .map_err(|_| Status::internal("Lock error"))?
+            .subscribe(&epoch, from_revision)?;
+        let (mut tx, rx) = mpsc::channel::<Result<UserEvent, Status>>(64);
+        tokio::spawn(async move {
+            for event in backlog {
+                if tx.send(Ok((&event).into())).await.is_err() {
+                    return;
+                }
+            }
+            loop {
+                let message = match changes.recv().await {
+                    Ok(event) => Ok((&event).into()),
+                    // The client is too slow, let it resume
+                    // from its last received revision
+                    Err(broadcast::RecvError::Lagged(_)) => Err(Status::aborted(
+                        "Watcher lagged behind, resume from the last received revision",
+                    )),
+                    Err(broadcast::RecvError::Closed) => return,
+                };
+                let is_last = message.is_err();
+                if tx.send(message).await.is_err() || is_last {
+                    return;
+                }
+            }
+        });
+        Ok(Response::new(rx))
     }
 }
 
 #[tokio::main]
 async fn main() -> prelude::ServiceResult<()> {
-    let users: Mutex<VecPack<user::User>> = Mutex::new(
-        VecPack::try_load_or_init(PathBuf::from("data/users"))
-            .expect("Error while loading users storage"),
-    );
+    // Check the audit log hash chain and exit
+    // => user_microservice verify-audit-log [path]
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(|arg| arg.as_str()) == Some("verify-audit-log") {
+        let path = args
+            .get(2)
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from(AUDIT_LOG_PATH));
+        match audit::verify(&path) {
+            Ok(count) => {
+                println!("Audit log is valid, {} entries verified", count);
+                return Ok(());
+            }
+            Err(err) => {
+                eprintln!("Audit log is broken: {}", err);
+                std::process::exit(1);
+            }
+        }
+    }
 
-    let user_service = UserService::new(users);
+    // Copy the YAML users into the database storage and exit
+    // => USER_STORAGE=sqlite user_microservice migrate-users [--dry-run] [dir]
+    if args.get(1).map(|arg| arg.as_str()) == Some("migrate-users") {
+        let dry_run = args.iter().skip(2).any(|arg| arg == "--dry-run");
+        let dir = args
+            .iter()
+            .skip(2)
+            .find(|arg| !arg.starts_with("--"))
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from("data/users"));
+        let storage = std::env::var(repository::STORAGE_ENV).unwrap_or_default();
+        if storage != "sqlite" && storage != "postgres" {
+            eprintln!("Set USER_STORAGE to sqlite or postgres to choose the target");
+            std::process::exit(1);
+        }
+        let mut target = repository::from_env().expect("Error while opening target storage");
+        let report = migrate::migrate_users(&dir, target.as_mut(), dry_run)?;
+        for record in &report.records {
+            println!("{}\t{}\t{}", record.file, record.user_id, record.outcome);
+        }
+        println!("{}", report.summary());
+        if report.failed() > 0 {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    let mut users = repository::from_env().expect("Error while loading users storage");
+    if std::env::var(repository::REWRITE_UPGRADED_ENV).is_ok_and(|v| v == "true") {
+        let count = repository::rewrite_upgraded(users.as_mut())
+            .expect("Error while rewriting upgraded users");
+        println!("{} users rewritten in the current schema", count);
+    }
+    let users = store::UserStore::load(users).expect("Error while loading users");
+
+    let mut roles: VecPack<role::Role> = VecPack::try_load_or_init(PathBuf::from("data/roles"))
+        .expect("Error while loading roles storage");
+    // Make sure the default roles exist
+    for default_role in role::default_roles() {
+        if roles.find_id(default_role.get_id()).is_err() {
+            roles
+                .insert(default_role)
+                .expect("Error while creating default roles");
+        }
+    }
+
+    let service_accounts: VecPack<service_account::ServiceAccount> =
+        VecPack::try_load_or_init(PathBuf::from("data/service_accounts"))
+            .expect("Error while loading service accounts storage");
+
+    let audit_log = audit::AuditLog::open(AUDIT_LOG_PATH).expect("Error while opening audit log");
+
+    let outbox = Arc::new(outbox::Outbox::new(
+        VecPack::try_load_or_init(PathBuf::from("data/outbox"))
+            .expect("Error while loading outbox storage"),
+        notifier::from_env().expect("Error while configuring notifier"),
+    ));
+    // Deliver queued messages in the background
+    tokio::spawn(outbox::run(outbox.clone()));
+
+    let webhooks = Arc::new(webhook::Webhooks::new(
+        VecPack::try_load_or_init(PathBuf::from("data/webhooks"))
+            .expect("Error while loading webhooks storage"),
+        VecPack::try_load_or_init(PathBuf::from("data/webhook_deliveries"))
+            .expect("Error while loading webhook deliveries storage"),
+    ));
+    tokio::spawn(webhook::run(webhooks.clone()));
+
+    let authenticator =
+        Arc::new(auth::Authenticator::from_env().expect("Error while loading service credentials"));
+
+    let user_service = UserService::new(
+        users,
+        roles,
+        service_accounts,
+        audit_log,
+        authenticator.clone(),
+        outbox.clone(),
+        webhooks.clone(),
+        events::from_env().expect("Error while connecting to the message broker"),
+        registration::RegistrationRules::from_env(),
+        template::Templates::from_env().expect("Error while loading templates"),
+    )
+    .expect("Error while loading API keys");
 
     let addr = "[::1]:50051".parse().unwrap();
 
+    #[allow(clippy::result_large_err)]
+    let interceptor = move |request: Request<()>| authenticator.intercept(request);
     Server::builder()
-        .add_service(UserServer::new(user_service))
+        .add_service(UserServer::with_interceptor(user_service, interceptor))
         .serve(addr)
         .await
         .expect("Error while staring server"); // Todo implement ? from<?>
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::Rng;
+
+    // Service with an admin and a plain user, in its own data directory
+    fn service() -> (UserService, PathBuf) {
+        let dir = std::env::temp_dir().join(format!(
+            "user_microservice_service_{}",
+            hex::encode(rand::thread_rng().gen::<[u8; 4]>())
+        ));
+        let mut users = repository::MemoryRepository::default();
+        for (id, role_id) in &[("admin", role::ROLE_ADMIN), ("demo", role::ROLE_USER)] {
+            let mut user = user::User::new(
+                id.to_string(),
+                "user".into(),
+                format!("{}@user.com", id),
+                "".into(),
+                "system".into(),
+                None,
+            )
+            .unwrap();
+            user.add_role(role_id).unwrap();
+            repository::UserRepository::insert(&mut users, user).unwrap();
+        }
+        let mut roles: VecPack<role::Role> = VecPack::try_load_or_init(dir.join("roles")).unwrap();
+        for default_role in role::default_roles() {
+            roles.insert(default_role).unwrap();
+        }
+        let service = UserService::new(
+            store::UserStore::load(Box::new(users)).unwrap(),
+            roles,
+            VecPack::try_load_or_init(dir.join("service_accounts")).unwrap(),
+            audit::AuditLog::open(dir.join("audit.log")).unwrap(),
+            Arc::new(auth::Authenticator::new(vec![])),
+            Arc::new(outbox::Outbox::new(
+                VecPack::try_load_or_init(dir.join("outbox")).unwrap(),
+                Arc::new(notifier::SpoolNotifier::new(dir.join("mail")).unwrap()),
+            )),
+            Arc::new(webhook::Webhooks::new(
+                VecPack::try_load_or_init(dir.join("webhooks")).unwrap(),
+                VecPack::try_load_or_init(dir.join("webhook_deliveries")).unwrap(),
+            )),
+            Arc::new(events::NoopPublisher),
+            registration::RegistrationRules::default(),
+            template::Templates::default(),
+        )
+        .unwrap();
+        (service, dir)
+    }
+
+    // Request as the interceptor passes it on, with the given metadata
+    fn request<T>(
+        service: &UserService,
+        message: T,
+        metadata: &[(&'static str, &str)],
+    ) -> Request<T> {
+        let mut incoming = Request::new(());
+        for (key, value) in metadata {
+            incoming.metadata_mut().insert(*key, value.parse().unwrap());
+        }
+        let intercepted = service.auth.intercept(incoming).unwrap();
+        let mut request = Request::new(message);
+        *request.metadata_mut() = intercepted.metadata().clone();
+        request
+    }
+
+    // Request of the logged in user
+    fn request_as<T>(service: &UserService, user_id: &str, message: T) -> Request<T> {
+        let (token, _) = service.auth.create_session(user_id).unwrap();
+        let bearer = format!("Bearer {}", token);
+        request(
+            service,
+            message,
+            &[(auth::AUTHORIZATION_METADATA_KEY, &bearer)],
+        )
+    }
+
+    fn create_new_request(username: &str) -> CreateNewRequest {
+        CreateNewRequest {
+            username: username.into(),
+            name: "New User".into(),
+            email: format!("{}@user.com", username),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_allowed_method() {
+        let (service, dir) = service();
+        let request = request_as(&service, "admin", create_new_request("created"));
+        assert_eq!(service.create_new(request).await.is_ok(), true); // should be ok
+                                                                     // Users act on themselves without the permission
+        let request = request_as(
+            &service,
+            "demo",
+            GetByIdRequest {
+                userid: "demo".into(),
+            },
+        );
+        assert_eq!(service.get_by_id(request).await.is_ok(), true); // should be ok
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_denied_method() {
+        let (service, dir) = service();
+        let request = request_as(&service, "demo", create_new_request("created"));
+        let status = service.create_new(request).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::PermissionDenied);
+        // user:read is not enough to change another user
+        let request = request_as(
+            &service,
+            "demo",
+            SetPasswordRequest {
+                userid: "admin".into(),
+                password: "HelloWorld1234".into(),
+                current_password: "".into(),
+            },
+        );
+        let status = service.set_password(request).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::PermissionDenied);
+        assert_eq!(service.users.snapshot().get("created").is_none(), true);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_service_account_on_admin() {
+        let (service, dir) = service();
+        let (api_key, key) = api_key::ApiKey::generate(
+            "main".into(),
+            vec![role::USER_WRITE.into()],
+            None,
+            "admin".into(),
+        )
+        .unwrap();
+        service
+            .auth
+            .register_api_key(KeyOwner::ServiceAccount("invoice".into()), &api_key)
+            .unwrap();
+        let set_password = |userid: &str| SetPasswordRequest {
+            userid: userid.into(),
+            password: "HelloWorld1234".into(),
+            current_password: "".into(),
+        };
+        let request_admin = request(
+            &service,
+            set_password("admin"),
+            &[(auth::API_KEY_METADATA_KEY, &key)],
+        );
+        let status = service.set_password(request_admin).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::PermissionDenied);
+        let request_demo = request(
+            &service,
+            set_password("demo"),
+            &[(auth::API_KEY_METADATA_KEY, &key)],
+        );
+        assert_eq!(service.set_password(request_demo).await.is_ok(), true); // should be ok
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_method_missing_from_policies() {
+        let (service, dir) = service();
+        // Admin only
+        let request = request_as(&service, "admin", ());
+        assert_eq!(
+            service.authorize(&request, "not_in_policies", None).is_ok(),
+            true
+        ); // should be ok
+        let request = request_as(&service, "demo", ());
+        match service.authorize(&request, "not_in_policies", None) {
+            Err(ServiceError::PermissionDenied(_)) => (),
+            _ => panic!("should be permission denied"),
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_spoofed_principal() {
+        let (service, dir) = service();
+        // A principal sent by the client is dropped by the interceptor
+        let request = request(
+            &service,
+            create_new_request("created"),
+            &[(auth::PRINCIPAL_METADATA_KEY, "user:admin")],
+        );
+        let status = service.create_new(request).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,258 @@
+// Copyright (C) 2020 Peter Mezei
+//
+// This file is part of Gardenzilla.
+//
+// Gardenzilla is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 2 of the License, or
+// (at your option) any later version.
+//
+// Gardenzilla is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
+
+use crate::prelude::ServiceError::*;
+use crate::prelude::*;
+use crate::repository::UserRepository;
+use crate::user::User;
+use std::path::{Path, PathBuf};
+
+// Extension of the files written by storaget
+const USER_FILE_EXTENSION: &str = "yml";
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Outcome {
+    Inserted,
+    // Stored with an older version, replaced
+    Updated,
+    // Stored with the same version, e.g. by a previous run
+    Unchanged,
+    // Stored with a newer version, left as it is
+    TargetNewer,
+    Failed(String),
+}
+
+impl std::fmt::Display for Outcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Outcome::Inserted => write!(f, "inserted"),
+            Outcome::Updated => write!(f, "updated"),
+            Outcome::Unchanged => write!(f, "unchanged"),
+            Outcome::TargetNewer => write!(f, "skipped, target is newer"),
+            Outcome::Failed(reason) => write!(f, "FAILED: {}", reason),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RecordReport {
+    pub file: String,
+    // Empty if the file could not be read
+    pub user_id: String,
+    pub outcome: Outcome,
+}
+
+#[derive(Clone, Debug)]
+pub struct MigrationReport {
+    pub dry_run: bool,
+    pub records: Vec<RecordReport>,
+}
+
+impl MigrationReport {
+    pub fn count(&self, outcome: &Outcome) -> usize {
+        self.records
+            .iter()
+            .filter(|r| std::mem::discriminant(&r.outcome) == std::mem::discriminant(outcome))
+            .count()
+    }
+    pub fn failed(&self) -> usize {
+        self.count(&Outcome::Failed(String::new()))
+    }
+    pub fn summary(&self) -> String {
+        format!(
+            "{}{} records: {} inserted, {} updated, {} unchanged, {} target newer, {} failed",
+            match self.dry_run {
+                true => "Dry run, nothing written. ",
+                false => "",
+            },
+            self.records.len(),
+            self.count(&Outcome::Inserted),
+            self.count(&Outcome::Updated),
+            self.count(&Outcome::Unchanged),
+            self.count(&Outcome::TargetNewer),
+            self.failed()
+        )
+    }
+}
+
+/// # Migrate users
+/// Copy every user file of a storaget directory into the target
+/// repository. Files are read one by one the way storaget reads them,
+/// so a broken file is reported instead of stopping the whole run.
+/// Users already stored with the same or a newer version are left
+/// alone, so the migration can be rerun any time.
+/// In dry run mode the outcomes are reported, but nothing is written.
+pub fn migrate_users(
+    dir: &Path,
+    target: &mut dyn UserRepository,
+    dry_run: bool,
+) -> ServiceResult<MigrationReport> {
+    let mut files = std::fs::read_dir(dir)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<Result<Vec<PathBuf>, std::io::Error>>()?;
+    files.retain(|path| {
+        path.extension()
+            .is_some_and(|ext| ext == USER_FILE_EXTENSION)
+    });
+    files.sort();
+    let mut records = Vec::with_capacity(files.len());
+    for path in files {
+        let file = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let record = match load_user(&path) {
+            Ok(user) => RecordReport {
+                file,
+                user_id: user.get_user_id().to_string(),
+                outcome: migrate_user(user, target, dry_run),
+            },
+            Err(err) => RecordReport {
+                file,
+                user_id: String::new(),
+                outcome: Outcome::Failed(err.to_string()),
+            },
+        };
+        records.push(record);
+    }
+    Ok(MigrationReport { dry_run, records })
+}
+
+fn load_user(path: &Path) -> ServiceResult<User> {
+    let content = std::fs::read_to_string(path)?;
+    let user: User = serde_yaml::from_str(&content)
+        .map_err(|e| BadRequest(format!("Malformed user file: {}", e)))?;
+    // storaget finds users by their file name
+    let file_id = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default();
+    if file_id != user.get_user_id() {
+        return Err(BadRequest(format!(
+            "File name does not match user ID {}",
+            user.get_user_id()
+        )));
+    }
+    user.validate()?;
+    Ok(user)
+}
+
+fn migrate_user(user: User, target: &mut dyn UserRepository, dry_run: bool) -> Outcome {
+    let (outcome, stored_version) = match target.find(user.get_user_id()) {
+        Err(err) => return Outcome::Failed(err.to_string()),
+        Ok(None) => (Outcome::Inserted, 0),
+        Ok(Some(stored)) if stored.get_version() == user.get_version() => {
+            return Outcome::Unchanged
+        }
+        Ok(Some(stored)) if stored.get_version() > user.get_version() => {
+            return Outcome::TargetNewer
+        }
+        Ok(Some(stored)) => (Outcome::Updated, stored.get_version()),
+    };
+    if dry_run {
+        return outcome;
+    }
+    let result = match outcome {
+        Outcome::Inserted => target.insert(user),
+        _ => target.update(user, stored_version),
+    };
+    match result {
+        Ok(_) => outcome,
+        Err(err) => Outcome::Failed(err.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::MemoryRepository;
+
+    fn user(id: &str) -> User {
+        User::new(
+            id.into(),
+            "user".into(),
+            format!("{}@user.com", id),
+            "".into(),
+            "admin".into(),
+            None,
+        )
+        .unwrap()
+    }
+
+    fn write(dir: &Path, file: &str, content: &str) {
+        std::fs::write(dir.join(file), content).unwrap();
+    }
+
+    #[test]
+    fn test_migrate_users() {
+        let dir =
+            std::env::temp_dir().join(format!("user_microservice_migrate_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        write(
+            &dir,
+            "demo.yml",
+            &serde_yaml::to_string(&user("demo")).unwrap(),
+        );
+        write(
+            &dir,
+            "other.yml",
+            &serde_yaml::to_string(&user("other")).unwrap(),
+        );
+        write(&dir, "broken.yml", "id: [");
+        write(
+            &dir,
+            "renamed.yml",
+            &serde_yaml::to_string(&user("demo2")).unwrap(),
+        );
+        write(&dir, "notes.txt", "not a user");
+        let mut target = MemoryRepository::default();
+
+        let report = migrate_users(&dir, &mut target, true).unwrap();
+        assert_eq!(report.records.len(), 4);
+        assert_eq!(report.count(&Outcome::Inserted), 2);
+        assert_eq!(report.failed(), 2);
+        assert_eq!(target.list().unwrap().len(), 0);
+
+        let report = migrate_users(&dir, &mut target, false).unwrap();
+        assert_eq!(report.count(&Outcome::Inserted), 2);
+        assert_eq!(report.records[0].file, "broken.yml");
+        assert_eq!(report.records[0].user_id, "");
+        assert_eq!(report.records[1].user_id, "demo");
+        assert_eq!(target.list().unwrap().len(), 2);
+
+        // Rerun changes nothing
+        let report = migrate_users(&dir, &mut target, false).unwrap();
+        assert_eq!(report.count(&Outcome::Unchanged), 2);
+
+        // Newer versions on either side
+        let demo = target.find("demo").unwrap().unwrap();
+        let mut renamed = demo.clone();
+        renamed.set_user_name("Demo User".into()).unwrap();
+        target
+            .update(crate::user::commit(&demo, renamed, "admin"), 1)
+            .unwrap();
+        let other = target.find("other").unwrap().unwrap();
+        let updated = crate::user::commit(&other, other.clone(), "admin");
+        write(&dir, "other.yml", &serde_yaml::to_string(&updated).unwrap());
+        let report = migrate_users(&dir, &mut target, false).unwrap();
+        assert_eq!(report.records[1].outcome, Outcome::TargetNewer);
+        assert_eq!(report.records[2].outcome, Outcome::Updated);
+        assert_eq!(target.find("other").unwrap().unwrap().get_version(), 2);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,345 @@
+// Copyright (C) 2020 Peter Mezei
+//
+// This file is part of Gardenzilla.
+//
+// Gardenzilla is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 2 of the License, or
+// (at your option) any later version.
+//
+// Gardenzilla is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
+
+use crate::prelude::*;
+use chrono::prelude::*;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+// SMTP relay as host:port, e.g. localhost:25
+pub const SMTP_SERVER_ENV: &str = "USER_SMTP_SERVER";
+// Sender address of the SMTP notifier
+pub const SMTP_FROM_ENV: &str = "USER_SMTP_FROM";
+// Directory of the file-spool notifier
+pub const MAIL_SPOOL_ENV: &str = "USER_MAIL_SPOOL";
+// Timeout of every SMTP read and write, in seconds
+const SMTP_TIMEOUT_SECONDS: u64 = 10;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+pub struct Message {
+    // Email address of the recipient
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+/// # Notifier
+/// Sends messages to users, e.g. invitations
+pub trait Notifier: Send + Sync {
+    fn send(&self, message: &Message) -> ServiceResult<()>;
+}
+
+/// # Spool notifier
+/// Writes every message into its own .eml file
+/// in the spool directory. For development and testing.
+pub struct SpoolNotifier {
+    dir: PathBuf,
+}
+
+impl SpoolNotifier {
+    pub fn new(dir: PathBuf) -> ServiceResult<Self> {
+        std::fs::create_dir_all(&dir).map_err(|e| {
+            ServiceError::internal_error(&format!("Cannot create mail spool: {}", e))
+        })?;
+        Ok(Self { dir })
+    }
+}
+
+impl Notifier for SpoolNotifier {
+    fn send(&self, message: &Message) -> ServiceResult<()> {
+        check_address(&message.to)?;
+        // Sortable by time, unique by the random suffix
+        let file_name = format!(
+            "{}-{}.eml",
+            Utc::now().format("%Y%m%d%H%M%S%f"),
+            hex::encode(rand::thread_rng().gen::<[u8; 4]>())
+        );
+        std::fs::write(self.dir.join(file_name), format_message("", message))
+            .map_err(|e| ServiceError::internal_error(&format!("Cannot write mail spool: {}", e)))
+    }
+}
+
+/// # SMTP notifier
+/// Sends messages through an SMTP relay, e.g. a local postfix.
+/// It speaks plain SMTP, so the relay should be trusted
+/// and reached through a private network.
+pub struct SmtpNotifier {
+    // host:port
+    server: String,
+    from: String,
+}
+
+impl SmtpNotifier {
+    pub fn new(server: String, from: String) -> Self {
+        Self { server, from }
+    }
+    fn session(&self, message: &Message) -> std::io::Result<()> {
+        let stream = TcpStream::connect(&self.server)?;
+        let timeout = Some(Duration::from_secs(SMTP_TIMEOUT_SECONDS));
+        stream.set_read_timeout(timeout)?;
+        stream.set_write_timeout(timeout)?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = stream;
+        read_reply(&mut reader, 220)?;
+        let commands = vec![
+            ("EHLO user-microservice".to_string(), 250),
+            (format!("MAIL FROM:<{}>", self.from), 250),
+            (format!("RCPT TO:<{}>", message.to), 250),
+            ("DATA".to_string(), 354),
+        ];
+        for (command, expected) in commands {
+            write!(writer, "{}\r\n", command)?;
+            read_reply(&mut reader, expected)?;
+        }
+        // Lines starting with a dot are escaped by another dot
+        for line in format_message(&self.from, message).lines() {
+            let line = match line.starts_with('.') {
+                true => format!(".{}", line),
+                false => line.to_string(),
+            };
+            write!(writer, "{}\r\n", line)?;
+        }
+        write!(writer, ".\r\n")?;
+        read_reply(&mut reader, 250)?;
+        write!(writer, "QUIT\r\n")?;
+        // The message is accepted already
+        let _ = read_reply(&mut reader, 221);
+        Ok(())
+    }
+}
+
+impl Notifier for SmtpNotifier {
+    fn send(&self, message: &Message) -> ServiceResult<()> {
+        check_address(&self.from)?;
+        check_address(&message.to)?;
+        self.session(message)
+            .map_err(|e| ServiceError::internal_error(&format!("Error while sending email: {}", e)))
+    }
+}
+
+// Addresses are written into SMTP commands and headers as they are,
+// so line breaks or angle brackets could inject commands or headers
+fn check_address(address: &str) -> ServiceResult<()> {
+    match address
+        .chars()
+        .any(|c| c.is_control() || c == '<' || c == '>')
+    {
+        true => Err(ServiceError::bad_request(&format!(
+            "Invalid email address: {:?}",
+            address
+        ))),
+        false => Ok(()),
+    }
+}
+
+// Read a possibly multiline reply and check its code
+fn read_reply<R: BufRead>(reader: &mut R, expected: u16) -> std::io::Result<()> {
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "SMTP server closed the connection",
+            ));
+        }
+        // Lines of a multiline reply are like 250-...
+        if line.as_bytes().get(3) == Some(&b'-') {
+            continue;
+        }
+        return match line.get(0..3).and_then(|code| code.parse::<u16>().ok()) {
+            Some(code) if code == expected => Ok(()),
+            _ => Err(std::io::Error::other(format!(
+                "Unexpected SMTP reply: {}",
+                line.trim_end()
+            ))),
+        };
+    }
+}
+
+// Message as an RFC 5322 email
+fn format_message(from: &str, message: &Message) -> String {
+    let mut headers = Vec::new();
+    if !from.is_empty() {
+        headers.push(format!("From: <{}>", from));
+    }
+    headers.push(format!("To: <{}>", message.to));
+    headers.push(format!("Subject: {}", encode_header(&message.subject)));
+    headers.push(format!("Date: {}", Utc::now().to_rfc2822()));
+    headers.push("MIME-Version: 1.0".to_string());
+    headers.push("Content-Type: text/plain; charset=UTF-8".to_string());
+    headers.push("Content-Transfer-Encoding: 8bit".to_string());
+    format!("{}\r\n\r\n{}\r\n", headers.join("\r\n"), message.body)
+}
+
+// Non-ASCII header values are Q-encoded, e.g. accented subjects
+fn encode_header(value: &str) -> String {
+    if value.is_ascii() {
+        return value.to_string();
+    }
+    let encoded = value
+        .bytes()
+        .map(|b| match b {
+            b' ' => "_".to_string(),
+            b'0'..=b'9' | b'a'..=b'z' | b'A'..=b'Z' => (b as char).to_string(),
+            _ => format!("={:02X}", b),
+        })
+        .collect::<String>();
+    format!("=?UTF-8?Q?{}?=", encoded)
+}
+
+/// # From env
+/// SMTP notifier if USER_SMTP_SERVER and USER_SMTP_FROM are set,
+/// spool notifier if USER_MAIL_SPOOL is set, error otherwise.
+/// Messages contain codes and tokens, so there is no fallback.
+pub fn from_env() -> ServiceResult<Arc<dyn Notifier>> {
+    let var = |name: &str| std::env::var(name).ok().filter(|v| !v.is_empty());
+    match (
+        var(SMTP_SERVER_ENV),
+        var(SMTP_FROM_ENV),
+        var(MAIL_SPOOL_ENV),
+    ) {
+        (Some(server), Some(from), _) => Ok(Arc::new(SmtpNotifier::new(server, from))),
+        (Some(_), None, _) => Err(ServiceError::bad_request(&format!(
+            "{} is required by {}",
+            SMTP_FROM_ENV, SMTP_SERVER_ENV
+        ))),
+        (None, _, Some(dir)) => Ok(Arc::new(SpoolNotifier::new(PathBuf::from(dir))?)),
+        (None, _, None) => Err(ServiceError::bad_request(&format!(
+            "No notifier configured, set {} and {}, or {}",
+            SMTP_SERVER_ENV, SMTP_FROM_ENV, MAIL_SPOOL_ENV
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    fn message() -> Message {
+        Message {
+            to: "demo@user.com".into(),
+            subject: "Új jelszó".into(),
+            body: "Hello\n.dot".into(),
+        }
+    }
+
+    #[test]
+    fn test_spool_notifier() {
+        let dir = std::env::temp_dir().join(format!(
+            "user_microservice_spool_{}",
+            hex::encode(rand::thread_rng().gen::<[u8; 4]>())
+        ));
+        let notifier = SpoolNotifier::new(dir.clone()).unwrap();
+        assert_eq!(notifier.send(&message()).is_ok(), true); // should be ok
+        let files = std::fs::read_dir(&dir).unwrap().collect::<Vec<_>>();
+        assert_eq!(files.len(), 1);
+        let content = std::fs::read_to_string(files[0].as_ref().unwrap().path()).unwrap();
+        assert_eq!(content.contains("To: <demo@user.com>"), true);
+        assert_eq!(
+            content.contains("Subject: =?UTF-8?Q?=C3=9Aj_jelsz=C3=B3?="),
+            true
+        );
+        assert_eq!(content.contains("Hello\n.dot"), true);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    // Local SMTP stand-in, returns the received session
+    fn smtp_server(data_reply: &'static str) -> (String, std::thread::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let handle = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut writer = stream;
+            let mut received = String::new();
+            let mut in_data = false;
+            writer.write_all(b"220 localhost ESMTP\r\n").unwrap();
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 {
+                    break;
+                }
+                received.push_str(&line);
+                let reply: &[u8] = if in_data {
+                    if line != ".\r\n" {
+                        continue;
+                    }
+                    in_data = false;
+                    data_reply.as_bytes()
+                } else if line.starts_with("EHLO") {
+                    b"250-localhost\r\n250 8BITMIME\r\n"
+                } else if line.starts_with("DATA") {
+                    in_data = true;
+                    b"354 go ahead\r\n"
+                } else if line.starts_with("QUIT") {
+                    writer.write_all(b"221 bye\r\n").unwrap();
+                    break;
+                } else {
+                    b"250 ok\r\n"
+                };
+                writer.write_all(reply).unwrap();
+            }
+            received
+        });
+        (address, handle)
+    }
+
+    #[test]
+    fn test_smtp_notifier() {
+        let (address, handle) = smtp_server("250 queued\r\n");
+        let notifier = SmtpNotifier::new(address, "noreply@gardenova.hu".into());
+        assert_eq!(notifier.send(&message()).is_ok(), true); // should be ok
+        let received = handle.join().unwrap();
+        assert_eq!(
+            received.contains("MAIL FROM:<noreply@gardenova.hu>\r\n"),
+            true
+        );
+        assert_eq!(received.contains("RCPT TO:<demo@user.com>\r\n"), true);
+        // Dot stuffed
+        assert_eq!(received.contains("\r\n..dot\r\n"), true);
+    }
+
+    #[test]
+    fn test_smtp_rejected() {
+        let (address, handle) = smtp_server("554 rejected\r\n");
+        let notifier = SmtpNotifier::new(address, "noreply@gardenova.hu".into());
+        assert_eq!(notifier.send(&message()).is_err(), true); // should be err
+        drop(notifier);
+        let _ = handle.join();
+    }
+
+    #[test]
+    fn test_address_injection() {
+        // Rejected before connecting anywhere
+        let notifier = SmtpNotifier::new("127.0.0.1:1".into(), "noreply@gardenova.hu".into());
+        let mut injected = message();
+        injected.to = "demo@user.com>\r\nRCPT TO:<other@user.com".into();
+        match notifier.send(&injected) {
+            Err(ServiceError::BadRequest(_)) => (),
+            _ => panic!("should be bad request"),
+        }
+        assert_eq!(check_address("demo@user.com").is_ok(), true); // should be ok
+        assert_eq!(check_address("demo@user.com\nBcc: x@y.com").is_err(), true);
+        // should be err
+    }
+}
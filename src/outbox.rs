@@ -0,0 +1,368 @@
+// Copyright (C) 2020 Peter Mezei
+//
+// This file is part of Gardenzilla.
+//
+// Gardenzilla is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 2 of the License, or
+// (at your option) any later version.
+//
+// Gardenzilla is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
+
+use crate::notifier::{Message, Notifier};
+use crate::prelude::ServiceError::*;
+use crate::prelude::*;
+use chrono::prelude::*;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use storaget::*;
+
+// Delay of the first retry in seconds, doubled by every further attempt
+const RETRY_BASE_SECONDS: i64 = 30;
+// Longest delay between two attempts, in seconds
+const RETRY_MAX_SECONDS: i64 = 6 * 60 * 60;
+// Failed attempts before a message is dead-lettered
+pub const MAX_ATTEMPTS: u32 = 8;
+// How often the delivery task looks for due messages, in seconds
+const POLL_INTERVAL_SECONDS: u64 = 5;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum OutboxStatus {
+    // Waiting for its next attempt
+    Pending,
+    Delivered,
+    // Gave up after MAX_ATTEMPTS, waits for a requeue
+    Dead,
+}
+
+/// # Outbox message
+/// Message waiting for delivery, or its delivery record.
+/// The body is dropped once delivered, as it may hold
+/// passwords or login codes.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct OutboxMessage {
+    id: String,
+    message: Message,
+    status: OutboxStatus,
+    // Failed attempts
+    attempts: u32,
+    date_created: DateTime<Utc>,
+    next_attempt_at: DateTime<Utc>,
+    delivered_at: Option<DateTime<Utc>>,
+    last_error: Option<String>,
+}
+
+impl Default for OutboxMessage {
+    fn default() -> Self {
+        OutboxMessage {
+            id: String::default(),
+            message: Message::default(),
+            status: OutboxStatus::Pending,
+            attempts: 0,
+            date_created: Utc::now(),
+            next_attempt_at: Utc::now(),
+            delivered_at: None,
+            last_error: None,
+        }
+    }
+}
+
+impl TryFrom for OutboxMessage {
+    type TryFrom = OutboxMessage;
+}
+
+impl VecPackMember for OutboxMessage {
+    fn get_id(&self) -> &str {
+        &self.id
+    }
+}
+
+impl OutboxMessage {
+    pub fn new(message: Message) -> Self {
+        let now = Utc::now();
+        OutboxMessage {
+            id: hex::encode(rand::thread_rng().gen::<[u8; 8]>()),
+            message,
+            status: OutboxStatus::Pending,
+            attempts: 0,
+            date_created: now,
+            next_attempt_at: now,
+            delivered_at: None,
+            last_error: None,
+        }
+    }
+    pub fn get_id(&self) -> &str {
+        &self.id
+    }
+    pub fn get_message(&self) -> &Message {
+        &self.message
+    }
+    pub fn get_status(&self) -> OutboxStatus {
+        self.status
+    }
+    pub fn get_attempts(&self) -> u32 {
+        self.attempts
+    }
+    pub fn get_date_created(&self) -> DateTime<Utc> {
+        self.date_created
+    }
+    pub fn get_next_attempt_at(&self) -> DateTime<Utc> {
+        self.next_attempt_at
+    }
+    pub fn get_delivered_at(&self) -> Option<DateTime<Utc>> {
+        self.delivered_at
+    }
+    pub fn get_last_error(&self) -> Option<&str> {
+        self.last_error.as_deref()
+    }
+    pub fn is_due(&self, now: DateTime<Utc>) -> bool {
+        self.status == OutboxStatus::Pending && self.next_attempt_at <= now
+    }
+    pub fn delivered(&mut self, now: DateTime<Utc>) {
+        self.status = OutboxStatus::Delivered;
+        self.delivered_at = Some(now);
+        self.last_error = None;
+        self.message.body = String::new();
+    }
+    /// Schedule the next attempt, or dead-letter
+    /// the message after MAX_ATTEMPTS
+    pub fn failed(&mut self, error: String, now: DateTime<Utc>) {
+        self.attempts += 1;
+        self.last_error = Some(error);
+        if self.attempts >= MAX_ATTEMPTS {
+            self.status = OutboxStatus::Dead;
+        } else {
+            self.next_attempt_at = now + retry_delay(self.attempts);
+        }
+    }
+    /// Give a dead message another MAX_ATTEMPTS
+    pub fn requeue(&mut self) -> ServiceResult<()> {
+        if self.status != OutboxStatus::Dead {
+            return Err(FailedPrecondition(
+                "Only dead messages can be requeued".into(),
+            ));
+        }
+        self.status = OutboxStatus::Pending;
+        self.attempts = 0;
+        self.next_attempt_at = Utc::now();
+        Ok(())
+    }
+}
+
+/// Delay after the given number of failed attempts
+pub fn retry_delay(attempts: u32) -> chrono::Duration {
+    let seconds = RETRY_BASE_SECONDS
+        .checked_mul(1 << attempts.saturating_sub(1).min(30))
+        .unwrap_or(RETRY_MAX_SECONDS)
+        .min(RETRY_MAX_SECONDS);
+    chrono::Duration::seconds(seconds)
+}
+
+/// # Outbox
+/// Durable queue of outgoing messages. Messages are stored
+/// before the RPC returns, and delivered by a background task,
+/// so a notifier failure never fails the request itself.
+pub struct Outbox {
+    messages: Arc<Mutex<VecPack<OutboxMessage>>>,
+    notifier: Arc<dyn Notifier>,
+}
+
+impl Outbox {
+    pub fn new(messages: VecPack<OutboxMessage>, notifier: Arc<dyn Notifier>) -> Self {
+        Self {
+            messages: Arc::new(Mutex::new(messages)),
+            notifier,
+        }
+    }
+    /// Store the message for delivery, returns its ID.
+    /// Written on the blocking thread pool.
+    pub async fn enqueue(&self, message: Message) -> ServiceResult<String> {
+        let message = OutboxMessage::new(message);
+        let id = message.get_id().to_string();
+        blocking(&self.messages, move |messages| {
+            messages.insert(message)?;
+            Ok(())
+        })
+        .await?;
+        Ok(id)
+    }
+    /// Messages with one of the statuses, or all of them if empty,
+    /// oldest first
+    pub fn list(&self, statuses: &[OutboxStatus]) -> ServiceResult<Vec<OutboxMessage>> {
+        let mut messages = self
+            .messages
+            .lock()
+            .map_err(|_| ServiceError::internal_error("Lock error"))?
+            .into_iter()
+            .map(|m: &mut Pack<OutboxMessage>| m.unpack().clone())
+            .filter(|m| statuses.is_empty() || statuses.contains(&m.get_status()))
+            .collect::<Vec<OutboxMessage>>();
+        messages.sort_by_key(|m| m.get_date_created());
+        Ok(messages)
+    }
+    pub async fn requeue(&self, id: &str) -> ServiceResult<OutboxMessage> {
+        let id = id.to_string();
+        blocking(&self.messages, move |messages| {
+            let message = messages
+                .find_id_mut(&id)
+                .map_err(|_| ServiceError::not_found("Message not found"))?;
+            let mut requeued = message.unpack().clone();
+            requeued.requeue()?;
+            message.update(|m| *m = requeued.clone())?;
+            Ok(requeued)
+        })
+        .await
+    }
+    /// # Deliver due
+    /// Try every due message once. The lock is not held while
+    /// sending, so enqueue is never blocked by a slow notifier.
+    /// Returns the number of delivered messages.
+    pub fn deliver_due(&self, now: DateTime<Utc>) -> ServiceResult<usize> {
+        let due = self
+            .messages
+            .lock()
+            .map_err(|_| ServiceError::internal_error("Lock error"))?
+            .into_iter()
+            .filter(|m: &&mut Pack<OutboxMessage>| m.unpack().is_due(now))
+            .map(|m: &mut Pack<OutboxMessage>| {
+                (m.unpack().get_id().to_string(), m.unpack().message.clone())
+            })
+            .collect::<Vec<(String, Message)>>();
+        let mut delivered = 0;
+        for (id, message) in due {
+            let result = self.notifier.send(&message);
+            let mut messages = self
+                .messages
+                .lock()
+                .map_err(|_| ServiceError::internal_error("Lock error"))?;
+            let stored = messages
+                .find_id_mut(&id)
+                .map_err(|_| ServiceError::not_found("Message not found"))?;
+            match result {
+                Ok(_) => {
+                    delivered += 1;
+                    stored.update(|m| m.delivered(now))?;
+                }
+                Err(err) => stored.update(|m| m.failed(err.to_string(), now))?,
+            }
+        }
+        Ok(delivered)
+    }
+}
+
+/// # Run
+/// Background delivery task, runs till the process exits.
+/// Sending is blocking IO, so it runs on the blocking thread pool.
+pub async fn run(outbox: Arc<Outbox>) {
+    loop {
+        let worker = outbox.clone();
+        match tokio::task::spawn_blocking(move || worker.deliver_due(Utc::now())).await {
+            Ok(Ok(_)) => (),
+            Ok(Err(err)) => eprintln!("Error while delivering outbox: {}", err),
+            Err(err) => eprintln!("Outbox delivery panicked: {}", err),
+        }
+        tokio::time::delay_for(std::time::Duration::from_secs(POLL_INTERVAL_SECONDS)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    // Fails the first `failures` sends
+    struct FlakyNotifier {
+        failures: Mutex<u32>,
+        sent: Mutex<Vec<Message>>,
+    }
+
+    impl Notifier for FlakyNotifier {
+        fn send(&self, message: &Message) -> ServiceResult<()> {
+            let mut failures = self.failures.lock().unwrap();
+            if *failures > 0 {
+                *failures -= 1;
+                return Err(ServiceError::internal_error("SMTP is down"));
+            }
+            self.sent.lock().unwrap().push(message.clone());
+            Ok(())
+        }
+    }
+
+    fn outbox(failures: u32) -> (Outbox, Arc<FlakyNotifier>, PathBuf) {
+        let dir = std::env::temp_dir().join(format!(
+            "user_microservice_outbox_{}",
+            hex::encode(rand::thread_rng().gen::<[u8; 4]>())
+        ));
+        let notifier = Arc::new(FlakyNotifier {
+            failures: Mutex::new(failures),
+            sent: Mutex::new(Vec::new()),
+        });
+        let messages = VecPack::try_load_or_init(dir.clone()).unwrap();
+        (Outbox::new(messages, notifier.clone()), notifier, dir)
+    }
+
+    fn message() -> Message {
+        Message {
+            to: "demo@user.com".into(),
+            subject: "Hello".into(),
+            body: "secret".into(),
+        }
+    }
+
+    #[test]
+    fn test_retry_delay() {
+        assert_eq!(retry_delay(1), chrono::Duration::seconds(30));
+        assert_eq!(retry_delay(2), chrono::Duration::seconds(60));
+        assert_eq!(retry_delay(4), chrono::Duration::seconds(240));
+        assert_eq!(
+            retry_delay(40),
+            chrono::Duration::seconds(RETRY_MAX_SECONDS)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_delivery() {
+        let (outbox, notifier, dir) = outbox(1);
+        let id = outbox.enqueue(message()).await.unwrap();
+        let now = Utc::now();
+        // First attempt fails, retried later
+        assert_eq!(outbox.deliver_due(now).unwrap(), 0);
+        let stored = outbox.list(&[]).unwrap().remove(0);
+        assert_eq!(stored.get_attempts(), 1);
+        assert_eq!(stored.get_last_error().is_some(), true);
+        assert_eq!(outbox.deliver_due(now).unwrap(), 0);
+        assert_eq!(outbox.deliver_due(now + retry_delay(1)).unwrap(), 1);
+        assert_eq!(notifier.sent.lock().unwrap().len(), 1);
+        let stored = outbox.list(&[OutboxStatus::Delivered]).unwrap().remove(0);
+        assert_eq!(stored.get_id(), id);
+        // Secrets are not kept
+        assert_eq!(stored.get_message().body, "");
+        assert_eq!(outbox.requeue(&id).await.is_err(), true); // should be err
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_dead_letter() {
+        let (outbox, notifier, dir) = outbox(MAX_ATTEMPTS);
+        let id = outbox.enqueue(message()).await.unwrap();
+        let mut now = Utc::now();
+        for _ in 0..MAX_ATTEMPTS {
+            assert_eq!(outbox.deliver_due(now).unwrap(), 0);
+            now += chrono::Duration::seconds(RETRY_MAX_SECONDS);
+        }
+        assert_eq!(outbox.list(&[OutboxStatus::Dead]).unwrap().len(), 1);
+        // Dead messages are not retried
+        assert_eq!(outbox.deliver_due(now).unwrap(), 0);
+        assert_eq!(outbox.requeue(&id).await.is_ok(), true); // should be ok
+        assert_eq!(outbox.deliver_due(Utc::now()).unwrap(), 1);
+        assert_eq!(notifier.sent.lock().unwrap()[0].body, "secret");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
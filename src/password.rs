@@ -48,7 +48,7 @@ pub fn hash_password(password: &str) -> ServiceResult<String> {
 ///                         &hash).unwrap();
 /// ```
 pub fn verify_password_from_hash<'a>(password: &'a str, hash: &'a str) -> ServiceResult<bool> {
-    match verify(password, &hash) {
+    match verify(password, hash) {
         Ok(result) => Ok(result),
         Err(_) => Err(InternalError(
             "ServiceError while trying verify password from hash".into(),
@@ -63,6 +63,7 @@ pub fn verify_password_from_hash<'a>(password: &'a str, hash: &'a str) -> Servic
 /// use core_lib::user::password::generate_random_password;
 /// let password = generate_random_password(None).unwrap();
 /// ```
+#[allow(dead_code)]
 pub fn generate_random_password(length: Option<u32>) -> ServiceResult<String> {
     let mut rng = rand::thread_rng();
     let mut password = "".to_owned();
@@ -3,6 +3,10 @@ pub enum ServiceError {
     NotFound(String),
     AlreadyExists(String),
     BadRequest(String),
+    FailedPrecondition(String),
+    Aborted(String),
+    Unauthenticated(String),
+    PermissionDenied(String),
 }
 
 impl ServiceError {
@@ -18,6 +22,18 @@ impl ServiceError {
     pub fn bad_request(msg: &str) -> Self {
         ServiceError::BadRequest(msg.to_string())
     }
+    pub fn failed_precondition(msg: &str) -> Self {
+        ServiceError::FailedPrecondition(msg.to_string())
+    }
+    pub fn aborted(msg: &str) -> Self {
+        ServiceError::Aborted(msg.to_string())
+    }
+    pub fn unauthenticated(msg: &str) -> Self {
+        ServiceError::Unauthenticated(msg.to_string())
+    }
+    pub fn permission_denied(msg: &str) -> Self {
+        ServiceError::PermissionDenied(msg.to_string())
+    }
 }
 
 impl std::fmt::Display for ServiceError {
@@ -27,6 +43,10 @@ impl std::fmt::Display for ServiceError {
             ServiceError::NotFound(msg) => write!(f, "{}", msg),
             ServiceError::AlreadyExists(msg) => write!(f, "{}", msg),
             ServiceError::BadRequest(msg) => write!(f, "{}", msg),
+            ServiceError::FailedPrecondition(msg) => write!(f, "{}", msg),
+            ServiceError::Aborted(msg) => write!(f, "{}", msg),
+            ServiceError::Unauthenticated(msg) => write!(f, "{}", msg),
+            ServiceError::PermissionDenied(msg) => write!(f, "{}", msg),
         }
     }
 }
@@ -47,6 +67,10 @@ impl From<ServiceError> for ::tonic::Status {
             ServiceError::NotFound(msg) => ::tonic::Status::not_found(msg),
             ServiceError::AlreadyExists(msg) => ::tonic::Status::already_exists(msg),
             ServiceError::BadRequest(msg) => ::tonic::Status::invalid_argument(msg),
+            ServiceError::FailedPrecondition(msg) => ::tonic::Status::failed_precondition(msg),
+            ServiceError::Aborted(msg) => ::tonic::Status::aborted(msg),
+            ServiceError::Unauthenticated(msg) => ::tonic::Status::unauthenticated(msg),
+            ServiceError::PermissionDenied(msg) => ::tonic::Status::permission_denied(msg),
         }
     }
 }
@@ -57,4 +81,51 @@ impl From<::storaget::PackError> for ServiceError {
     }
 }
 
+impl From<::std::io::Error> for ServiceError {
+    fn from(error: ::std::io::Error) -> Self {
+        ServiceError::internal_error(&error.to_string())
+    }
+}
+
+impl From<::rusqlite::Error> for ServiceError {
+    fn from(error: ::rusqlite::Error) -> Self {
+        ServiceError::internal_error(&error.to_string())
+    }
+}
+
+impl From<::tokio_postgres::Error> for ServiceError {
+    fn from(error: ::tokio_postgres::Error) -> Self {
+        ServiceError::internal_error(&error.to_string())
+    }
+}
+
+impl From<::deadpool_postgres::PoolError> for ServiceError {
+    fn from(error: ::deadpool_postgres::PoolError) -> Self {
+        ServiceError::internal_error(&format!("Postgres pool error: {}", error))
+    }
+}
+
 pub type ServiceResult<T> = Result<T, ServiceError>;
+
+/// # Blocking
+/// Run a call on the locked storage on the blocking thread pool,
+/// so its disk or database I/O does not block the async executor
+pub async fn blocking<S, T, F>(
+    storage: &::std::sync::Arc<::std::sync::Mutex<S>>,
+    call: F,
+) -> ServiceResult<T>
+where
+    S: Send + 'static,
+    T: Send + 'static,
+    F: FnOnce(&mut S) -> ServiceResult<T> + Send + 'static,
+{
+    let storage = storage.clone();
+    ::tokio::task::spawn_blocking(move || {
+        let mut storage = storage
+            .lock()
+            .map_err(|_| ServiceError::internal_error("Lock error"))?;
+        call(&mut storage)
+    })
+    .await
+    .map_err(|e| ServiceError::internal_error(&format!("Storage task failed: {}", e)))?
+}
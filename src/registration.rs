@@ -0,0 +1,73 @@
+// Copyright (C) 2020 Peter Mezei
+//
+// This file is part of Gardenzilla.
+//
+// Gardenzilla is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 2 of the License, or
+// (at your option) any later version.
+//
+// Gardenzilla is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
+
+// Email domains approved automatically, separated by comma.
+// * approves every registration.
+pub const AUTO_APPROVE_DOMAINS_ENV: &str = "USER_AUTO_APPROVE_DOMAINS";
+
+/// # Registration rules
+/// Decide which self-registered users are approved
+/// without waiting for an admin
+#[derive(Clone, Debug, Default)]
+pub struct RegistrationRules {
+    domains: Vec<String>,
+}
+
+impl RegistrationRules {
+    pub fn new(domains: Vec<String>) -> Self {
+        Self {
+            domains: domains
+                .into_iter()
+                .map(|domain| domain.trim().trim_start_matches('@').to_lowercase())
+                .filter(|domain| !domain.is_empty())
+                .collect(),
+        }
+    }
+    /// Load rules from USER_AUTO_APPROVE_DOMAINS,
+    /// e.g. USER_AUTO_APPROVE_DOMAINS=gardenova.hu,example.com
+    pub fn from_env() -> Self {
+        let value = std::env::var(AUTO_APPROVE_DOMAINS_ENV).unwrap_or_default();
+        Self::new(value.split(',').map(|domain| domain.to_string()).collect())
+    }
+    pub fn is_auto_approved(&self, email: &str) -> bool {
+        let domain = match email.rsplit('@').next() {
+            Some(domain) if email.contains('@') => domain.to_lowercase(),
+            _ => return false,
+        };
+        self.domains.iter().any(|d| d == "*" || *d == domain)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_auto_approval() {
+        let rules = RegistrationRules::new(vec![" @Gardenova.hu".into(), "".into()]);
+        assert_eq!(rules.is_auto_approved("demo@gardenova.hu"), true);
+        assert_eq!(rules.is_auto_approved("demo@GARDENOVA.HU"), true);
+        assert_eq!(rules.is_auto_approved("demo@shop.gardenova.hu"), false);
+        assert_eq!(rules.is_auto_approved("gardenova.hu"), false);
+        assert_eq!(
+            RegistrationRules::default().is_auto_approved("demo@gardenova.hu"),
+            false
+        );
+        let everyone = RegistrationRules::new(vec!["*".into()]);
+        assert_eq!(everyone.is_auto_approved("demo@user.com"), true);
+    }
+}
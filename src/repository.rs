@@ -0,0 +1,396 @@
+// Copyright (C) 2020 Peter Mezei
+//
+// This file is part of Gardenzilla.
+//
+// Gardenzilla is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 2 of the License, or
+// (at your option) any later version.
+//
+// Gardenzilla is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
+
+use crate::postgres::{self, PostgresRepository};
+use crate::prelude::ServiceError::*;
+use crate::prelude::*;
+use crate::sqlite::SqliteRepository;
+use crate::user::{User, UserStatus};
+use std::path::PathBuf;
+use storaget::*;
+
+// Storage backend of the users, vecpack, sqlite or postgres
+pub const STORAGE_ENV: &str = "USER_STORAGE";
+// Database file of the sqlite backend
+pub const SQLITE_PATH_ENV: &str = "USER_SQLITE_PATH";
+// Connection URL of the postgres backend
+pub const POSTGRES_URL_ENV: &str = "USER_POSTGRES_URL";
+// Maximum number of pooled Postgres connections
+pub const POSTGRES_POOL_SIZE_ENV: &str = "USER_POSTGRES_POOL_SIZE";
+// Set to true to store users loaded from an older schema in the current one
+pub const REWRITE_UPGRADED_ENV: &str = "USER_REWRITE_UPGRADED";
+const VECPACK_DIR: &str = "data/users";
+const SQLITE_DEFAULT_PATH: &str = "data/users.db";
+const POSTGRES_DEFAULT_POOL_SIZE: usize = 16;
+
+/// # User query
+/// Every set field must match. Deleted users are
+/// left out unless asked for, or their status is queried.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct UserQuery {
+    pub include_deleted: bool,
+    pub status: Option<UserStatus>,
+    // Matched case insensitively
+    pub email: Option<String>,
+    // Users with a membership at the customer
+    pub customer_id: Option<String>,
+}
+
+impl UserQuery {
+    pub fn matches(&self, user: &User) -> bool {
+        let status_matches = match self.status {
+            Some(status) => user.get_status() == status,
+            None => self.include_deleted || !user.is_deleted(),
+        };
+        status_matches
+            && self
+                .email
+                .as_ref()
+                .is_none_or(|e| user.get_user_email().to_lowercase() == e.to_lowercase())
+            && self
+                .customer_id
+                .as_ref()
+                .is_none_or(|c| user.get_membership(c).is_some())
+    }
+}
+
+/// # User repository
+/// Storage of the users behind UserService.
+/// Users are returned as copies; a change is stored by update.
+pub trait UserRepository: Send {
+    /// User by ID, deleted ones included
+    fn find(&self, id: &str) -> ServiceResult<Option<User>>;
+    /// Store a new user, fails if the ID is taken
+    fn insert(&mut self, user: User) -> ServiceResult<()>;
+    /// Replace a stored user with the same ID, if it is still
+    /// at the given version. Aborted otherwise, so a change made
+    /// on an old copy never overwrites a newer one.
+    fn update(&mut self, user: User, version: u64) -> ServiceResult<()>;
+    /// Remove the user for good. Users are only marked as deleted
+    /// by the service, so this is for maintenance only.
+    fn delete(&mut self, id: &str) -> ServiceResult<()>;
+    /// Every user, deleted ones included, in storage order
+    fn list(&self) -> ServiceResult<Vec<User>>;
+    /// Users matching the query, in storage order
+    fn query(&self, query: &UserQuery) -> ServiceResult<Vec<User>> {
+        Ok(self
+            .list()?
+            .into_iter()
+            .filter(|user| query.matches(user))
+            .collect())
+    }
+}
+
+/// # VecPack repository
+/// Default repository, one YAML file per user
+pub struct VecPackRepository {
+    users: VecPack<User>,
+    // IDs in storage order, so listing needs no mutable access
+    ids: Vec<String>,
+}
+
+impl VecPackRepository {
+    pub fn new(mut users: VecPack<User>) -> Self {
+        let ids = users
+            .into_iter()
+            .map(|u: &mut Pack<User>| u.unpack().get_user_id().to_string())
+            .collect();
+        Self { users, ids }
+    }
+}
+
+impl UserRepository for VecPackRepository {
+    fn find(&self, id: &str) -> ServiceResult<Option<User>> {
+        Ok(self.users.find_id(id).ok().map(|u| u.unpack().clone()))
+    }
+    fn insert(&mut self, user: User) -> ServiceResult<()> {
+        if self.users.find_id(user.get_user_id()).is_ok() {
+            return Err(AlreadyExists("User exist!".into()));
+        }
+        let id = user.get_user_id().to_string();
+        self.users.insert(user)?;
+        self.ids.push(id);
+        Ok(())
+    }
+    fn update(&mut self, user: User, version: u64) -> ServiceResult<()> {
+        let stored = self
+            .users
+            .find_id_mut(user.get_user_id())
+            .map_err(|_| NotFound("User not found".into()))?;
+        if stored.unpack().get_version() != version {
+            return Err(Aborted("User was updated meanwhile".into()));
+        }
+        stored.update(|u| *u = user.clone())?;
+        Ok(())
+    }
+    // VecPack cannot remove, so the pack is taken out
+    // of it and its file is removed here
+    fn delete(&mut self, id: &str) -> ServiceResult<()> {
+        let position = self
+            .users
+            .iter()
+            .position(|u| u.unpack().get_user_id() == id)
+            .ok_or_else(|| NotFound("User not found".into()))?;
+        self.users.as_vec_mut().remove(position);
+        self.ids.retain(|i| i != id);
+        std::fs::remove_file(self.users.get_path().join(format!("{}.yml", id)))
+            .map_err(|e| InternalError(format!("Cannot remove user file: {}", e)))
+    }
+    fn list(&self) -> ServiceResult<Vec<User>> {
+        Ok(self
+            .ids
+            .iter()
+            .filter_map(|id| self.users.find_id(id).ok())
+            .map(|u| u.unpack().clone())
+            .collect())
+    }
+}
+
+/// # Memory repository
+/// Keeps the users in memory only, for tests
+#[derive(Default)]
+pub struct MemoryRepository {
+    users: Vec<User>,
+}
+
+impl UserRepository for MemoryRepository {
+    fn find(&self, id: &str) -> ServiceResult<Option<User>> {
+        Ok(self.users.iter().find(|u| u.get_user_id() == id).cloned())
+    }
+    fn insert(&mut self, user: User) -> ServiceResult<()> {
+        if self.find(user.get_user_id())?.is_some() {
+            return Err(AlreadyExists("User exist!".into()));
+        }
+        self.users.push(user);
+        Ok(())
+    }
+    fn update(&mut self, user: User, version: u64) -> ServiceResult<()> {
+        match self
+            .users
+            .iter_mut()
+            .find(|u| u.get_user_id() == user.get_user_id())
+        {
+            Some(stored) if stored.get_version() == version => {
+                *stored = user;
+                Ok(())
+            }
+            Some(_) => Err(Aborted("User was updated meanwhile".into())),
+            None => Err(NotFound("User not found".into())),
+        }
+    }
+    fn delete(&mut self, id: &str) -> ServiceResult<()> {
+        let count = self.users.len();
+        self.users.retain(|u| u.get_user_id() != id);
+        match self.users.len() < count {
+            true => Ok(()),
+            false => Err(NotFound("User not found".into())),
+        }
+    }
+    fn list(&self) -> ServiceResult<Vec<User>> {
+        Ok(self.users.clone())
+    }
+}
+
+/// # From env
+/// Repository selected by USER_STORAGE,
+/// the VecPack one under data/users by default.
+/// Postgres needs the threaded runtime.
+pub fn from_env() -> ServiceResult<Box<dyn UserRepository>> {
+    match std::env::var(STORAGE_ENV).unwrap_or_default().as_str() {
+        "" | "vecpack" => Ok(Box::new(VecPackRepository::new(VecPack::try_load_or_init(
+            PathBuf::from(VECPACK_DIR),
+        )?))),
+        "sqlite" => {
+            let path = std::env::var(SQLITE_PATH_ENV)
+                .ok()
+                .filter(|p| !p.is_empty())
+                .unwrap_or_else(|| SQLITE_DEFAULT_PATH.to_string());
+            Ok(Box::new(SqliteRepository::open(path)?))
+        }
+        "postgres" => {
+            let url = std::env::var(POSTGRES_URL_ENV).map_err(|_| {
+                InternalError(format!(
+                    "{} is required by postgres storage",
+                    POSTGRES_URL_ENV
+                ))
+            })?;
+            let pool_size = match std::env::var(POSTGRES_POOL_SIZE_ENV) {
+                Ok(size) => size.parse::<usize>().map_err(|_| {
+                    InternalError(format!("{} must be a number", POSTGRES_POOL_SIZE_ENV))
+                })?,
+                Err(_) => POSTGRES_DEFAULT_POOL_SIZE,
+            };
+            Ok(Box::new(postgres::wait(PostgresRepository::connect(
+                &url, pool_size,
+            ))?))
+        }
+        other => Err(InternalError(format!("Unknown user storage: {}", other))),
+    }
+}
+
+/// # Rewrite upgraded
+/// Store every user loaded from an older schema version in the
+/// current one, so it is not upgraded again by the next load.
+/// Returns the number of rewritten users.
+pub fn rewrite_upgraded(repo: &mut dyn UserRepository) -> ServiceResult<usize> {
+    let mut count = 0;
+    for mut user in repo.list()? {
+        if user.get_upgraded_from().is_some() {
+            let version = user.get_version();
+            user.clear_upgraded_from();
+            repo.update(user, version)?;
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+/// Behaviour every repository should have,
+/// run against an empty repository
+#[cfg(test)]
+pub fn check_repository(repo: &mut dyn UserRepository, supports_delete: bool) {
+    let new_user = |id: &str, email: &str| {
+        User::new(
+            id.into(),
+            "user".into(),
+            email.into(),
+            "".into(),
+            "admin".into(),
+            None,
+        )
+        .unwrap()
+    };
+    repo.insert(new_user("demo", "demo@user.com")).unwrap();
+    repo.insert(new_user("other", "other@user.com")).unwrap();
+    assert_eq!(repo.insert(new_user("demo", "x@user.com")).is_err(), true); // should be err
+    assert_eq!(repo.find("demo").unwrap().is_some(), true);
+    assert_eq!(repo.find("unknown").unwrap().is_none(), true);
+
+    let mut user = repo.find("demo").unwrap().unwrap();
+    user.set_user_name("Demo User".into()).unwrap();
+    user.add_customer("c1".into(), "member".into()).unwrap();
+    let stale = user.clone();
+    let user = crate::user::commit(&stale, user, "admin");
+    repo.update(user, 1).unwrap();
+    assert_eq!(
+        repo.find("demo").unwrap().unwrap().get_user_name(),
+        "Demo User"
+    );
+    // Made on an old version, not stored
+    match repo.update(stale, 1) {
+        Err(Aborted(_)) => (),
+        _ => panic!("should be aborted"),
+    }
+    assert_eq!(repo.find("demo").unwrap().unwrap().get_version(), 2);
+    match repo.update(new_user("unknown", "x@user.com"), 1) {
+        Err(NotFound(_)) => (),
+        _ => panic!("should be not found"),
+    }
+
+    let ids = |users: Vec<User>| {
+        users
+            .iter()
+            .map(|u| u.get_user_id().to_string())
+            .collect::<Vec<String>>()
+    };
+    assert_eq!(ids(repo.list().unwrap()), vec!["demo", "other"]);
+    let by_email = UserQuery {
+        email: Some("OTHER@user.com".into()),
+        ..UserQuery::default()
+    };
+    assert_eq!(ids(repo.query(&by_email).unwrap()), vec!["other"]);
+    let by_customer = UserQuery {
+        customer_id: Some("c1".into()),
+        ..UserQuery::default()
+    };
+    assert_eq!(ids(repo.query(&by_customer).unwrap()), vec!["demo"]);
+
+    let mut deleted = repo.find("other").unwrap().unwrap();
+    deleted.delete().unwrap();
+    repo.update(deleted, 1).unwrap();
+    assert_eq!(
+        ids(repo.query(&UserQuery::default()).unwrap()),
+        vec!["demo"]
+    );
+    let with_deleted = UserQuery {
+        include_deleted: true,
+        ..UserQuery::default()
+    };
+    assert_eq!(repo.query(&with_deleted).unwrap().len(), 2);
+    let by_status = UserQuery {
+        status: Some(UserStatus::Deleted),
+        ..UserQuery::default()
+    };
+    assert_eq!(ids(repo.query(&by_status).unwrap()), vec!["other"]);
+    // Deleted users are still found by ID
+    assert_eq!(repo.find("other").unwrap().unwrap().is_deleted(), true);
+
+    if supports_delete {
+        assert_eq!(repo.delete("other").is_ok(), true); // should be ok
+        assert_eq!(repo.find("other").unwrap().is_none(), true);
+        assert_eq!(repo.delete("other").is_err(), true); // should be err
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::Rng;
+
+    #[test]
+    fn test_memory_repository() {
+        check_repository(&mut MemoryRepository::default(), true);
+    }
+
+    #[test]
+    fn test_rewrite_upgraded() {
+        let dir = std::env::temp_dir().join(format!(
+            "user_microservice_users_{}",
+            hex::encode(rand::thread_rng().gen::<[u8; 4]>())
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("mezeipetister3.yml");
+        std::fs::write(&file, include_str!("../fixtures/users/v1.yml")).unwrap();
+        let mut repo = VecPackRepository::new(VecPack::try_load_or_init(dir.clone()).unwrap());
+        assert_eq!(rewrite_upgraded(&mut repo).unwrap(), 1);
+        assert_eq!(
+            std::fs::read_to_string(&file)
+                .unwrap()
+                .contains("schema_version"),
+            true
+        );
+        // Loads in the current schema from now on
+        let mut repo = VecPackRepository::new(VecPack::try_load_or_init(dir.clone()).unwrap());
+        assert_eq!(rewrite_upgraded(&mut repo).unwrap(), 0);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_vecpack_repository() {
+        let dir = std::env::temp_dir().join(format!(
+            "user_microservice_users_{}",
+            hex::encode(rand::thread_rng().gen::<[u8; 4]>())
+        ));
+        let mut repo = VecPackRepository::new(VecPack::try_load_or_init(dir.clone()).unwrap());
+        check_repository(&mut repo, true);
+        // Removed from the disk as well
+        let reloaded = VecPackRepository::new(VecPack::try_load_or_init(dir.clone()).unwrap());
+        assert_eq!(reloaded.find("other").unwrap().is_none(), true);
+        assert_eq!(reloaded.find("demo").unwrap().is_some(), true);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,247 @@
+// Copyright (C) 2020 Peter Mezei
+//
+// This file is part of Gardenzilla.
+//
+// Gardenzilla is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 2 of the License, or
+// (at your option) any later version.
+//
+// Gardenzilla is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
+
+use crate::prelude::ServiceError::*;
+use crate::prelude::*;
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use storaget::*;
+
+// Permissions used by the user service itself
+pub const USER_READ: &str = "user:read";
+pub const USER_WRITE: &str = "user:write";
+pub const USER_ADMIN: &str = "user:admin";
+
+// Roles created when the role storage is empty
+pub const ROLE_ADMIN: &str = "admin";
+pub const ROLE_USER: &str = "user";
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Role {
+    id: String,
+    name: String,
+    // Permissions like user:read, or with wildcard like user:*
+    permissions: Vec<String>,
+    date_created: DateTime<Utc>,
+    created_by: String,
+}
+
+impl Default for Role {
+    fn default() -> Self {
+        Role {
+            id: String::default(),
+            name: String::default(),
+            permissions: Vec::new(),
+            date_created: Utc::now(),
+            created_by: String::default(),
+        }
+    }
+}
+
+impl TryFrom for Role {
+    type TryFrom = Role;
+}
+
+impl VecPackMember for Role {
+    fn get_id(&self) -> &str {
+        &self.id
+    }
+}
+
+impl Role {
+    pub fn new(
+        id: String,
+        name: String,
+        permissions: Vec<String>,
+        created_by: String,
+    ) -> ServiceResult<Self> {
+        let id = id.to_lowercase();
+        if id.len() < 2
+            || id.len() > 20
+            || !id.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        {
+            return Err(BadRequest(
+                "A szerepkör azonosítója 2-20 karakter, angol kisbetű, szám és _ lehet".into(),
+            ));
+        }
+        let mut role = Role {
+            id,
+            name: String::default(),
+            permissions: Vec::new(),
+            date_created: Utc::now(),
+            created_by,
+        };
+        role.set_name(name)?;
+        role.set_permissions(permissions)?;
+        Ok(role)
+    }
+    pub fn get_id(&self) -> &str {
+        &self.id
+    }
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+    pub fn set_name(&mut self, name: String) -> ServiceResult<()> {
+        if name.is_empty() {
+            return Err(BadRequest("A szerepkör neve nem lehet üres".into()));
+        }
+        self.name = name;
+        Ok(())
+    }
+    pub fn get_permissions(&self) -> &Vec<String> {
+        &self.permissions
+    }
+    pub fn set_permissions(&mut self, mut permissions: Vec<String>) -> ServiceResult<()> {
+        for permission in &permissions {
+            validate_permission(permission)?;
+        }
+        permissions.sort();
+        permissions.dedup();
+        self.permissions = permissions;
+        Ok(())
+    }
+    pub fn get_date_created(&self) -> DateTime<Utc> {
+        self.date_created
+    }
+    pub fn get_created_by(&self) -> &str {
+        &self.created_by
+    }
+    /// Does this role grant the permission
+    pub fn grants(&self, permission: &str) -> bool {
+        self.permissions
+            .iter()
+            .any(|granted| permission_matches(granted, permission))
+    }
+}
+
+/// Roles the service needs to be usable from the first start
+pub fn default_roles() -> Vec<Role> {
+    vec![
+        Role::new(
+            ROLE_ADMIN.into(),
+            "Adminisztrátor".into(),
+            vec![USER_READ.into(), USER_WRITE.into(), USER_ADMIN.into()],
+            "system".into(),
+        )
+        .expect("Invalid default role"),
+        Role::new(
+            ROLE_USER.into(),
+            "Felhasználó".into(),
+            vec![USER_READ.into()],
+            "system".into(),
+        )
+        .expect("Invalid default role"),
+    ]
+}
+
+/// # Validate permission
+/// Permission format is resource:action, e.g. user:read.
+/// Action can be * meaning every action on the resource,
+/// and * alone means every permission.
+pub fn validate_permission(permission: &str) -> ServiceResult<()> {
+    if permission == "*" {
+        return Ok(());
+    }
+    let valid_part =
+        |part: &str| !part.is_empty() && part.chars().all(|c| c.is_ascii_lowercase() || c == '_');
+    let mut parts = permission.splitn(2, ':');
+    match (parts.next(), parts.next()) {
+        (Some(resource), Some(action))
+            if valid_part(resource) && (action == "*" || valid_part(action)) =>
+        {
+            Ok(())
+        }
+        _ => Err(BadRequest(format!(
+            "Rossz jogosultság formátum: {}. Helyesen pl.: user:read",
+            permission
+        ))),
+    }
+}
+
+/// Does the granted permission, maybe with wildcard, cover the permission
+pub fn permission_matches(granted: &str, permission: &str) -> bool {
+    if granted == "*" || granted == permission {
+        return true;
+    }
+    match granted.strip_suffix(":*") {
+        Some(resource) => permission.split(':').next().is_some_and(|r| r == resource),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_permission() {
+        assert_eq!(validate_permission("user:read").is_ok(), true); // should be ok
+        assert_eq!(validate_permission("user:*").is_ok(), true); // should be ok
+        assert_eq!(validate_permission("*").is_ok(), true); // should be ok
+        assert_eq!(validate_permission("user").is_err(), true); // should be err
+        assert_eq!(validate_permission("user:").is_err(), true); // should be err
+        assert_eq!(validate_permission("User:Read").is_err(), true); // should be err
+    }
+
+    #[test]
+    fn test_grants() {
+        let role = Role::new(
+            "editor".into(),
+            "Editor".into(),
+            vec!["user:read".into(), "customer:*".into()],
+            "admin".into(),
+        )
+        .unwrap();
+        assert_eq!(role.grants("user:read"), true);
+        assert_eq!(role.grants("user:write"), false);
+        assert_eq!(role.grants("customer:write"), true);
+        assert_eq!(role.grants("customers:write"), false);
+        let root = Role::new("root".into(), "Root".into(), vec!["*".into()], "".into()).unwrap();
+        assert_eq!(root.grants("user:admin"), true);
+    }
+
+    #[test]
+    fn test_new_role() {
+        assert_eq!(
+            Role::new("x".into(), "X".into(), vec![], "".into()).is_err(),
+            true
+        ); // should be err
+        assert_eq!(
+            Role::new("viewer".into(), "".into(), vec![], "".into()).is_err(),
+            true
+        ); // should be err
+        assert_eq!(
+            Role::new(
+                "viewer".into(),
+                "Viewer".into(),
+                vec!["bad".into()],
+                "".into()
+            )
+            .is_err(),
+            true
+        ); // should be err
+        let role = Role::new(
+            "Viewer".into(),
+            "Viewer".into(),
+            vec!["user:read".into(), "user:read".into()],
+            "".into(),
+        )
+        .unwrap();
+        assert_eq!(role.get_id(), "viewer");
+        assert_eq!(role.get_permissions().len(), 1);
+    }
+}
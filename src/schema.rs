@@ -0,0 +1,147 @@
+// Copyright (C) 2020 Peter Mezei
+//
+// This file is part of Gardenzilla.
+//
+// Gardenzilla is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 2 of the License, or
+// (at your option) any later version.
+//
+// Gardenzilla is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
+
+use crate::role::ROLE_USER;
+use serde_json::{json, Map, Value};
+
+// Schema versions of the stored users
+// 1: files written before the version was recorded,
+//    customers are a plain list of customer IDs
+// 2: customers are memberships with a role
+
+/// Schema version of the users written by this build.
+/// Increase it with a new migration whenever a stored
+/// user would not load anymore.
+pub const CURRENT_VERSION: u32 = 2;
+
+type Migration = fn(&mut Map<String, Value>) -> Result<(), String>;
+
+// MIGRATIONS[n] upgrades version n + 1 to n + 2
+const MIGRATIONS: &[Migration] = &[v1_to_v2];
+
+fn v1_to_v2(user: &mut Map<String, Value>) -> Result<(), String> {
+    if let Some(customers) = user.get_mut("customers").and_then(|c| c.as_array_mut()) {
+        for customer in customers.iter_mut() {
+            if let Some(customer_id) = customer.as_str().map(|c| c.to_string()) {
+                *customer = json!({
+                    "customer_id": customer_id,
+                    "role": ROLE_USER,
+                    "date_joined": null,
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+/// # Upgrade
+/// Bring a stored user of any known schema version to the current one.
+/// Returns the version the user was stored with.
+pub fn upgrade(user: &mut Value) -> Result<u32, String> {
+    let record = user
+        .as_object_mut()
+        .ok_or_else(|| "User record must be a map".to_string())?;
+    let version = match record.get("schema_version") {
+        None => 1,
+        Some(version) => version
+            .as_u64()
+            .ok_or_else(|| "schema_version must be a number".to_string())?
+            as u32,
+    };
+    if version == 0 || version > CURRENT_VERSION {
+        return Err(format!(
+            "Unknown user schema version {}, the newest known is {}",
+            version, CURRENT_VERSION
+        ));
+    }
+    for migration in &MIGRATIONS[(version - 1) as usize..] {
+        migration(record)?;
+    }
+    record.insert("schema_version".to_string(), CURRENT_VERSION.into());
+    Ok(version)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::user::{User, UserStatus};
+
+    // A fixture file for every schema version, as it was written
+    const V1: &str = include_str!("../fixtures/users/v1.yml");
+    const V2: &str = include_str!("../fixtures/users/v2.yml");
+
+    #[test]
+    fn test_chain() {
+        assert_eq!(MIGRATIONS.len() as u32, CURRENT_VERSION - 1);
+    }
+
+    #[test]
+    fn test_v1() {
+        let user: User = serde_yaml::from_str(V1).unwrap();
+        assert_eq!(user.get_upgraded_from(), Some(1));
+        assert_eq!(user.get_user_id(), "mezeipetister3");
+        assert_eq!(user.get_status(), UserStatus::Active);
+        assert_eq!(user.get_version(), 1);
+        assert_eq!(
+            user.get_customer_ids(),
+            vec!["gardenova".to_string(), "kertbolt".to_string()]
+        );
+        assert_eq!(user.get_membership("kertbolt").unwrap().role, ROLE_USER);
+        assert_eq!(user.get_default_customer(), Some("gardenova"));
+        assert_eq!(user.validate().is_ok(), true); // should be ok
+    }
+
+    #[test]
+    fn test_v2() {
+        let user: User = serde_yaml::from_str(V2).unwrap();
+        assert_eq!(user.get_upgraded_from(), None);
+        assert_eq!(user.get_version(), 2);
+        assert_eq!(user.get_membership("gardenova").unwrap().role, "admin");
+        assert_eq!(user.get_history().len(), 2);
+        assert_eq!(user.get_roles(), &vec!["user".to_string()]);
+        assert_eq!(user.validate().is_ok(), true); // should be ok
+    }
+
+    #[test]
+    fn test_rewrite() {
+        // Written back in the current schema, then loads as it is
+        let user: User = serde_yaml::from_str(V1).unwrap();
+        let rewritten = serde_yaml::to_string(&user).unwrap();
+        assert_eq!(
+            rewritten.contains(&format!("schema_version: {}", CURRENT_VERSION)),
+            true
+        );
+        let user: User = serde_yaml::from_str(&rewritten).unwrap();
+        assert_eq!(user.get_upgraded_from(), None);
+        assert_eq!(user.get_customers().len(), 2);
+        // The same for JSON, used by the database storages
+        let json = serde_json::to_string(&user).unwrap();
+        let user: User = serde_json::from_str(&json).unwrap();
+        assert_eq!(user.get_upgraded_from(), None);
+    }
+
+    #[test]
+    fn test_unknown_version() {
+        let newer = V2.replace(
+            "schema_version: 2",
+            &format!("schema_version: {}", CURRENT_VERSION + 1),
+        );
+        assert_eq!(serde_yaml::from_str::<User>(&newer).is_err(), true); // should be err
+        let mut not_a_map = json!(["demo"]);
+        assert_eq!(upgrade(&mut not_a_map).is_err(), true); // should be err
+    }
+}
@@ -0,0 +1,547 @@
+// Copyright (C) 2020 Peter Mezei
+//
+// This file is part of Gardenzilla.
+//
+// Gardenzilla is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 2 of the License, or
+// (at your option) any later version.
+//
+// Gardenzilla is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
+
+use crate::user::User;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
+
+// Score of a query term matching an indexed term
+const SCORE_EXACT: f64 = 3.0;
+const SCORE_PREFIX: f64 = 2.0;
+const SCORE_FUZZY: f64 = 1.0;
+
+// User id => (best weighted score, matched (field, term) pairs)
+type Matches = HashMap<String, (f64, Vec<(Field, String)>)>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Field {
+    Id,
+    Name,
+    Email,
+    Phone,
+}
+
+impl Field {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Field::Id => "id",
+            Field::Name => "name",
+            Field::Email => "email",
+            Field::Phone => "phone",
+        }
+    }
+    // Name and ID hits are more relevant than
+    // hits in email or phone
+    fn weight(&self) -> f64 {
+        match self {
+            Field::Id => 1.5,
+            Field::Name => 2.0,
+            Field::Email => 1.0,
+            Field::Phone => 1.0,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Token {
+    // Normalized term
+    term: String,
+    // Character offsets in the original value
+    start: usize,
+    end: usize,
+}
+
+#[derive(Debug)]
+struct IndexedField {
+    field: Field,
+    value: String,
+    tokens: Vec<Token>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Highlight {
+    pub field: Field,
+    pub value: String,
+    // Character offsets (start, end) in value, end is exclusive
+    pub spans: Vec<(usize, usize)>,
+}
+
+#[derive(Debug)]
+pub struct SearchHit {
+    pub user_id: String,
+    pub score: f64,
+    pub highlights: Vec<Highlight>,
+}
+
+/// # Search index
+/// In-memory inverted index over user id, name, email and phone.
+/// Terms are lowercase and accent free, so "Mezei Péter" can be found
+/// by "mezei peter" as well.
+#[derive(Default)]
+pub struct SearchIndex {
+    // Normalized term => (user id, field) pairs containing it
+    terms: BTreeMap<String, BTreeSet<(String, Field)>>,
+    // Bigram => indexed terms containing it, so fuzzy matching
+    // only compares the terms sharing enough bigrams with the query
+    bigrams: HashMap<String, BTreeSet<String>>,
+    // User id => its indexed fields
+    docs: HashMap<String, Vec<IndexedField>>,
+}
+
+impl SearchIndex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Index user, or reindex it when it is already indexed
+    pub fn insert(&mut self, user: &User) {
+        let user_id = user.get_user_id().to_string();
+        self.remove(&user_id);
+        let fields = vec![
+            index_field(Field::Id, user.get_user_id()),
+            index_field(Field::Name, user.get_user_name()),
+            index_field(Field::Email, user.get_user_email()),
+            index_field(Field::Phone, user.get_user_phone()),
+        ];
+        for field in &fields {
+            for token in &field.tokens {
+                if !self.terms.contains_key(&token.term) {
+                    for bigram in bigrams(&token.term) {
+                        self.bigrams
+                            .entry(bigram)
+                            .or_default()
+                            .insert(token.term.clone());
+                    }
+                }
+                self.terms
+                    .entry(token.term.clone())
+                    .or_default()
+                    .insert((user_id.clone(), field.field));
+            }
+        }
+        self.docs.insert(user_id, fields);
+    }
+    /// Remove user from the index
+    pub fn remove(&mut self, user_id: &str) {
+        if let Some(fields) = self.docs.remove(user_id) {
+            for field in fields {
+                for token in field.tokens {
+                    if let Some(postings) = self.terms.get_mut(&token.term) {
+                        postings.remove(&(user_id.to_string(), field.field));
+                        if postings.is_empty() {
+                            self.terms.remove(&token.term);
+                            self.remove_bigrams(&token.term);
+                        }
+                    }
+                }
+            }
+        }
+    }
+    fn remove_bigrams(&mut self, term: &str) {
+        for bigram in bigrams(term) {
+            if let Some(terms) = self.bigrams.get_mut(&bigram) {
+                terms.remove(term);
+                if terms.is_empty() {
+                    self.bigrams.remove(&bigram);
+                }
+            }
+        }
+    }
+    /// # Search
+    /// Every query term must match (exact, prefix or fuzzy) at least
+    /// one indexed term of the user. Hits are ordered by score desc.
+    pub fn search(&self, query: &str, limit: usize) -> Vec<SearchHit> {
+        let query_terms: Vec<String> = tokenize(query).into_iter().map(|t| t.term).collect();
+        if query_terms.is_empty() {
+            return Vec::new();
+        }
+        let mut candidates: Option<Matches> = None;
+        for query_term in &query_terms {
+            let matches = self.match_term(query_term);
+            candidates = Some(match candidates {
+                None => matches,
+                Some(previous) => previous
+                    .into_iter()
+                    .filter_map(|(user_id, (score, mut matched))| {
+                        let (term_score, term_matched) = matches.get(&user_id)?;
+                        matched.extend(term_matched.iter().cloned());
+                        Some((user_id, (score + term_score, matched)))
+                    })
+                    .collect(),
+            });
+        }
+        let mut hits = candidates
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(user_id, (score, matched))| SearchHit {
+                highlights: self.highlights(&user_id, &matched),
+                user_id,
+                score,
+            })
+            .collect::<Vec<SearchHit>>();
+        hits.sort_by(|a, b| {
+            b.score
+                .partial_cmp(&a.score)
+                .unwrap_or(Ordering::Equal)
+                .then_with(|| a.user_id.cmp(&b.user_id))
+        });
+        hits.truncate(limit);
+        hits
+    }
+    fn match_term(&self, query_term: &str) -> Matches {
+        let max_edits = max_edits(query_term);
+        // Terms having query_term as prefix
+        let mut terms = self
+            .terms
+            .range(query_term.to_string()..)
+            .take_while(|(term, _)| term.starts_with(query_term))
+            .map(|(term, postings)| {
+                let score = if term == query_term {
+                    SCORE_EXACT
+                } else {
+                    SCORE_PREFIX
+                };
+                (term, score, postings)
+            })
+            .collect::<Vec<(&String, f64, &BTreeSet<(String, Field)>)>>();
+        // and the fuzzy matching ones
+        for term in self.fuzzy_candidates(query_term, max_edits) {
+            if !term.starts_with(query_term) && fuzzy_match(query_term, term, max_edits) {
+                if let Some(postings) = self.terms.get(term) {
+                    terms.push((term, SCORE_FUZZY, postings));
+                }
+            }
+        }
+        let mut result: Matches = HashMap::new();
+        for (term, score, postings) in terms {
+            for (user_id, field) in postings {
+                let entry = result
+                    .entry(user_id.clone())
+                    .or_insert_with(|| (0.0, Vec::new()));
+                entry.0 = entry.0.max(score * field.weight());
+                entry.1.push((*field, term.clone()));
+            }
+        }
+        result
+    }
+    // Terms sharing enough bigrams with query_term to be within
+    // max_edits of it. One edit breaks at most two bigrams of the
+    // query, so a match keeps all but 2 * max_edits of them.
+    fn fuzzy_candidates(&self, query_term: &str, max_edits: usize) -> Vec<&String> {
+        if max_edits == 0 {
+            return Vec::new();
+        }
+        let query_bigrams = bigrams(query_term);
+        let required = query_bigrams.len().saturating_sub(2 * max_edits).max(1);
+        let mut shared: HashMap<&String, usize> = HashMap::new();
+        for bigram in &query_bigrams {
+            for term in self.bigrams.get(bigram).into_iter().flatten() {
+                *shared.entry(term).or_insert(0) += 1;
+            }
+        }
+        shared
+            .into_iter()
+            .filter(|(_, count)| *count >= required)
+            .map(|(term, _)| term)
+            .collect()
+    }
+    fn highlights(&self, user_id: &str, matched: &[(Field, String)]) -> Vec<Highlight> {
+        let fields = match self.docs.get(user_id) {
+            Some(fields) => fields,
+            None => return Vec::new(),
+        };
+        fields
+            .iter()
+            .filter_map(|field| {
+                let mut spans = field
+                    .tokens
+                    .iter()
+                    .filter(|token| {
+                        matched
+                            .iter()
+                            .any(|(f, term)| *f == field.field && *term == token.term)
+                    })
+                    .map(|token| (token.start, token.end))
+                    .collect::<Vec<(usize, usize)>>();
+                if spans.is_empty() {
+                    return None;
+                }
+                spans.sort();
+                spans.dedup();
+                Some(Highlight {
+                    field: field.field,
+                    value: field.value.clone(),
+                    spans,
+                })
+            })
+            .collect()
+    }
+}
+
+fn index_field(field: Field, value: &str) -> IndexedField {
+    let mut tokens = tokenize(value);
+    // Phone numbers are typed in many formats,
+    // so we index the digits joined as well
+    if field == Field::Phone && tokens.len() > 1 {
+        let digits: String = value.chars().filter(|c| c.is_ascii_digit()).collect();
+        let start = value.chars().position(|c| c.is_ascii_digit());
+        let end = value
+            .chars()
+            .enumerate()
+            .filter(|(_, c)| c.is_ascii_digit())
+            .last()
+            .map(|(pos, _)| pos + 1);
+        if let (false, Some(start), Some(end)) = (digits.is_empty(), start, end) {
+            tokens.push(Token {
+                term: digits,
+                start,
+                end,
+            });
+        }
+    }
+    IndexedField {
+        field,
+        value: value.to_string(),
+        tokens,
+    }
+}
+
+/// # Normalize
+/// Lowercase and remove accents, e.g. "Péter" => "peter"
+pub fn normalize(value: &str) -> String {
+    value.chars().flat_map(fold).collect()
+}
+
+// Fold a single character into its lowercase, accent free form.
+// Combining marks fold into nothing.
+fn fold(c: char) -> impl Iterator<Item = char> {
+    std::iter::once(c)
+        .nfd()
+        .filter(|c| !is_combining_mark(*c))
+        .flat_map(char::to_lowercase)
+}
+
+// Split value into normalized alphanumeric terms
+// remembering their character offsets in value
+fn tokenize(value: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let mut term = String::new();
+    let mut start = 0;
+    for (pos, c) in value.chars().enumerate() {
+        let folded: String = fold(c).collect();
+        if folded.is_empty() {
+            // Standalone combining mark, skip it
+            continue;
+        }
+        if folded.chars().all(char::is_alphanumeric) {
+            if term.is_empty() {
+                start = pos;
+            }
+            term.push_str(&folded);
+        } else if !term.is_empty() {
+            tokens.push(Token {
+                term: std::mem::take(&mut term),
+                start,
+                end: pos,
+            });
+        }
+    }
+    if !term.is_empty() {
+        tokens.push(Token {
+            term,
+            start,
+            end: value.chars().count(),
+        });
+    }
+    tokens
+}
+
+// Distinct character pairs of term
+fn bigrams(term: &str) -> BTreeSet<String> {
+    let chars: Vec<char> = term.chars().collect();
+    chars.windows(2).map(|pair| pair.iter().collect()).collect()
+}
+
+// Allowed typos depending on the query term length
+fn max_edits(query_term: &str) -> usize {
+    match query_term.chars().count() {
+        0..=3 => 0,
+        4..=7 => 1,
+        _ => 2,
+    }
+}
+
+// Query term is a partially typed word, so we compare it
+// to the same length prefix of the term and to the whole term.
+fn fuzzy_match(query_term: &str, term: &str, max_edits: usize) -> bool {
+    if max_edits == 0 {
+        return false;
+    }
+    let prefix: String = term.chars().take(query_term.chars().count()).collect();
+    levenshtein(query_term, &prefix) <= max_edits || levenshtein(query_term, term) <= max_edits
+}
+
+fn levenshtein(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let cost = if ca == *cb { 0 } else { 1 };
+            let value = (previous[j] + cost)
+                .min(previous[j + 1] + 1)
+                .min(current[j] + 1);
+            current.push(value);
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn index() -> SearchIndex {
+        let mut index = SearchIndex::new();
+        index.insert(
+            &User::new(
+                "mezeipetister".into(),
+                "Mezei Péter".into(),
+                "mezeipetister@gmail.com".into(),
+                "+36 30 123 4567".into(),
+                "admin".into(),
+                None,
+            )
+            .unwrap(),
+        );
+        index.insert(
+            &User::new(
+                "annakovacs".into(),
+                "Kovács Anna".into(),
+                "anna@gardenova.hu".into(),
+                "+36 20 999 8888".into(),
+                "admin".into(),
+                None,
+            )
+            .unwrap(),
+        );
+        index
+    }
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize("Mezei Péter"), "mezei peter");
+        assert_eq!(normalize("Őrült Űrhajó"), "orult urhajo");
+    }
+
+    #[test]
+    fn test_accent_insensitive() {
+        let index = index();
+        let hits = index.search("mezei peter", 10);
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].user_id, "mezeipetister");
+        let hits = index.search("KOVÁCS", 10);
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].user_id, "annakovacs");
+    }
+
+    #[test]
+    fn test_prefix_and_fuzzy() {
+        let index = index();
+        // Type-ahead
+        assert_eq!(index.search("pet", 10)[0].user_id, "mezeipetister");
+        // Typo
+        assert_eq!(index.search("kovbcs", 10)[0].user_id, "annakovacs");
+        // Joined phone digits
+        assert_eq!(index.search("3630123", 10)[0].user_id, "mezeipetister");
+        // All terms must match
+        assert_eq!(index.search("anna peter", 10).len(), 0);
+        assert_eq!(index.search("", 10).len(), 0);
+    }
+
+    #[test]
+    fn test_fuzzy_candidates() {
+        let index = index();
+        // Only the terms sharing bigrams with the query are compared
+        let candidates = index.fuzzy_candidates("kovbcs", 1);
+        assert_eq!(candidates.iter().any(|t| *t == "kovacs"), true);
+        assert_eq!(candidates.iter().any(|t| *t == "mezei"), false);
+        assert_eq!(index.fuzzy_candidates("kov", 0).len(), 0);
+        // Removed terms are gone from the bigrams as well
+        let mut index = index;
+        index.remove("annakovacs");
+        assert_eq!(index.fuzzy_candidates("kovbcs", 1).len(), 0);
+    }
+
+    #[test]
+    fn test_ranking() {
+        let mut index = index();
+        index.insert(
+            &User::new(
+                "petersen".into(),
+                "Petersen Ákos".into(),
+                "akos@petersen.dk".into(),
+                "".into(),
+                "admin".into(),
+                None,
+            )
+            .unwrap(),
+        );
+        // Exact name hit beats prefix hit
+        let hits = index.search("peter", 10);
+        assert_eq!(hits.len(), 2);
+        assert_eq!(hits[0].user_id, "mezeipetister");
+        assert_eq!(hits[1].user_id, "petersen");
+        // Limit
+        assert_eq!(index.search("36", 10).len(), 2);
+        assert_eq!(index.search("36", 1).len(), 1);
+    }
+
+    #[test]
+    fn test_highlights() {
+        let index = index();
+        let hits = index.search("peter", 10);
+        let name = hits[0]
+            .highlights
+            .iter()
+            .find(|h| h.field == Field::Name)
+            .unwrap();
+        assert_eq!(name.value, "Mezei Péter");
+        assert_eq!(name.spans, vec![(6, 11)]);
+    }
+
+    #[test]
+    fn test_reindex_and_remove() {
+        let mut index = index();
+        let mut user = User::new(
+            "annakovacs".into(),
+            "Kovács Anna".into(),
+            "anna@gardenova.hu".into(),
+            "".into(),
+            "admin".into(),
+            None,
+        )
+        .unwrap();
+        user.set_user_name("Szabó Anna".into()).unwrap();
+        index.insert(&user);
+        assert_eq!(index.search("kovacs", 10).len(), 0);
+        assert_eq!(index.search("szabo", 10)[0].user_id, "annakovacs");
+        index.remove("annakovacs");
+        assert_eq!(index.search("anna", 10).len(), 0);
+    }
+}
@@ -0,0 +1,158 @@
+// Copyright (C) 2020 Peter Mezei
+//
+// This file is part of Gardenzilla.
+//
+// Gardenzilla is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 2 of the License, or
+// (at your option) any later version.
+//
+// Gardenzilla is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
+
+use crate::api_key::ApiKey;
+use crate::prelude::ServiceError::*;
+use crate::prelude::*;
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use storaget::*;
+
+/// # Service account
+/// Principal of another microservice calling us.
+/// It is not a user: it cannot log in, and it acts
+/// only through its API keys.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ServiceAccount {
+    id: String,
+    name: String,
+    date_created: DateTime<Utc>,
+    created_by: String,
+    api_keys: Vec<ApiKey>,
+}
+
+impl Default for ServiceAccount {
+    fn default() -> Self {
+        ServiceAccount {
+            id: String::default(),
+            name: String::default(),
+            date_created: Utc::now(),
+            created_by: String::default(),
+            api_keys: Vec::new(),
+        }
+    }
+}
+
+impl TryFrom for ServiceAccount {
+    type TryFrom = ServiceAccount;
+}
+
+impl VecPackMember for ServiceAccount {
+    fn get_id(&self) -> &str {
+        &self.id
+    }
+}
+
+impl ServiceAccount {
+    pub fn new(id: String, name: String, created_by: String) -> ServiceResult<Self> {
+        let id = id.to_lowercase();
+        if id.len() < 2
+            || id.len() > 30
+            || !id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        {
+            return Err(BadRequest(
+                "A szolgáltatás azonosítója 2-30 karakter, angol kisbetű, szám, _ és - lehet"
+                    .into(),
+            ));
+        }
+        if name.is_empty() {
+            return Err(BadRequest("A szolgáltatás neve nem lehet üres".into()));
+        }
+        Ok(ServiceAccount {
+            id,
+            name,
+            date_created: Utc::now(),
+            created_by,
+            api_keys: Vec::new(),
+        })
+    }
+    pub fn get_id(&self) -> &str {
+        &self.id
+    }
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+    pub fn get_date_created(&self) -> DateTime<Utc> {
+        self.date_created
+    }
+    pub fn get_created_by(&self) -> &str {
+        &self.created_by
+    }
+    pub fn get_api_keys(&self) -> &Vec<ApiKey> {
+        &self.api_keys
+    }
+    pub fn get_api_key(&self, key_id: &str) -> Option<&ApiKey> {
+        self.api_keys.iter().find(|k| k.get_id() == key_id)
+    }
+    pub fn add_api_key(&mut self, api_key: ApiKey) {
+        self.api_keys.push(api_key);
+    }
+    /// Record key use, returns true if the stored last use changed
+    pub fn touch_api_key(&mut self, key_id: &str, date: DateTime<Utc>) -> bool {
+        match self.api_keys.iter_mut().find(|k| k.get_id() == key_id) {
+            Some(api_key) => api_key.touch(date),
+            None => false,
+        }
+    }
+    pub fn revoke_api_key(&mut self, key_id: &str) -> ServiceResult<()> {
+        match self.api_keys.iter_mut().find(|k| k.get_id() == key_id) {
+            Some(api_key) => api_key.revoke(),
+            None => Err(NotFound("A kulcs nem található".into())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_new_service_account() {
+        assert_eq!(
+            ServiceAccount::new("x".into(), "X".into(), "".into()).is_err(),
+            true
+        ); // should be err
+        assert_eq!(
+            ServiceAccount::new("invoice".into(), "".into(), "".into()).is_err(),
+            true
+        ); // should be err
+        let account =
+            ServiceAccount::new("Invoice-Service".into(), "Invoice".into(), "admin".into())
+                .unwrap();
+        assert_eq!(account.get_id(), "invoice-service");
+    }
+
+    #[test]
+    fn test_api_keys() {
+        let mut account =
+            ServiceAccount::new("invoice".into(), "Invoice".into(), "admin".into()).unwrap();
+        let (api_key, _) = ApiKey::generate(
+            "main".into(),
+            vec!["user:read".into()],
+            None,
+            "admin".into(),
+        )
+        .unwrap();
+        let key_id = api_key.get_id().to_string();
+        account.add_api_key(api_key);
+        assert_eq!(account.revoke_api_key(&key_id).is_ok(), true); // should be ok
+        assert_eq!(account.get_api_key(&key_id).unwrap().is_active(), false);
+        assert_eq!(account.revoke_api_key("unknown").is_err(), true); // should be err
+    }
+}
@@ -0,0 +1,324 @@
+// Copyright (C) 2020 Peter Mezei
+//
+// This file is part of Gardenzilla.
+//
+// Gardenzilla is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 2 of the License, or
+// (at your option) any later version.
+//
+// Gardenzilla is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
+
+use crate::prelude::ServiceError::*;
+use crate::prelude::*;
+use crate::repository::{UserQuery, UserRepository};
+use crate::user::{User, UserStatus};
+use rusqlite::types::ToSql;
+use rusqlite::{params, Connection, OptionalExtension, Transaction};
+use std::path::Path;
+
+/// Schema migrations, applied in order. The number of applied
+/// ones is kept in PRAGMA user_version. Never change a released
+/// migration, add a new one instead.
+const MIGRATIONS: &[&str] = &[
+    // 1: users with their indexed fields next to the JSON document
+    "CREATE TABLE users (
+        id TEXT PRIMARY KEY NOT NULL,
+        email TEXT NOT NULL,
+        status TEXT NOT NULL,
+        version INTEGER NOT NULL,
+        data TEXT NOT NULL
+    );
+    CREATE INDEX users_email ON users (email);
+    CREATE TABLE user_customers (
+        user_id TEXT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+        customer_id TEXT NOT NULL,
+        PRIMARY KEY (user_id, customer_id)
+    );
+    CREATE INDEX user_customers_customer ON user_customers (customer_id);",
+    // 2: status is queried by the pending registrations
+    "CREATE INDEX users_status ON users (status);",
+];
+
+/// # SQLite repository
+/// Users stored in a single SQLite database. Every user is kept as
+/// a JSON document; email, status and customers are stored in columns
+/// as well for the indexed queries.
+pub struct SqliteRepository {
+    connection: Connection,
+}
+
+impl SqliteRepository {
+    /// Open or create the database file and migrate its schema
+    pub fn open(path: impl AsRef<Path>) -> ServiceResult<Self> {
+        if let Some(dir) = path.as_ref().parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        Self::init(Connection::open(path)?)
+    }
+    /// Database in memory, for tests
+    pub fn in_memory() -> ServiceResult<Self> {
+        Self::init(Connection::open_in_memory()?)
+    }
+    fn init(mut connection: Connection) -> ServiceResult<Self> {
+        connection.execute_batch(
+            "PRAGMA foreign_keys = ON;
+            PRAGMA journal_mode = WAL;",
+        )?;
+        migrate(&mut connection)?;
+        Ok(Self { connection })
+    }
+    /// Number of applied migrations
+    pub fn schema_version(&self) -> ServiceResult<usize> {
+        schema_version(&self.connection)
+    }
+}
+
+fn schema_version(connection: &Connection) -> ServiceResult<usize> {
+    let version: i64 = connection.query_row("PRAGMA user_version", params![], |row| row.get(0))?;
+    Ok(version as usize)
+}
+
+/// Apply the missing migrations, each one in its own transaction
+fn migrate(connection: &mut Connection) -> ServiceResult<()> {
+    let applied = schema_version(connection)?;
+    if applied > MIGRATIONS.len() {
+        return Err(FailedPrecondition(format!(
+            "Database schema version {} is newer than supported {}",
+            applied,
+            MIGRATIONS.len()
+        )));
+    }
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(applied) {
+        let tx = connection.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.execute_batch(&format!("PRAGMA user_version = {}", index + 1))?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+fn status_name(status: UserStatus) -> String {
+    format!("{:?}", status)
+}
+
+fn to_json(user: &User) -> ServiceResult<String> {
+    serde_json::to_string(user)
+        .map_err(|e| InternalError(format!("Error while serializing user: {}", e)))
+}
+
+fn from_json(data: &str) -> ServiceResult<User> {
+    serde_json::from_str(data)
+        .map_err(|e| InternalError(format!("Error while deserializing user: {}", e)))
+}
+
+// Store the indexed fields and the document of the user.
+// An upsert keeps the rowid, so the storage order as well.
+fn write_user(tx: &Transaction, user: &User) -> ServiceResult<()> {
+    tx.execute(
+        "INSERT INTO users (id, email, status, version, data)
+        VALUES (?1, ?2, ?3, ?4, ?5)
+        ON CONFLICT (id) DO UPDATE SET
+            email = excluded.email,
+            status = excluded.status,
+            version = excluded.version,
+            data = excluded.data",
+        params![
+            user.get_user_id(),
+            user.get_user_email().to_lowercase(),
+            status_name(user.get_status()),
+            user.get_version() as i64,
+            to_json(user)?,
+        ],
+    )?;
+    tx.execute(
+        "DELETE FROM user_customers WHERE user_id = ?1",
+        params![user.get_user_id()],
+    )?;
+    for customer_id in user.get_customer_ids() {
+        tx.execute(
+            "INSERT INTO user_customers (user_id, customer_id) VALUES (?1, ?2)",
+            params![user.get_user_id(), customer_id],
+        )?;
+    }
+    Ok(())
+}
+
+impl UserRepository for SqliteRepository {
+    fn find(&self, id: &str) -> ServiceResult<Option<User>> {
+        let data: Option<String> = self
+            .connection
+            .query_row("SELECT data FROM users WHERE id = ?1", params![id], |row| {
+                row.get(0)
+            })
+            .optional()?;
+        data.map(|d| from_json(&d)).transpose()
+    }
+    fn insert(&mut self, user: User) -> ServiceResult<()> {
+        let tx = self.connection.transaction()?;
+        let exists: Option<i64> = tx
+            .query_row(
+                "SELECT 1 FROM users WHERE id = ?1",
+                params![user.get_user_id()],
+                |row| row.get(0),
+            )
+            .optional()?;
+        if exists.is_some() {
+            return Err(AlreadyExists("User exist!".into()));
+        }
+        write_user(&tx, &user)?;
+        tx.commit()?;
+        Ok(())
+    }
+    fn update(&mut self, user: User, version: u64) -> ServiceResult<()> {
+        let tx = self.connection.transaction()?;
+        let stored: Option<i64> = tx
+            .query_row(
+                "SELECT version FROM users WHERE id = ?1",
+                params![user.get_user_id()],
+                |row| row.get(0),
+            )
+            .optional()?;
+        match stored {
+            None => return Err(NotFound("User not found".into())),
+            Some(stored) if stored != version as i64 => {
+                return Err(Aborted("User was updated meanwhile".into()))
+            }
+            Some(_) => (),
+        }
+        write_user(&tx, &user)?;
+        tx.commit()?;
+        Ok(())
+    }
+    fn delete(&mut self, id: &str) -> ServiceResult<()> {
+        match self
+            .connection
+            .execute("DELETE FROM users WHERE id = ?1", params![id])?
+        {
+            0 => Err(NotFound("User not found".into())),
+            _ => Ok(()),
+        }
+    }
+    fn list(&self) -> ServiceResult<Vec<User>> {
+        self.query(&UserQuery {
+            include_deleted: true,
+            ..UserQuery::default()
+        })
+    }
+    fn query(&self, query: &UserQuery) -> ServiceResult<Vec<User>> {
+        let mut conditions: Vec<&str> = Vec::new();
+        let mut values: Vec<Box<dyn ToSql>> = Vec::new();
+        match query.status {
+            Some(status) => {
+                conditions.push("status = ?");
+                values.push(Box::new(status_name(status)));
+            }
+            None if !query.include_deleted => {
+                conditions.push("status <> ?");
+                values.push(Box::new(status_name(UserStatus::Deleted)));
+            }
+            None => (),
+        }
+        if let Some(email) = &query.email {
+            conditions.push("email = ?");
+            values.push(Box::new(email.to_lowercase()));
+        }
+        if let Some(customer_id) = &query.customer_id {
+            conditions.push("id IN (SELECT user_id FROM user_customers WHERE customer_id = ?)");
+            values.push(Box::new(customer_id.clone()));
+        }
+        let mut sql = "SELECT data FROM users".to_string();
+        if !conditions.is_empty() {
+            sql.push_str(" WHERE ");
+            sql.push_str(&conditions.join(" AND "));
+        }
+        // Insertion order, like the other repositories
+        sql.push_str(" ORDER BY rowid");
+        let mut statement = self.connection.prepare(&sql)?;
+        let rows = statement.query_map(values.iter().map(|v| v.as_ref()), |row| {
+            row.get::<_, String>(0)
+        })?;
+        rows.map(|data| from_json(&data?)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::check_repository;
+
+    #[test]
+    fn test_sqlite_repository() {
+        check_repository(&mut SqliteRepository::in_memory().unwrap(), true);
+    }
+
+    #[test]
+    fn test_migrations() {
+        let path =
+            std::env::temp_dir().join(format!("user_microservice_users_{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let repo = SqliteRepository::open(&path).unwrap();
+        assert_eq!(repo.schema_version().unwrap(), MIGRATIONS.len());
+        drop(repo);
+        // Reopen does not apply them again
+        let mut repo = SqliteRepository::open(&path).unwrap();
+        assert_eq!(repo.schema_version().unwrap(), MIGRATIONS.len());
+        let user = User::new(
+            "demo".into(),
+            "user".into(),
+            "demo@user.com".into(),
+            "".into(),
+            "admin".into(),
+            None,
+        )
+        .unwrap();
+        repo.insert(user).unwrap();
+        assert_eq!(repo.find("demo").unwrap().is_some(), true);
+        // A newer schema is refused
+        repo.connection
+            .execute_batch(&format!("PRAGMA user_version = {}", MIGRATIONS.len() + 1))
+            .unwrap();
+        drop(repo);
+        assert_eq!(SqliteRepository::open(&path).is_err(), true); // should be err
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_failed_update_is_rolled_back() {
+        let mut repo = SqliteRepository::in_memory().unwrap();
+        let mut user = User::new(
+            "demo".into(),
+            "user".into(),
+            "demo@user.com".into(),
+            "".into(),
+            "admin".into(),
+            Some("c1".into()),
+        )
+        .unwrap();
+        repo.insert(user.clone()).unwrap();
+        // Break the customer insert, after the user row is written
+        repo.connection
+            .execute_batch(
+                "CREATE TRIGGER no_c2 BEFORE INSERT ON user_customers
+                WHEN NEW.customer_id = 'c2'
+                BEGIN SELECT RAISE(ABORT, 'no c2'); END;",
+            )
+            .unwrap();
+        user.set_user_name("Changed".into()).unwrap();
+        user.add_customer("c2".into(), "member".into()).unwrap();
+        assert_eq!(repo.update(user, 1).is_err(), true); // should be err
+        let stored = repo.find("demo").unwrap().unwrap();
+        assert_eq!(stored.get_user_name(), "user");
+        let by_customer = UserQuery {
+            customer_id: Some("c1".into()),
+            ..UserQuery::default()
+        };
+        assert_eq!(repo.query(&by_customer).unwrap().len(), 1);
+    }
+}
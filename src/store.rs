@@ -0,0 +1,514 @@
+// Copyright (C) 2020 Peter Mezei
+//
+// This file is part of Gardenzilla.
+//
+// Gardenzilla is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 2 of the License, or
+// (at your option) any later version.
+//
+// Gardenzilla is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
+
+use crate::auth::hash_secret;
+use crate::prelude::*;
+use crate::repository::{UserQuery, UserRepository};
+use crate::user::User;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex, RwLock};
+
+// Users are split into shards, and IDs in storage order into
+// chunks, so a new snapshot copies only the shards and chunk
+// it changes, and shares the rest with the previous one.
+const SHARDS: usize = 64;
+const ORDER_CHUNK: usize = 256;
+
+type Shard = HashMap<String, Arc<User>>;
+
+/// # Snapshot
+/// Every user at one point in time. A snapshot never changes,
+/// a write creates a new one sharing the unchanged users.
+#[derive(Clone)]
+pub struct Snapshot {
+    shards: Vec<Arc<Shard>>,
+    // IDs in storage order
+    order: Vec<Arc<Vec<String>>>,
+    // Open token hash => user ID, copied only when tokens change
+    tokens: Arc<HashMap<String, String>>,
+}
+
+impl Snapshot {
+    fn new(users: Vec<User>) -> Self {
+        let mut shards = vec![Shard::new(); SHARDS];
+        let mut order = Vec::new();
+        let mut tokens = HashMap::new();
+        for user in users {
+            let id = user.get_user_id().to_string();
+            for hash in user.token_hashes() {
+                tokens.insert(hash.to_string(), id.clone());
+            }
+            push_ordered(&mut order, id.clone());
+            shards[shard_of(&id)].insert(id, Arc::new(user));
+        }
+        Self {
+            shards: shards.into_iter().map(Arc::new).collect(),
+            order,
+            tokens: Arc::new(tokens),
+        }
+    }
+    /// User by ID, deleted ones included
+    pub fn get(&self, id: &str) -> Option<&User> {
+        self.shards[shard_of(id)].get(id).map(|u| u.as_ref())
+    }
+    /// Every user, deleted ones included, in storage order
+    pub fn list(&self) -> impl Iterator<Item = &User> {
+        self.order
+            .iter()
+            .flat_map(|chunk| chunk.iter())
+            .filter_map(move |id| self.get(id))
+    }
+    /// Users matching the query, in storage order
+    pub fn query(&self, query: &UserQuery) -> Vec<&User> {
+        self.list().filter(|u| query.matches(u)).collect()
+    }
+    /// User having the open invitation, verification
+    /// or password reset token, even if expired
+    pub fn find_by_token(&self, token: &str) -> Option<&User> {
+        self.tokens
+            .get(&hash_secret(token))
+            .and_then(|id| self.get(id))
+    }
+    // Copy of this snapshot with the users added or replaced
+    fn with(&self, users: Vec<User>) -> Self {
+        let mut next = self.clone();
+        for user in users {
+            let id = user.get_user_id().to_string();
+            let previous: Vec<String> = next
+                .get(&id)
+                .map(|u| u.token_hashes().into_iter().map(String::from).collect())
+                .unwrap_or_default();
+            if previous != user.token_hashes() {
+                let tokens = Arc::make_mut(&mut next.tokens);
+                for hash in &previous {
+                    tokens.remove(hash);
+                }
+                for hash in user.token_hashes() {
+                    tokens.insert(hash.to_string(), id.clone());
+                }
+            }
+            let shard = Arc::make_mut(&mut next.shards[shard_of(&id)]);
+            if !shard.contains_key(&id) {
+                push_ordered(&mut next.order, id.clone());
+            }
+            shard.insert(id, Arc::new(user));
+        }
+        next
+    }
+}
+
+fn shard_of(id: &str) -> usize {
+    let mut hasher = DefaultHasher::new();
+    id.hash(&mut hasher);
+    (hasher.finish() % SHARDS as u64) as usize
+}
+
+// Append to the last chunk, or start a new one when it is full
+fn push_ordered(order: &mut Vec<Arc<Vec<String>>>, id: String) {
+    match order.last_mut() {
+        Some(chunk) if chunk.len() < ORDER_CHUNK => Arc::make_mut(chunk).push(id),
+        _ => order.push(Arc::new(vec![id])),
+    }
+}
+
+/// # User store
+/// Users shared by the RPCs, on top of a repository.
+/// Readers take the current snapshot and never wait for a write,
+/// not even for its disk I/O. Writes are made one at a time, and the
+/// repository is only called from the blocking thread pool, so a slow
+/// disk or database does not block the async executor.
+/// Other replicas may write a shared repository, so writers read
+/// the users they change from the repository, and the snapshot
+/// catches up with every user read or written that way.
+pub struct UserStore {
+    // Locked only to take or replace the current snapshot
+    snapshot: RwLock<Arc<Snapshot>>,
+    repository: Arc<Mutex<Box<dyn UserRepository>>>,
+    // Held by the writer through its awaited storage calls
+    writer: tokio::sync::Mutex<()>,
+}
+
+impl UserStore {
+    /// Load every user of the repository
+    pub fn load(repository: Box<dyn UserRepository>) -> ServiceResult<Self> {
+        Ok(Self {
+            snapshot: RwLock::new(Arc::new(Snapshot::new(repository.list()?))),
+            repository: Arc::new(Mutex::new(repository)),
+            writer: tokio::sync::Mutex::new(()),
+        })
+    }
+    /// Current users
+    pub fn snapshot(&self) -> Arc<Snapshot> {
+        self.snapshot.read().unwrap().clone()
+    }
+    // Run a repository call on the blocking thread pool
+    async fn with_repository<T, F>(&self, call: F) -> ServiceResult<T>
+    where
+        F: FnOnce(&mut dyn UserRepository) -> ServiceResult<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        blocking(&self.repository, move |repository| {
+            call(repository.as_mut())
+        })
+        .await
+    }
+    /// Start writing, after the previous writer has finished.
+    /// Keep the writer till the change is published, so
+    /// changes are published in the storage order.
+    pub async fn writer(&self) -> Writer<'_> {
+        Writer {
+            store: self,
+            _guard: self.writer.lock().await,
+        }
+    }
+}
+
+pub struct Writer<'a> {
+    store: &'a UserStore,
+    _guard: tokio::sync::MutexGuard<'a, ()>,
+}
+
+impl<'a> Writer<'a> {
+    /// Current users, including the ones written by this writer
+    pub fn snapshot(&self) -> Arc<Snapshot> {
+        self.store.snapshot()
+    }
+    /// # Fresh
+    /// User by ID as stored now, deleted ones included.
+    /// Read it here before changing it, the snapshot may be
+    /// behind the changes of other replicas.
+    pub async fn fresh(&self, id: &str) -> ServiceResult<Option<User>> {
+        let key = id.to_string();
+        let user = self
+            .store
+            .with_repository(move |repository| repository.find(&key))
+            .await?;
+        if let Some(user) = &user {
+            self.publish(vec![user.clone()])?;
+        }
+        Ok(user)
+    }
+    /// Users matching the query as stored now, in storage order
+    pub async fn query(&self, query: UserQuery) -> ServiceResult<Vec<User>> {
+        let users = self
+            .store
+            .with_repository(move |repository| repository.query(&query))
+            .await?;
+        self.publish(users.clone())?;
+        Ok(users)
+    }
+    /// Store a new user, fails if the ID is taken
+    pub async fn insert(&self, user: User) -> ServiceResult<()> {
+        self.write(user, None).await
+    }
+    /// Replace a stored user with the same ID.
+    /// The change is stored only over the version in the snapshot,
+    /// Aborted if another replica has changed the user since.
+    /// The snapshot gets the stored user then.
+    pub async fn update(&self, user: User) -> ServiceResult<()> {
+        let version = self
+            .snapshot()
+            .get(user.get_user_id())
+            .map(|u| u.get_version())
+            .ok_or_else(|| ServiceError::not_found("User not found"))?;
+        let id = user.get_user_id().to_string();
+        match self.write(user, Some(version)).await {
+            Err(ServiceError::Aborted(msg)) => {
+                self.fresh(&id).await?;
+                Err(ServiceError::Aborted(msg))
+            }
+            result => result,
+        }
+    }
+    // Readers see the change once it is stored.
+    // Inserted without a version, updated over the given one.
+    async fn write(&self, user: User, version: Option<u64>) -> ServiceResult<()> {
+        let stored = user.clone();
+        self.store
+            .with_repository(move |repository| match version {
+                None => repository.insert(stored),
+                Some(version) => repository.update(stored, version),
+            })
+            .await?;
+        self.publish(vec![user])
+    }
+    // Replace the users in the snapshot at once
+    fn publish(&self, users: Vec<User>) -> ServiceResult<()> {
+        if users.is_empty() {
+            return Ok(());
+        }
+        let next = Arc::new(self.store.snapshot().with(users));
+        *self
+            .store
+            .snapshot
+            .write()
+            .map_err(|_| ServiceError::internal_error("Lock error"))? = next;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::{MemoryRepository, VecPackRepository};
+    use rand::Rng;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::time::{Duration, Instant};
+
+    fn user(id: &str) -> User {
+        User::new(
+            id.into(),
+            "user".into(),
+            format!("{}@user.com", id),
+            "".into(),
+            "admin".into(),
+            None,
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_store() {
+        let mut repository = MemoryRepository::default();
+        repository.insert(user("demo")).unwrap();
+        let store = UserStore::load(Box::new(repository)).unwrap();
+        let before = store.snapshot();
+
+        let writer = store.writer().await;
+        writer.insert(user("other")).await.unwrap();
+        assert_eq!(writer.insert(user("other")).await.is_err(), true); // should be err
+        let mut demo = writer.snapshot().get("demo").unwrap().clone();
+        demo.set_user_name("Demo User".into()).unwrap();
+        writer.update(demo).await.unwrap();
+        assert_eq!(writer.update(user("unknown")).await.is_err(), true); // should be err
+        drop(writer);
+
+        // Snapshots taken earlier never change
+        assert_eq!(before.list().count(), 1);
+        assert_eq!(before.get("demo").unwrap().get_user_name(), "user");
+        let after = store.snapshot();
+        let ids = after
+            .list()
+            .map(|u| u.get_user_id().to_string())
+            .collect::<Vec<String>>();
+        assert_eq!(ids, vec!["demo", "other"]);
+        assert_eq!(after.get("demo").unwrap().get_user_name(), "Demo User");
+        assert_eq!(after.get("unknown").is_none(), true);
+        // Stored in the repository as well
+        let stored = store.repository.lock().unwrap().list().unwrap();
+        assert_eq!(stored.len(), 2);
+        assert_eq!(stored[0].get_user_name(), "Demo User");
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn test_writers_take_turns() {
+        let store = Arc::new(UserStore::load(Box::new(MemoryRepository::default())).unwrap());
+        store.writer().await.insert(user("demo")).await.unwrap();
+        // Every writer reads and updates the version, none is lost
+        let tasks = (0..20)
+            .map(|_| {
+                let store = store.clone();
+                tokio::spawn(async move {
+                    let writer = store.writer().await;
+                    let current = writer.snapshot().get("demo").unwrap().clone();
+                    let updated = crate::user::commit(&current, current.clone(), "admin");
+                    writer.update(updated).await.unwrap();
+                })
+            })
+            .collect::<Vec<_>>();
+        for task in tasks {
+            task.await.unwrap();
+        }
+        assert_eq!(store.snapshot().get("demo").unwrap().get_version(), 21);
+    }
+
+    #[tokio::test]
+    async fn test_changed_by_other_replica() {
+        let store = UserStore::load(Box::new(MemoryRepository::default())).unwrap();
+        store.writer().await.insert(user("demo")).await.unwrap();
+        // Another replica updates the shared repository
+        {
+            let mut repository = store.repository.lock().unwrap();
+            let current = repository.find("demo").unwrap().unwrap();
+            let mut renamed = current.clone();
+            renamed.set_user_name("Other Replica".into()).unwrap();
+            repository
+                .update(crate::user::commit(&current, renamed, "admin"), 1)
+                .unwrap();
+        }
+        let writer = store.writer().await;
+        // A change made on the snapshot is not stored over it
+        let stale = writer.snapshot().get("demo").unwrap().clone();
+        let updated = crate::user::commit(&stale, stale.clone(), "admin");
+        match writer.update(updated).await {
+            Err(ServiceError::Aborted(_)) => (),
+            _ => panic!("should be aborted"),
+        }
+        assert_eq!(
+            writer.snapshot().get("demo").unwrap().get_user_name(),
+            "Other Replica"
+        );
+        // A change made on the fresh user is stored
+        let current = writer.fresh("demo").await.unwrap().unwrap();
+        let updated = crate::user::commit(&current, current.clone(), "admin");
+        writer.update(updated).await.unwrap();
+        drop(writer);
+        assert_eq!(store.snapshot().get("demo").unwrap().get_version(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_find_by_token() {
+        let mut invited = user("invited");
+        let token = invited.invite("admin").unwrap();
+        let store = UserStore::load(Box::new(MemoryRepository::default())).unwrap();
+        let writer = store.writer().await;
+        writer.insert(invited.clone()).await.unwrap();
+        let found = writer.snapshot().find_by_token(&token).cloned();
+        assert_eq!(found.unwrap().get_user_id(), "invited");
+        assert_eq!(writer.snapshot().find_by_token("unknown").is_none(), true);
+        // A renewed token replaces the old one
+        let mut renewed = invited.clone();
+        let new_token = renewed.renew_invitation("admin").unwrap();
+        writer
+            .update(crate::user::commit(&invited, renewed, "admin"))
+            .await
+            .unwrap();
+        assert_eq!(writer.snapshot().find_by_token(&token).is_none(), true);
+        assert_eq!(writer.snapshot().find_by_token(&new_token).is_some(), true);
+    }
+
+    #[test]
+    fn test_snapshot_sharing() {
+        let ids = (0..600)
+            .map(|n| format!("user_{}", n))
+            .collect::<Vec<String>>();
+        let before = Snapshot::new(ids.iter().map(|id| user(id)).collect());
+        let mut renamed = user("user_0");
+        renamed.set_user_name("Renamed".into()).unwrap();
+        let after = before.with(vec![renamed, user("user_new")]);
+        // Storage order kept over the chunks, new users at the end
+        let listed = after
+            .list()
+            .map(|u| u.get_user_id().to_string())
+            .collect::<Vec<String>>();
+        assert_eq!(listed[..600], ids[..]);
+        assert_eq!(listed[600], "user_new");
+        assert_eq!(after.get("user_0").unwrap().get_user_name(), "Renamed");
+        assert_eq!(before.get("user_0").unwrap().get_user_name(), "user");
+        assert_eq!(before.get("user_new").is_none(), true);
+        // Only the changed shards and the last chunk are copied
+        let copied = (0..SHARDS)
+            .filter(|n| !Arc::ptr_eq(&before.shards[*n], &after.shards[*n]))
+            .count();
+        assert_eq!(copied <= 2, true);
+        assert_eq!(Arc::ptr_eq(&before.order[0], &after.order[0]), true);
+    }
+
+    // Reads done by the reader threads while a writer updates
+    // users on disk the whole time
+    fn concurrent_reads<R, W>(read: R, mut write: W, duration: Duration) -> usize
+    where
+        R: Fn(&str) -> bool + Send + Sync + 'static,
+        W: FnMut(&str),
+    {
+        let read = Arc::new(read);
+        let stop = Arc::new(AtomicBool::new(false));
+        let reads = Arc::new(AtomicUsize::new(0));
+        let readers = (0..4)
+            .map(|_| {
+                let (read, stop, reads) = (read.clone(), stop.clone(), reads.clone());
+                std::thread::spawn(move || {
+                    while !stop.load(Ordering::Relaxed) {
+                        assert_eq!(read("user_1"), true);
+                        reads.fetch_add(1, Ordering::Relaxed);
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        let start = Instant::now();
+        let mut n = 0;
+        while start.elapsed() < duration {
+            write(&format!("user_{}", n % 100));
+            n += 1;
+        }
+        stop.store(true, Ordering::Relaxed);
+        for reader in readers {
+            reader.join().unwrap();
+        }
+        reads.load(Ordering::Relaxed)
+    }
+
+    fn vecpack_repository() -> (std::path::PathBuf, VecPackRepository) {
+        let dir = std::env::temp_dir().join(format!(
+            "user_microservice_bench_{}",
+            hex::encode(rand::thread_rng().gen::<[u8; 4]>())
+        ));
+        let mut repository =
+            VecPackRepository::new(storaget::VecPack::try_load_or_init(dir.clone()).unwrap());
+        for n in 0..100 {
+            repository.insert(user(&format!("user_{}", n))).unwrap();
+        }
+        (dir, repository)
+    }
+
+    // Read throughput under concurrent updates, a single lock around
+    // the repository compared to the store. Readers of the store never
+    // wait for the disk, so they must get far more reads done.
+    // It runs for seconds, so it is ignored by default, run it by
+    // cargo test --release test_concurrent_reads -- --ignored
+    #[test]
+    #[ignore]
+    fn test_concurrent_reads() {
+        let duration = Duration::from_secs(2);
+
+        let (dir, repository) = vecpack_repository();
+        let locked = Arc::new(Mutex::new(repository));
+        let reader = locked.clone();
+        let reads = concurrent_reads(
+            move |id| reader.lock().unwrap().find(id).unwrap().is_some(),
+            |id| {
+                let mut repository = locked.lock().unwrap();
+                let user = repository.find(id).unwrap().unwrap();
+                let version = user.get_version();
+                repository.update(user, version).unwrap();
+            },
+            duration,
+        );
+        let locked_reads = reads;
+        std::fs::remove_dir_all(dir).unwrap();
+
+        let (dir, repository) = vecpack_repository();
+        let store = Arc::new(UserStore::load(Box::new(repository)).unwrap());
+        let reader = store.clone();
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        let reads = concurrent_reads(
+            move |id| reader.snapshot().get(id).is_some(),
+            |id| {
+                runtime.block_on(async {
+                    let writer = store.writer().await;
+                    let user = writer.snapshot().get(id).unwrap().clone();
+                    writer.update(user).await.unwrap();
+                })
+            },
+            duration,
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+        assert_eq!(reads > locked_reads * 2, true);
+    }
+}
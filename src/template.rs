@@ -0,0 +1,196 @@
+// Copyright (C) 2020 Peter Mezei
+//
+// This file is part of Gardenzilla.
+//
+// Gardenzilla is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 2 of the License, or
+// (at your option) any later version.
+//
+// Gardenzilla is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
+
+use crate::notifier::Message;
+use crate::prelude::*;
+
+// Locale of the messages sent to users, e.g. hu or en
+pub const LOCALE_ENV: &str = "USER_LOCALE";
+pub const DEFAULT_LOCALE: &str = "hu";
+const LOCALES: &[&str] = &["hu", "en"];
+
+/// Messages sent to users
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Template {
+    // {name}, {userid}, {token}, {expires_at}
+    Invitation,
+    // {name}, {userid}, {token}, {expires_at}
+    PasswordReset,
+    // {name}, {code}, {expires_at}
+    LoginCode,
+    // {name}, {code}, {link}, {expires_at}
+    LoginLink,
+    // {name}
+    RegistrationApproved,
+    // {name}, {reason}
+    RegistrationRejected,
+    // {name}, {userid}, {token}, {expires_at}
+    EmailVerification,
+}
+
+// (subject, body) of the template in the locale
+fn text(template: Template, locale: &str) -> (&'static str, &'static str) {
+    match (locale, template) {
+        ("en", Template::Invitation) => (
+            "Invitation to Gardenova",
+            "Dear {name}!\n\nYou are invited to Gardenova.\nYour user ID: {userid}\nYour invitation code: {token}\nThe invitation expires at: {expires_at}",
+        ),
+        ("en", Template::PasswordReset) => (
+            "Gardenova password reset",
+            "Dear {name}!\n\nA password reset was requested for your account. If it was not you, ignore this email, your password stays the same.\nYour user ID: {userid}\nYour password reset code: {token}\nThe code can be used once, and expires at: {expires_at}",
+        ),
+        ("en", Template::LoginCode) => (
+            "Gardenova login code",
+            "Dear {name}!\n\nYour login code: {code}\nThe code can be used once, and expires at: {expires_at}",
+        ),
+        ("en", Template::LoginLink) => (
+            "Gardenova login code",
+            "Dear {name}!\n\nYour login code: {code}\nOr click here to log in: {link}\nThe code can be used once, and expires at: {expires_at}",
+        ),
+        ("en", Template::RegistrationApproved) => (
+            "Registration approved",
+            "Dear {name}!\n\nYour registration is approved, you can log in now.",
+        ),
+        ("en", Template::RegistrationRejected) => (
+            "Registration rejected",
+            "Dear {name}!\n\nYour registration is rejected.\n{reason}",
+        ),
+        ("en", Template::EmailVerification) => (
+            "Confirm your email address",
+            "Dear {name}!\n\nPlease confirm your email address to finish your registration.\nYour user ID: {userid}\nYour verification code: {token}\nThe code expires at: {expires_at}",
+        ),
+        (_, Template::Invitation) => (
+            "Meghívó a Gardenova rendszerbe",
+            "Kedves {name}!\n\nMeghívtak a Gardenova rendszerbe.\nA felhasználói neved: {userid}\nA meghívó kódod: {token}\nA meghívó lejár: {expires_at}",
+        ),
+        (_, Template::PasswordReset) => (
+            "Gardenova jelszó visszaállítás",
+            "Kedves {name}!\n\nA fiókodhoz jelszó visszaállítást kértek. Ha nem te voltál, hagyd figyelmen kívül ezt a levelet, a jelszavad nem változik.\nA felhasználói neved: {userid}\nA jelszó visszaállító kódod: {token}\nA kód egyszer használható, és lejár: {expires_at}",
+        ),
+        (_, Template::LoginCode) => (
+            "Gardenova belépési kód",
+            "Kedves {name}!\n\nA belépési kódod: {code}\nA kód egyszer használható, és lejár: {expires_at}",
+        ),
+        (_, Template::LoginLink) => (
+            "Gardenova belépési kód",
+            "Kedves {name}!\n\nA belépési kódod: {code}\nVagy kattints ide a belépéshez: {link}\nA kód egyszer használható, és lejár: {expires_at}",
+        ),
+        (_, Template::RegistrationApproved) => (
+            "Regisztráció jóváhagyva",
+            "Kedves {name}!\n\nA regisztrációdat jóváhagytuk, már be tudsz lépni.",
+        ),
+        (_, Template::RegistrationRejected) => (
+            "Regisztráció elutasítva",
+            "Kedves {name}!\n\nA regisztrációdat elutasítottuk.\n{reason}",
+        ),
+        (_, Template::EmailVerification) => (
+            "Erősítsd meg az email címed",
+            "Kedves {name}!\n\nA regisztrációd befejezéséhez erősítsd meg az email címed.\nA felhasználói neved: {userid}\nAz ellenőrző kódod: {token}\nA kód lejár: {expires_at}",
+        ),
+    }
+}
+
+/// # Templates
+/// Render the messages sent to users in the configured locale
+#[derive(Clone, Debug)]
+pub struct Templates {
+    locale: String,
+}
+
+impl Default for Templates {
+    fn default() -> Self {
+        Self {
+            locale: DEFAULT_LOCALE.to_string(),
+        }
+    }
+}
+
+impl Templates {
+    pub fn new(locale: &str) -> ServiceResult<Self> {
+        let locale = locale.trim().to_lowercase();
+        if !LOCALES.contains(&locale.as_str()) {
+            return Err(ServiceError::bad_request(&format!(
+                "Unsupported locale: {}, use one of: {}",
+                locale,
+                LOCALES.join(", ")
+            )));
+        }
+        Ok(Self { locale })
+    }
+    /// Load the locale from USER_LOCALE, hu by default
+    pub fn from_env() -> ServiceResult<Self> {
+        match std::env::var(LOCALE_ENV) {
+            Ok(locale) if !locale.is_empty() => Self::new(&locale),
+            _ => Ok(Self::default()),
+        }
+    }
+    pub fn get_locale(&self) -> &str {
+        &self.locale
+    }
+    /// Message to the address, {key} placeholders replaced by the values
+    pub fn render(&self, template: Template, to: &str, values: &[(&str, &str)]) -> Message {
+        let (subject, body) = text(template, &self.locale);
+        let fill = |text: &str| {
+            values.iter().fold(text.to_string(), |text, (key, value)| {
+                text.replace(&format!("{{{}}}", key), value)
+            })
+        };
+        Message {
+            to: to.to_string(),
+            subject: fill(subject),
+            body: fill(body),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let templates = Templates::default();
+        let message = templates.render(
+            Template::PasswordReset,
+            "demo@user.com",
+            &[("name", "Demo"), ("userid", "demo"), ("token", "secret")],
+        );
+        assert_eq!(message.to, "demo@user.com");
+        assert_eq!(message.subject, "Gardenova jelszó visszaállítás");
+        assert_eq!(message.body.starts_with("Kedves Demo!\n\n"), true);
+        assert_eq!(
+            message
+                .body
+                .contains("A jelszó visszaállító kódod: secret\n"),
+            true
+        );
+    }
+
+    #[test]
+    fn test_locale() {
+        let templates = Templates::new(" EN").unwrap();
+        assert_eq!(templates.get_locale(), "en");
+        let message = templates.render(
+            Template::RegistrationRejected,
+            "demo@user.com",
+            &[("name", "Demo"), ("reason", "Unknown company")],
+        );
+        assert_eq!(message.subject, "Registration rejected");
+        assert_eq!(message.body.ends_with("Unknown company"), true);
+        assert_eq!(Templates::new("de").is_err(), true); // should be err
+    }
+}
@@ -15,16 +15,63 @@
 // You should have received a copy of the GNU General Public License
 // along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
 
+use crate::api_key::ApiKey;
+use crate::auth::{generate_token, hash_secret};
 use crate::password::*;
 use crate::prelude::ServiceError::*;
 use crate::prelude::*;
+use crate::protos::user::*;
+use crate::role::ROLE_USER;
+use crate::schema;
 use chrono::prelude::*;
-use protos::user::*;
-use serde::{Deserialize, Serialize};
+use serde::de::Error;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use storaget::*;
 
+// UserObj fields that can be updated
+pub const MUTABLE_FIELDS: &[&str] = &["name", "email", "phone"];
+// UserObj fields that can never be updated
+const IMMUTABLE_FIELDS: &[&str] = &[
+    "id",
+    "created_by",
+    "created_at",
+    "customers",
+    "memberships",
+    "default_customer",
+    "roles",
+    "version",
+    "updated_at",
+    "updated_by",
+    "status",
+];
+// Invitation lifetime in hours
+const INVITATION_TTL_HOURS: i64 = 72;
+// Email verification lifetime in hours
+const VERIFICATION_TTL_HOURS: i64 = 24;
+// Password reset token lifetime in hours
+const PASSWORD_RESET_TTL_HOURS: i64 = 1;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+pub enum UserStatus {
+    #[default]
+    Active,
+    Deleted,
+    // Invited, waiting for the invitation to be accepted
+    Invited,
+    // Self-registered, waiting for admin approval
+    PendingApproval,
+    // Self-registered, waiting for the email address to be verified
+    PendingVerification,
+}
+
+// Serialized through the impls below, see schema
 #[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(remote = "Self")]
 pub struct User {
+    schema_version: u32,
+    // Older schema version the user was loaded from, if any
+    #[serde(skip)]
+    upgraded_from: Option<u32>,
     id: String,
     name: String,
     email: String,
@@ -32,26 +79,138 @@ pub struct User {
     password_hash: String,
     date_created: DateTime<Utc>,
     created_by: String,
-    customers: Vec<String>,
+    #[serde(default)]
+    customers: Vec<Membership>,
+    // Customer ID used when the client does not specify one
+    #[serde(default)]
+    default_customer: Option<String>,
+    // Users are never removed, only marked as deleted
+    #[serde(default)]
+    status: UserStatus,
+    // Increased by every update
+    #[serde(default = "default_version")]
+    version: u64,
+    #[serde(default)]
+    date_updated: Option<DateTime<Utc>>,
+    #[serde(default)]
+    updated_by: Option<String>,
+    // Every change made on the user, oldest first
+    #[serde(default)]
+    history: Vec<HistoryEntry>,
+    // Role IDs
+    #[serde(default)]
+    roles: Vec<String>,
+    // Personal access tokens, revoked ones included
+    #[serde(default)]
+    tokens: Vec<ApiKey>,
+    // Open invitation of an invited user
+    #[serde(default)]
+    invitation: Option<Invitation>,
+    // Open email verification of a self-registered user
+    #[serde(default)]
+    verification: Option<EmailToken>,
+    // Open password reset requested by the user
+    #[serde(default)]
+    password_reset: Option<EmailToken>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Invitation {
+    // Only the hash of the token sent to the user
+    token_hash: String,
+    pub date_created: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub invited_by: String,
+}
+
+/// Single-use token sent to the email address of the user
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct EmailToken {
+    // Only the hash of the token sent to the user
+    token_hash: String,
+    pub date_created: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl EmailToken {
+    // New token with the one to send
+    fn new(ttl_hours: i64) -> (Self, String) {
+        let token = generate_token();
+        let now = Utc::now();
+        let email_token = EmailToken {
+            token_hash: hash_secret(&token),
+            date_created: now,
+            expires_at: now + chrono::Duration::hours(ttl_hours),
+        };
+        (email_token, token)
+    }
+    fn matches(&self, token: &str) -> bool {
+        self.token_hash == hash_secret(token)
+    }
+    fn is_expired(&self) -> bool {
+        self.expires_at <= Utc::now()
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct FieldChange {
+    pub field: String,
+    pub old_value: String,
+    pub new_value: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct HistoryEntry {
+    // User version created by this change
+    pub version: u64,
+    pub date: DateTime<Utc>,
+    pub actor: String,
+    pub changes: Vec<FieldChange>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Membership {
+    pub customer_id: String,
+    // Role ID the user has at this customer
+    pub role: String,
+    // None for memberships created before join dates were recorded
+    pub date_joined: Option<DateTime<Utc>>,
+}
+
+fn default_version() -> u64 {
+    1
+}
+
+// Stored users of older schema versions are upgraded before deserializing
+impl<'de> Deserialize<'de> for User {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut record = serde_json::Value::deserialize(deserializer)?;
+        let version = schema::upgrade(&mut record).map_err(D::Error::custom)?;
+        let mut user = User::deserialize(record).map_err(D::Error::custom)?;
+        if version < schema::CURRENT_VERSION {
+            user.upgraded_from = Some(version);
+        }
+        Ok(user)
+    }
+}
+
+impl Serialize for User {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        User::serialize(self, serializer)
+    }
 }
 
 impl From<User> for UserObj {
     fn from(user: User) -> Self {
-        UserObj {
-            id: user.id,
-            name: user.name,
-            email: user.email,
-            phone: user.phone,
-            customers: user.customers,
-            created_by: user.created_by,
-            created_at: user.date_created.to_string(),
-        }
+        (&user).into()
     }
 }
 
 impl Default for User {
     fn default() -> Self {
         User {
+            schema_version: schema::CURRENT_VERSION,
+            upgraded_from: None,
             id: String::default(),
             name: String::default(),
             email: String::default(),
@@ -60,6 +219,17 @@ impl Default for User {
             date_created: Utc::now(),
             created_by: String::default(),
             customers: Vec::new(),
+            default_customer: None,
+            status: UserStatus::default(),
+            version: default_version(),
+            date_updated: None,
+            updated_by: None,
+            history: Vec::new(),
+            roles: Vec::new(),
+            tokens: Vec::new(),
+            invitation: None,
+            verification: None,
+            password_reset: None,
         }
     }
 }
@@ -74,6 +244,14 @@ impl TryFrom for User {
 //     }
 // }
 
+// Email addresses end up in SMTP commands and mail headers,
+// where line breaks and angle brackets could inject new ones
+fn has_forbidden_email_chars(email: &str) -> bool {
+    email
+        .chars()
+        .any(|c| c.is_control() || c == '<' || c == '>')
+}
+
 impl User {
     pub fn new(
         mut id: String,
@@ -81,6 +259,7 @@ impl User {
         mut email: String,
         phone: String,
         created_by: String,
+        customer_id: Option<String>,
     ) -> ServiceResult<Self> {
         // Conver ID into lowercase anyway.
         id = id.to_lowercase();
@@ -137,6 +316,11 @@ impl User {
                 "Nem megfelelő email cím. Legalább @ jelet és pontot kell tartalmaznia".to_string(),
             ));
         }
+        if has_forbidden_email_chars(&email) {
+            return Err(BadRequest(
+                "Az email cím nem tartalmazhat vezérlő karaktert, < vagy > jelet".to_string(),
+            ));
+        }
         // Validate Name length
         if name.len() > name_max_chars || name.len() < name_min_chars {
             return Err(BadRequest(format!(
@@ -145,7 +329,9 @@ impl User {
             )));
         }
 
-        Ok(User {
+        let mut user = User {
+            schema_version: schema::CURRENT_VERSION,
+            upgraded_from: None,
             id,
             name,
             email,
@@ -153,9 +339,31 @@ impl User {
             password_hash: "".into(),
             date_created: Utc::now(),
             created_by,
-            // TODO: Attach default customer at initialisation process
             customers: Vec::new(),
-        })
+            default_customer: None,
+            status: UserStatus::Active,
+            version: default_version(),
+            date_updated: None,
+            updated_by: None,
+            history: Vec::new(),
+            roles: Vec::new(),
+            tokens: Vec::new(),
+            invitation: None,
+            verification: None,
+            password_reset: None,
+        };
+        // Attach default customer at initialisation process
+        if let Some(customer_id) = customer_id {
+            user.add_customer(customer_id, ROLE_USER.to_string())?;
+        }
+        // First history entry has every initial value
+        user.history.push(HistoryEntry {
+            version: user.version,
+            date: user.date_created,
+            actor: user.created_by.clone(),
+            changes: User::default().diff(&user),
+        });
+        Ok(user)
     }
 }
 
@@ -175,6 +383,15 @@ impl User {
             Ok(())
         }
     }
+    /// Older schema version the user was loaded from,
+    /// None if it is stored in the current one
+    pub fn get_upgraded_from(&self) -> Option<u32> {
+        self.upgraded_from
+    }
+    /// Stored in the current schema from now on
+    pub fn clear_upgraded_from(&mut self) {
+        self.upgraded_from = None;
+    }
     pub fn get_date_created(&self) -> DateTime<Utc> {
         self.date_created
     }
@@ -195,6 +412,11 @@ impl User {
         &self.email
     }
     pub fn set_user_email(&mut self, email: String) -> ServiceResult<()> {
+        if has_forbidden_email_chars(&email) {
+            return Err(BadRequest(
+                "Az email cím nem tartalmazhat vezérlő karaktert, < vagy > jelet".into(),
+            ));
+        }
         if email.contains('@') && email.contains('.') && email.len() > 5 {
             self.email = email;
             Ok(())
@@ -221,9 +443,414 @@ impl User {
     pub fn get_created_by(&self) -> &str {
         &self.created_by
     }
-    pub fn get_customers(&self) -> &Vec<String> {
+    pub fn get_customers(&self) -> &Vec<Membership> {
         &self.customers
     }
+    pub fn get_customer_ids(&self) -> Vec<String> {
+        self.customers
+            .iter()
+            .map(|m| m.customer_id.to_string())
+            .collect()
+    }
+    pub fn get_membership(&self, customer_id: &str) -> Option<&Membership> {
+        self.customers.iter().find(|m| m.customer_id == customer_id)
+    }
+    /// Explicitly set default customer, or the first one
+    pub fn get_default_customer(&self) -> Option<&str> {
+        self.default_customer
+            .as_deref()
+            .or_else(|| self.customers.first().map(|m| m.customer_id.as_str()))
+    }
+    /// Add customer membership; the first one becomes the default
+    pub fn add_customer(&mut self, customer_id: String, role: String) -> ServiceResult<()> {
+        if customer_id.is_empty() {
+            return Err(BadRequest("Az ügyfél azonosító nem lehet üres".into()));
+        }
+        if self.get_membership(&customer_id).is_some() {
+            return Err(AlreadyExists(
+                "A felhasználó már tagja ennek az ügyfélnek".into(),
+            ));
+        }
+        if self.customers.is_empty() {
+            self.default_customer = Some(customer_id.clone());
+        }
+        self.customers.push(Membership {
+            customer_id,
+            role,
+            date_joined: Some(Utc::now()),
+        });
+        Ok(())
+    }
+    /// Remove customer membership. When it was the default one,
+    /// the next membership becomes the default.
+    pub fn remove_customer(&mut self, customer_id: &str) -> ServiceResult<()> {
+        if self.get_membership(customer_id).is_none() {
+            return Err(NotFound(
+                "A felhasználó nem tagja ennek az ügyfélnek".into(),
+            ));
+        }
+        self.customers.retain(|m| m.customer_id != customer_id);
+        if self.default_customer.as_deref() == Some(customer_id) {
+            self.default_customer = self.customers.first().map(|m| m.customer_id.clone());
+        }
+        Ok(())
+    }
+    pub fn set_customer_role(&mut self, customer_id: &str, role: String) -> ServiceResult<()> {
+        match self
+            .customers
+            .iter_mut()
+            .find(|m| m.customer_id == customer_id)
+        {
+            Some(membership) => {
+                membership.role = role;
+                Ok(())
+            }
+            None => Err(NotFound(
+                "A felhasználó nem tagja ennek az ügyfélnek".into(),
+            )),
+        }
+    }
+    pub fn set_default_customer(&mut self, customer_id: &str) -> ServiceResult<()> {
+        if self.get_membership(customer_id).is_none() {
+            return Err(NotFound(
+                "A felhasználó nem tagja ennek az ügyfélnek".into(),
+            ));
+        }
+        self.default_customer = Some(customer_id.to_string());
+        Ok(())
+    }
+    /// # Update fields
+    /// Update the fields listed in paths from a UserObj.
+    /// Empty paths means all the mutable fields.
+    /// Only the listed fields are validated.
+    pub fn update_fields(&mut self, from: &UserObj, paths: &[String]) -> ServiceResult<()> {
+        let paths: Vec<&str> = match paths.is_empty() {
+            true => MUTABLE_FIELDS.to_vec(),
+            false => paths.iter().map(|p| p.as_str()).collect(),
+        };
+        // Check every path before changing anything
+        for path in &paths {
+            if IMMUTABLE_FIELDS.contains(path) {
+                return Err(BadRequest(format!("A(z) {} mező nem módosítható", path)));
+            }
+            if !MUTABLE_FIELDS.contains(path) {
+                return Err(BadRequest(format!("Ismeretlen mező: {}", path)));
+            }
+        }
+        for path in paths {
+            match path {
+                "name" => self.set_user_name(from.name.to_string())?,
+                "email" => self.set_user_email(from.email.to_string())?,
+                "phone" => self.set_user_phone(from.phone.to_string())?,
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+    pub fn get_version(&self) -> u64 {
+        self.version
+    }
+    /// Check the version the client has seen.
+    /// It is required, versions start from 1.
+    pub fn check_version(&self, version: u64) -> ServiceResult<()> {
+        if version == 0 {
+            return Err(FailedPrecondition(
+                "A felhasználó verziója kötelező, kérlek töltsd újra".into(),
+            ));
+        }
+        if version != self.version {
+            return Err(Aborted(format!(
+                "A felhasználót időközben módosították (verzió: {}, jelenlegi: {}), kérlek töltsd újra",
+                version, self.version
+            )));
+        }
+        Ok(())
+    }
+    pub fn get_date_updated(&self) -> Option<DateTime<Utc>> {
+        self.date_updated
+    }
+    pub fn get_updated_by(&self) -> Option<&str> {
+        self.updated_by.as_deref()
+    }
+    pub fn get_history(&self) -> &Vec<HistoryEntry> {
+        &self.history
+    }
+    // Fields we keep history for, with printable values
+    fn tracked_fields(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("name", self.name.clone()),
+            ("email", self.email.clone()),
+            ("phone", self.phone.clone()),
+            (
+                "customers",
+                self.customers
+                    .iter()
+                    .map(|m| format!("{} ({})", m.customer_id, m.role))
+                    .collect::<Vec<String>>()
+                    .join(", "),
+            ),
+            (
+                "default_customer",
+                self.get_default_customer().unwrap_or_default().to_string(),
+            ),
+            ("status", format!("{:?}", self.status)),
+            ("roles", self.roles.join(", ")),
+            (
+                "tokens",
+                self.tokens
+                    .iter()
+                    .filter(|t| t.get_revoked_at().is_none())
+                    .map(|t| format!("{} ({})", t.get_id(), t.get_name()))
+                    .collect::<Vec<String>>()
+                    .join(", "),
+            ),
+        ]
+    }
+    /// # Diff
+    /// Field level changes from self to other.
+    /// Password hash is never included, only the fact it changed.
+    pub fn diff(&self, other: &User) -> Vec<FieldChange> {
+        let mut changes = self
+            .tracked_fields()
+            .into_iter()
+            .zip(other.tracked_fields())
+            .filter(|((_, old), (_, new))| old != new)
+            .map(|((field, old_value), (_, new_value))| FieldChange {
+                field: field.to_string(),
+                old_value,
+                new_value,
+            })
+            .collect::<Vec<FieldChange>>();
+        if self.password_hash != other.password_hash {
+            let masked = |hash: &str| match hash.is_empty() {
+                true => "",
+                false => "***",
+            };
+            changes.push(FieldChange {
+                field: "password".into(),
+                old_value: masked(&self.password_hash).into(),
+                new_value: masked(&other.password_hash).into(),
+            });
+        }
+        changes
+    }
+    pub fn get_roles(&self) -> &Vec<String> {
+        &self.roles
+    }
+    pub fn add_role(&mut self, role_id: &str) -> ServiceResult<()> {
+        if self.roles.iter().any(|r| r == role_id) {
+            return Err(AlreadyExists(
+                "A felhasználó már rendelkezik ezzel a szerepkörrel".into(),
+            ));
+        }
+        self.roles.push(role_id.to_string());
+        Ok(())
+    }
+    pub fn remove_role(&mut self, role_id: &str) -> ServiceResult<()> {
+        if !self.roles.iter().any(|r| r == role_id) {
+            return Err(NotFound(
+                "A felhasználó nem rendelkezik ezzel a szerepkörrel".into(),
+            ));
+        }
+        self.roles.retain(|r| r != role_id);
+        Ok(())
+    }
+    pub fn get_tokens(&self) -> &Vec<ApiKey> {
+        &self.tokens
+    }
+    pub fn get_token(&self, token_id: &str) -> Option<&ApiKey> {
+        self.tokens.iter().find(|t| t.get_id() == token_id)
+    }
+    pub fn add_token(&mut self, token: ApiKey) {
+        self.tokens.push(token);
+    }
+    pub fn revoke_token(&mut self, token_id: &str) -> ServiceResult<()> {
+        match self.tokens.iter_mut().find(|t| t.get_id() == token_id) {
+            Some(token) => token.revoke(),
+            None => Err(NotFound("A token nem található".into())),
+        }
+    }
+    /// Record token use, returns true if the stored last use changed
+    pub fn touch_token(&mut self, token_id: &str, date: DateTime<Utc>) -> bool {
+        match self.tokens.iter_mut().find(|t| t.get_id() == token_id) {
+            Some(token) => token.touch(date),
+            None => false,
+        }
+    }
+    pub fn get_status(&self) -> UserStatus {
+        self.status
+    }
+    pub fn is_deleted(&self) -> bool {
+        self.status == UserStatus::Deleted
+    }
+    /// Only active users can log in and call us
+    pub fn is_active(&self) -> bool {
+        self.status == UserStatus::Active
+    }
+    /// # Invite
+    /// Turn a just created user into an invited one.
+    /// Returns the invitation token to send to the user.
+    pub fn invite(&mut self, invited_by: &str) -> ServiceResult<String> {
+        if !self.is_new() {
+            return Err(FailedPrecondition("Csak új felhasználó hívható meg".into()));
+        }
+        self.status = UserStatus::Invited;
+        self.refresh_initial_history();
+        self.renew_invitation(invited_by)
+    }
+    /// # Register
+    /// Set the password of a just created, self-registered user.
+    /// It waits for its email address to be verified, returns
+    /// the verification token to send to the address.
+    pub fn register(&mut self, password: String) -> ServiceResult<String> {
+        if !self.is_new() {
+            return Err(FailedPrecondition(
+                "Csak új felhasználó regisztrálható".into(),
+            ));
+        }
+        self.set_password(password)?;
+        self.status = UserStatus::PendingVerification;
+        self.refresh_initial_history();
+        self.renew_verification()
+    }
+    pub fn is_pending_verification(&self) -> bool {
+        self.status == UserStatus::PendingVerification
+    }
+    /// New verification token, the previous one becomes invalid
+    pub fn renew_verification(&mut self) -> ServiceResult<String> {
+        if !self.is_pending_verification() {
+            return Err(FailedPrecondition(
+                "A felhasználó nem vár email megerősítésre".into(),
+            ));
+        }
+        let (verification, token) = EmailToken::new(VERIFICATION_TTL_HOURS);
+        self.verification = Some(verification);
+        Ok(token)
+    }
+    pub fn get_verification(&self) -> Option<&EmailToken> {
+        self.verification.as_ref()
+    }
+    /// Does the verification token belong to this user, even if expired
+    pub fn has_verification(&self, token: &str) -> bool {
+        self.is_pending_verification()
+            && self.verification.as_ref().is_some_and(|v| v.matches(token))
+    }
+    /// # Verify email
+    /// Confirm the address by the token sent to it.
+    /// Unless approved, the user waits for admin approval then.
+    pub fn verify_email(&mut self, token: &str, approved: bool) -> ServiceResult<()> {
+        if !self.has_verification(token) {
+            return Err(NotFound("Az ellenőrző kód nem található".into()));
+        }
+        if self.verification.as_ref().is_none_or(|v| v.is_expired()) {
+            return Err(FailedPrecondition("Az ellenőrző kód lejárt".into()));
+        }
+        self.verification = None;
+        self.status = match approved {
+            true => UserStatus::Active,
+            false => UserStatus::PendingApproval,
+        };
+        Ok(())
+    }
+    pub fn is_pending_approval(&self) -> bool {
+        self.status == UserStatus::PendingApproval
+    }
+    pub fn approve(&mut self) -> ServiceResult<()> {
+        if !self.is_pending_approval() {
+            return Err(FailedPrecondition(
+                "A felhasználó nem vár jóváhagyásra".into(),
+            ));
+        }
+        self.status = UserStatus::Active;
+        Ok(())
+    }
+    /// Rejected registrations are deleted, so their ID stays reserved
+    pub fn reject(&mut self) -> ServiceResult<()> {
+        if !self.is_pending_approval() {
+            return Err(FailedPrecondition(
+                "A felhasználó nem vár jóváhagyásra".into(),
+            ));
+        }
+        self.status = UserStatus::Deleted;
+        Ok(())
+    }
+    // Created but never stored or changed
+    fn is_new(&self) -> bool {
+        self.version == default_version() && self.password_hash.is_empty()
+    }
+    // Initial history entry should show the state
+    // set up right after creation
+    fn refresh_initial_history(&mut self) {
+        let changes = User::default().diff(self);
+        if let Some(entry) = self.history.first_mut() {
+            entry.changes = changes;
+        }
+    }
+    /// New invitation token for an invited user,
+    /// the previous one becomes invalid
+    pub fn renew_invitation(&mut self, invited_by: &str) -> ServiceResult<String> {
+        if self.status != UserStatus::Invited {
+            return Err(FailedPrecondition(
+                "A felhasználónak nincs függő meghívója".into(),
+            ));
+        }
+        let token = generate_token();
+        let now = Utc::now();
+        self.invitation = Some(Invitation {
+            token_hash: hash_secret(&token),
+            date_created: now,
+            expires_at: now + chrono::Duration::hours(INVITATION_TTL_HOURS),
+            invited_by: invited_by.to_string(),
+        });
+        Ok(token)
+    }
+    pub fn get_invitation(&self) -> Option<&Invitation> {
+        self.invitation.as_ref()
+    }
+    /// Hashes of the open invitation, verification and
+    /// password reset tokens, to find the user by a token
+    pub fn token_hashes(&self) -> Vec<&str> {
+        let mut hashes = Vec::new();
+        if let Some(invitation) = &self.invitation {
+            hashes.push(invitation.token_hash.as_str());
+        }
+        for token in self.verification.iter().chain(self.password_reset.iter()) {
+            hashes.push(token.token_hash.as_str());
+        }
+        hashes
+    }
+    /// Does the invitation token belong to this user, even if expired
+    pub fn has_invitation(&self, token: &str) -> bool {
+        self.status == UserStatus::Invited
+            && self
+                .invitation
+                .as_ref()
+                .is_some_and(|i| i.token_hash == hash_secret(token))
+    }
+    /// Accept the invitation and set the first password
+    pub fn accept_invitation(&mut self, token: &str, password: String) -> ServiceResult<()> {
+        if !self.has_invitation(token) {
+            return Err(NotFound("A meghívó nem található".into()));
+        }
+        if self
+            .invitation
+            .as_ref()
+            .is_none_or(|i| i.expires_at <= Utc::now())
+        {
+            return Err(FailedPrecondition("A meghívó lejárt".into()));
+        }
+        self.set_password(password)?;
+        self.status = UserStatus::Active;
+        self.invitation = None;
+        Ok(())
+    }
+    pub fn delete(&mut self) -> ServiceResult<()> {
+        if self.is_deleted() {
+            return Err(NotFound("A felhasználó már törölve lett".into()));
+        }
+        self.status = UserStatus::Deleted;
+        Ok(())
+    }
     pub fn get_password_hash(&self) -> &str {
         &self.password_hash
     }
@@ -233,44 +860,118 @@ impl User {
         Ok(())
     }
 
-    // TODO: Maybe should be at a higher level using User trait reference as input?
-    // Maybe this?
-    // => fn reset_password<T: User>(user: &T) -> Result<(), String> {...}
-    pub fn reset_password(&mut self) -> ServiceResult<()> {
-        // TODO!: Implement it using RPC service
-        // let new_password = generate_random_password(None)?;
-        // self.password_hash = hash_password(&new_password)?;
-        // match email::new(
-        //     &self.get_user_email(),
-        //     "Gardenova ÚJ JELSZÓ",
-        //     &format!(
-        //         "A felhasználói neved: {}\nAz új jelszavad: {}",
-        //         self.get_user_id(),
-        //         &new_password
-        //     ),
-        // )
-        // .send()
-        // {
-        //     Ok(_) => (),
-        //     // TODO:
-        //     // Use email pool, in case of email service failure.
-        //     // Instead of using ServiceError in case of ServiceError - directly here -,
-        //     // We should say its Ok(()) now, and in case of ServiceError, the email pool,
-        //     // should manage the trials.
-        //     Err(msg) => {
-        //         return Err(InternalServiceError(format!(
-        //             "Az új jelszó elkészült, de hiba az email elküldése során. A hibaüzenet: {}",
-        //             msg
-        //         )))
-        //     }
-        // }
+    /// # Change password
+    /// Set by the user, knowing the current password
+    pub fn change_password(
+        &mut self,
+        current_password: &str,
+        password: String,
+    ) -> ServiceResult<()> {
+        if !verify_password_from_hash(current_password, &self.password_hash).unwrap_or(false) {
+            return Err(PermissionDenied("A jelenlegi jelszó hibás".into()));
+        }
+        self.set_password(password)
+    }
+
+    /// # Request password reset
+    /// New single-use token to set the password by, the previous
+    /// one becomes invalid. Returns the token to send to the user.
+    pub fn request_password_reset(&mut self) -> ServiceResult<String> {
+        if !self.is_active() {
+            return Err(FailedPrecondition(
+                "Csak aktív felhasználó jelszava állítható vissza".into(),
+            ));
+        }
+        let (password_reset, token) = EmailToken::new(PASSWORD_RESET_TTL_HOURS);
+        self.password_reset = Some(password_reset);
+        Ok(token)
+    }
+    pub fn get_password_reset(&self) -> Option<&EmailToken> {
+        self.password_reset.as_ref()
+    }
+    /// Does the password reset token belong to this user, even if expired
+    pub fn has_password_reset(&self, token: &str) -> bool {
+        self.is_active()
+            && self
+                .password_reset
+                .as_ref()
+                .is_some_and(|r| r.matches(token))
+    }
+    /// # Reset password
+    /// Set the password by the token sent to the user.
+    /// The token can be used once.
+    pub fn reset_password(&mut self, token: &str, password: String) -> ServiceResult<()> {
+        if !self.has_password_reset(token) {
+            return Err(NotFound("A jelszó visszaállító kód nem található".into()));
+        }
+        if self.password_reset.as_ref().is_none_or(|r| r.is_expired()) {
+            return Err(FailedPrecondition(
+                "A jelszó visszaállító kód lejárt".into(),
+            ));
+        }
+        self.set_password(password)?;
+        self.password_reset = None;
+        Ok(())
+    }
+
+    /// # Validate
+    /// Check a stored user, e.g. one loaded from a file.
+    /// Rules changed over time, so only what the stored
+    /// user needs to work is checked, not every new user rule.
+    pub fn validate(&self) -> ServiceResult<()> {
+        if self.id.is_empty() || self.id != self.id.to_lowercase() {
+            return Err(BadRequest(
+                "A felhasználói azonosító nem lehet üres, és csak kisbetűs lehet".into(),
+            ));
+        }
+        if self.name.is_empty() {
+            return Err(BadRequest("A név nem lehet üres".into()));
+        }
+        if !self.email.contains('@') {
+            return Err(BadRequest(format!(
+                "Nem megfelelő email cím: {}",
+                self.email
+            )));
+        }
+        let mut customer_ids = self.get_customer_ids();
+        customer_ids.sort();
+        customer_ids.dedup();
+        if customer_ids.len() != self.customers.len() {
+            return Err(BadRequest("Ismétlődő ügyfél tagság".into()));
+        }
+        if let Some(default_customer) = &self.default_customer {
+            if self.get_membership(default_customer).is_none() {
+                return Err(BadRequest(
+                    "A felhasználó nem tagja az alapértelmezett ügyfélnek".into(),
+                ));
+            }
+        }
         Ok(())
     }
 }
 
-/**
- * StorageObject implementation for UserObject
- */
+/// # Commit
+/// Prepare the updated user for storing, increasing its version
+/// and recording the changes made by actor into its history.
+/// Every user update should go through this.
+pub fn commit(current: &User, mut updated: User, actor: &str) -> User {
+    let changes = current.diff(&updated);
+    let now = Utc::now();
+    updated.version = current.version + 1;
+    // Stored in the current schema
+    updated.upgraded_from = None;
+    updated.date_updated = Some(now);
+    updated.updated_by = Some(actor.to_string());
+    updated.history.push(HistoryEntry {
+        version: updated.version,
+        date: now,
+        actor: actor.to_string(),
+        changes,
+    });
+    updated
+}
+
+// StorageObject implementation for UserObject
 // impl storage::StorageObject for UserV1 {
 //     fn get_id(&self) -> &str {
 //         &self.id
@@ -318,6 +1019,7 @@ mod tests {
             "demo@user.com".into(),
             "".into(),
             "".into(),
+            None,
         )
         .unwrap();
         // At this point ID should be None;
@@ -338,12 +1040,30 @@ mod tests {
             "demo@user.com".into(),
             "".into(),
             "".into(),
+            None,
         )
         .unwrap();
 
         assert_eq!(user.set_user_email("demo@demo.com".into()).is_ok(), true); // should be ok
         assert_eq!(user.set_user_email("wohoo".into()).is_err(), true); // should be err
         assert_eq!(user.set_user_email("demo@company.com".into()).is_ok(), true); // should be ok
+        assert_eq!(
+            user.set_user_email("demo@user.com>\r\nRCPT TO:<x@y.com".into())
+                .is_err(),
+            true
+        ); // should be err
+        assert_eq!(
+            User::new(
+                "demo".into(),
+                "user".into(),
+                "demo@user.com\nBcc: x@y.com".into(),
+                "".into(),
+                "".into(),
+                None,
+            )
+            .is_err(),
+            true
+        ); // should be err
 
         // Check email wether email is correct
         assert_eq!(user.get_user_email(), "demo@company.com");
@@ -357,6 +1077,7 @@ mod tests {
             "demo@user.com".into(),
             "".into(),
             "".into(),
+            None,
         )
         .unwrap();
         assert_eq!(user.get_user_name(), "user");
@@ -374,6 +1095,7 @@ mod tests {
             "demo@user.com".into(),
             "".into(),
             "".into(),
+            None,
         )
         .unwrap();
         let phone_number: &str = "+99 (701) 479 397129";
@@ -391,6 +1113,7 @@ mod tests {
             "demo@user.com".into(),
             "".into(),
             "".into(),
+            None,
         )
         .unwrap();
         let password: &str = "HelloWorld749";
@@ -407,14 +1130,384 @@ mod tests {
             true
         );
     }
-    // #[test]
-    // #[ignore]
-    // fn test_reset_password() {
-    //     let mut user: UserV1 = UserV1::new("demo".into(), "user".into(), "demo@user.com".into());
-    //     user.set_user_email(&env::var("E_TO_TEST_EMAIL").unwrap())
-    //         .unwrap();
-    //     user.set_user_name(&env::var("E_TO_TEST_NAME").unwrap())
-    //         .unwrap();
-    //     assert_eq!(user.reset_password().is_ok(), true);
-    // }
+    #[test]
+    fn test_update_fields() {
+        let mut user: User = User::new(
+            "demo".into(),
+            "Demo User".into(),
+            "demo@user.com".into(),
+            "+36 30 123 4567".into(),
+            "".into(),
+            None,
+        )
+        .unwrap();
+        let mut from: UserObj = (&user).into();
+        from.phone = "+36 20 765 4321".into();
+        // Name is invalid, but not in the mask
+        from.name = "".into();
+        assert_eq!(user.update_fields(&from, &["phone".into()]).is_ok(), true); // should be ok
+        assert_eq!(user.get_user_phone(), "+36 20 765 4321");
+        assert_eq!(user.get_user_name(), "Demo User");
+        // Empty mask means every mutable field
+        assert_eq!(user.update_fields(&from, &[]).is_err(), true); // should be err
+        assert_eq!(user.update_fields(&from, &["id".into()]).is_err(), true); // should be err
+        assert_eq!(
+            user.update_fields(&from, &["created_by".into()]).is_err(),
+            true
+        ); // should be err
+        assert_eq!(user.update_fields(&from, &["wohoo".into()]).is_err(), true);
+        // should be err
+    }
+
+    #[test]
+    fn test_check_version() {
+        let user: User = User::new(
+            "demo".into(),
+            "user".into(),
+            "demo@user.com".into(),
+            "".into(),
+            "".into(),
+            None,
+        )
+        .unwrap();
+        assert_eq!(user.get_version(), 1);
+        assert_eq!(user.check_version(0).is_err(), true); // should be err
+        assert_eq!(user.check_version(1).is_ok(), true); // should be ok
+        assert_eq!(user.check_version(2).is_err(), true); // should be err
+    }
+
+    #[test]
+    fn test_user_diff() {
+        let user: User = User::new(
+            "demo".into(),
+            "Demo User".into(),
+            "demo@user.com".into(),
+            "".into(),
+            "admin".into(),
+            None,
+        )
+        .unwrap();
+        // Creation is the first history entry
+        assert_eq!(user.get_history().len(), 1);
+        assert_eq!(user.get_history()[0].actor, "admin");
+        let mut updated = user.clone();
+        updated.set_user_email("demo@company.com".into()).unwrap();
+        updated.set_password("HelloWorld749".into()).unwrap();
+        let changes = user.diff(&updated);
+        assert_eq!(changes.len(), 2);
+        assert_eq!(
+            changes[0],
+            FieldChange {
+                field: "email".into(),
+                old_value: "demo@user.com".into(),
+                new_value: "demo@company.com".into(),
+            }
+        );
+        // Hash is never stored in history
+        assert_eq!(changes[1].field, "password");
+        assert_eq!(changes[1].new_value, "***");
+        assert_eq!(user.diff(&user).len(), 0);
+    }
+
+    #[test]
+    fn test_user_roles() {
+        let mut user: User = User::new(
+            "demo".into(),
+            "user".into(),
+            "demo@user.com".into(),
+            "".into(),
+            "".into(),
+            None,
+        )
+        .unwrap();
+        assert_eq!(user.add_role("admin").is_ok(), true); // should be ok
+        assert_eq!(user.add_role("admin").is_err(), true); // should be err
+        assert_eq!(user.get_roles(), &vec!["admin".to_string()]);
+        assert_eq!(user.remove_role("user").is_err(), true); // should be err
+        assert_eq!(user.remove_role("admin").is_ok(), true); // should be ok
+        assert_eq!(user.get_roles().len(), 0);
+    }
+
+    #[test]
+    fn test_user_tokens() {
+        let mut user = User::new(
+            "demo".into(),
+            "user".into(),
+            "demo@user.com".into(),
+            "".into(),
+            "".into(),
+            None,
+        )
+        .unwrap();
+        let (token, _) = ApiKey::generate(
+            "script".into(),
+            vec!["user:read".into()],
+            None,
+            "demo".into(),
+        )
+        .unwrap();
+        let token_id = token.get_id().to_string();
+        let before = user.clone();
+        user.add_token(token);
+        assert_eq!(before.diff(&user)[0].field, "tokens");
+        assert_eq!(user.touch_token(&token_id, Utc::now()), true);
+        assert_eq!(user.revoke_token(&token_id).is_ok(), true); // should be ok
+        assert_eq!(user.get_token(&token_id).unwrap().is_active(), false);
+        assert_eq!(user.revoke_token("unknown").is_err(), true); // should be err
+    }
+
+    #[test]
+    fn test_user_invitation() {
+        let mut user = User::new(
+            "demo".into(),
+            "user".into(),
+            "demo@user.com".into(),
+            "".into(),
+            "admin".into(),
+            None,
+        )
+        .unwrap();
+        let token = user.invite("admin").unwrap();
+        assert_eq!(user.get_status(), UserStatus::Invited);
+        assert_eq!(user.is_active(), false);
+        assert_eq!(user.has_invitation(&token), true);
+        assert_eq!(user.has_invitation("wrong"), false);
+        // Renewal invalidates the previous token
+        let renewed = user.renew_invitation("admin").unwrap();
+        assert_eq!(user.has_invitation(&token), false);
+        assert_eq!(
+            user.accept_invitation(&renewed, "weak".into()).is_err(),
+            true
+        ); // should be err
+        assert_eq!(
+            user.accept_invitation(&renewed, "HelloWorld749".into())
+                .is_ok(),
+            true
+        ); // should be ok
+        assert_eq!(user.is_active(), true);
+        assert_eq!(user.has_invitation(&renewed), false);
+        assert_eq!(user.invite("admin").is_err(), true); // should be err
+    }
+
+    #[test]
+    fn test_user_registration() {
+        let new_user = || {
+            User::new(
+                "demo".into(),
+                "user".into(),
+                "demo@user.com".into(),
+                "".into(),
+                "demo".into(),
+                None,
+            )
+            .unwrap()
+        };
+        let mut user = new_user();
+        assert_eq!(user.register("weak".into()).is_err(), true); // should be err
+        let token = user.register("HelloWorld749".into()).unwrap();
+        assert_eq!(user.is_pending_verification(), true);
+        assert_eq!(user.is_active(), false);
+        assert_eq!(user.approve().is_err(), true); // should be err
+        assert_eq!(user.verify_email("wrong", false).is_err(), true); // should be err
+        assert_eq!(user.verify_email(&token, false).is_ok(), true); // should be ok
+        assert_eq!(user.verify_email(&token, false).is_err(), true); // should be err
+        assert_eq!(user.is_pending_approval(), true);
+        assert_eq!(user.is_active(), false);
+        assert_eq!(user.approve().is_ok(), true); // should be ok
+        assert_eq!(user.is_active(), true);
+        assert_eq!(user.approve().is_err(), true); // should be err
+        assert_eq!(user.reject().is_err(), true); // should be err
+        let mut rejected = new_user();
+        let token = rejected.register("HelloWorld749".into()).unwrap();
+        rejected.verify_email(&token, false).unwrap();
+        assert_eq!(rejected.reject().is_ok(), true); // should be ok
+        assert_eq!(rejected.is_deleted(), true);
+        let mut approved = new_user();
+        let token = approved.register("HelloWorld749".into()).unwrap();
+        // Renewal invalidates the previous token
+        let renewed = approved.renew_verification().unwrap();
+        assert_eq!(approved.has_verification(&token), false);
+        approved.verify_email(&renewed, true).unwrap();
+        assert_eq!(approved.is_active(), true);
+        assert_eq!(approved.renew_verification().is_err(), true); // should be err
+                                                                  // Expired
+        let mut expired = new_user();
+        let token = expired.register("HelloWorld749".into()).unwrap();
+        expired.verification.as_mut().unwrap().expires_at = Utc::now();
+        assert_eq!(expired.verify_email(&token, true).is_err(), true); // should be err
+    }
+
+    #[test]
+    fn test_user_customers() {
+        let mut user: User = User::new(
+            "demo".into(),
+            "user".into(),
+            "demo@user.com".into(),
+            "".into(),
+            "".into(),
+            Some("gardenova".into()),
+        )
+        .unwrap();
+        assert_eq!(user.get_default_customer(), Some("gardenova"));
+        assert_eq!(user.get_customers()[0].role, ROLE_USER);
+        assert_eq!(
+            user.add_customer("gardenova".into(), "admin".into())
+                .is_err(),
+            true
+        ); // should be err
+        assert_eq!(
+            user.add_customer("kertbolt".into(), "admin".into()).is_ok(),
+            true
+        ); // should be ok
+        assert_eq!(user.set_default_customer("kertbolt").is_ok(), true); // should be ok
+        assert_eq!(
+            user.set_customer_role("gardenova", "admin".into()).is_ok(),
+            true
+        ); // should be ok
+        assert_eq!(user.get_membership("gardenova").unwrap().role, "admin");
+        assert_eq!(user.remove_customer("kertbolt").is_ok(), true); // should be ok
+        assert_eq!(user.get_default_customer(), Some("gardenova"));
+        assert_eq!(user.remove_customer("kertbolt").is_err(), true); // should be err
+    }
+
+    #[test]
+    fn test_legacy_customers() {
+        let user: User = serde_yaml::from_str(
+            "id: demo\nname: Demo User\nemail: demo@user.com\nphone: \"-\"\npassword_hash: \"\"\ndate_created: \"2020-08-16T16:45:32.177904548Z\"\ncreated_by: demo\ncustomers: [gardenova]\n",
+        )
+        .unwrap();
+        assert_eq!(user.get_customer_ids(), vec!["gardenova".to_string()]);
+        assert_eq!(user.get_customers()[0].date_joined, None);
+        assert_eq!(user.get_default_customer(), Some("gardenova"));
+    }
+
+    #[test]
+    fn test_user_delete() {
+        let mut user: User = User::new(
+            "demo".into(),
+            "user".into(),
+            "demo@user.com".into(),
+            "".into(),
+            "".into(),
+            None,
+        )
+        .unwrap();
+        assert_eq!(user.get_status(), UserStatus::Active);
+        assert_eq!(user.delete().is_ok(), true); // should be ok
+        assert_eq!(user.is_deleted(), true);
+        assert_eq!(user.delete().is_err(), true); // should be err
+    }
+
+    #[test]
+    fn test_change_password() {
+        let mut user: User = User::new(
+            "demo".into(),
+            "user".into(),
+            "demo@user.com".into(),
+            "".into(),
+            "demo".into(),
+            None,
+        )
+        .unwrap();
+        // No password yet, nothing to prove
+        assert_eq!(
+            user.change_password("", "HelloWorld749".into()).is_err(),
+            true
+        ); // should be err
+        user.set_password("HelloWorld749".into()).unwrap();
+        assert_eq!(
+            user.change_password("wrong", "HelloWorld750".into())
+                .is_err(),
+            true
+        ); // should be err
+        assert_eq!(
+            user.change_password("HelloWorld749", "HelloWorld750".into())
+                .is_ok(),
+            true
+        ); // should be ok
+        assert_eq!(
+            verify_password_from_hash("HelloWorld750", user.get_password_hash()).unwrap(),
+            true
+        );
+    }
+
+    #[test]
+    fn test_reset_password() {
+        let mut user: User = User::new(
+            "demo".into(),
+            "user".into(),
+            "demo@user.com".into(),
+            "".into(),
+            "demo".into(),
+            None,
+        )
+        .unwrap();
+        user.set_password("HelloWorld749".into()).unwrap();
+        let token = user.request_password_reset().unwrap();
+        // Requesting changes nothing yet
+        assert_eq!(
+            verify_password_from_hash("HelloWorld749", user.get_password_hash()).unwrap(),
+            true
+        );
+        assert_eq!(
+            user.reset_password("wrong", "HelloWorld750".into())
+                .is_err(),
+            true
+        ); // should be err
+           // A new request invalidates the previous token
+        let renewed = user.request_password_reset().unwrap();
+        assert_eq!(user.has_password_reset(&token), false);
+        assert_eq!(
+            user.reset_password(&renewed, "HelloWorld750".into())
+                .is_ok(),
+            true
+        ); // should be ok
+        assert_eq!(
+            verify_password_from_hash("HelloWorld750", user.get_password_hash()).unwrap(),
+            true
+        );
+        assert_eq!(
+            verify_password_from_hash("HelloWorld749", user.get_password_hash()).unwrap(),
+            false
+        );
+        // Used once only
+        assert_eq!(
+            user.reset_password(&renewed, "HelloWorld751".into())
+                .is_err(),
+            true
+        ); // should be err
+
+        let token = user.request_password_reset().unwrap();
+        user.password_reset.as_mut().unwrap().expires_at = Utc::now();
+        assert_eq!(
+            user.reset_password(&token, "HelloWorld751".into()).is_err(),
+            true
+        ); // should be err
+    }
+
+    #[test]
+    fn test_validate() {
+        let user: User = User::new(
+            "demo".into(),
+            "user".into(),
+            "demo@user.com".into(),
+            "".into(),
+            "demo".into(),
+            Some("c1".into()),
+        )
+        .unwrap();
+        assert_eq!(user.validate().is_ok(), true); // should be ok
+        let mut broken = user.clone();
+        broken.id = "Demo".into();
+        assert_eq!(broken.validate().is_err(), true); // should be err
+        let mut broken = user.clone();
+        broken.email = "demo".into();
+        assert_eq!(broken.validate().is_err(), true); // should be err
+        let mut broken = user.clone();
+        broken.default_customer = Some("c2".into());
+        assert_eq!(broken.validate().is_err(), true); // should be err
+        let mut broken = user;
+        broken.customers.push(broken.customers[0].clone());
+        assert_eq!(broken.validate().is_err(), true); // should be err
+    }
 }
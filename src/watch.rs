@@ -0,0 +1,200 @@
+// Copyright (C) 2020 Peter Mezei
+//
+// This file is part of Gardenzilla.
+//
+// Gardenzilla is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 2 of the License, or
+// (at your option) any later version.
+//
+// Gardenzilla is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
+
+use crate::prelude::ServiceError::*;
+use crate::prelude::*;
+use crate::user::User;
+use chrono::prelude::*;
+use rand::Rng;
+use std::collections::VecDeque;
+use tokio::sync::broadcast;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChangeKind {
+    Created,
+    Updated,
+    Deleted,
+}
+
+#[derive(Clone, Debug)]
+pub struct ChangeEvent {
+    pub epoch: String,
+    pub revision: u64,
+    pub kind: ChangeKind,
+    // User state after the change
+    pub user: User,
+    pub date_created: DateTime<Utc>,
+}
+
+/// # Change log
+/// Keeps the last `capacity` user change events in memory
+/// and broadcasts every new event to the watchers.
+/// Revisions start from 1 and are increased by every change.
+/// Revisions are only comparable within the same epoch, a new
+/// epoch is started by every service start.
+pub struct ChangeLog {
+    epoch: String,
+    revision: u64,
+    capacity: usize,
+    events: VecDeque<ChangeEvent>,
+    sender: broadcast::Sender<ChangeEvent>,
+}
+
+impl ChangeLog {
+    pub fn new(capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(capacity);
+        Self {
+            epoch: hex::encode(rand::thread_rng().gen::<[u8; 8]>()),
+            revision: 0,
+            capacity,
+            events: VecDeque::with_capacity(capacity),
+            sender,
+        }
+    }
+    /// Epoch the revisions belong to
+    pub fn get_epoch(&self) -> &str {
+        &self.epoch
+    }
+    /// Last published revision
+    pub fn get_revision(&self) -> u64 {
+        self.revision
+    }
+    /// Record a change and notify the watchers.
+    /// Returns the revision of the new event.
+    pub fn publish(&mut self, kind: ChangeKind, user: &User) -> u64 {
+        self.revision += 1;
+        let event = ChangeEvent {
+            epoch: self.epoch.clone(),
+            revision: self.revision,
+            kind,
+            user: user.clone(),
+            date_created: Utc::now(),
+        };
+        if self.events.len() == self.capacity {
+            self.events.pop_front();
+        }
+        self.events.push_back(event.clone());
+        // Error only means there is no watcher at the moment
+        let _ = self.sender.send(event);
+        self.revision
+    }
+    /// # Subscribe
+    /// Returns the retained events after `from_revision`, and a receiver
+    /// for the upcoming ones. As both are taken at the same time,
+    /// no event can be missed between them.
+    /// Resuming needs the epoch of the revision; after a restart the
+    /// changes made meanwhile are unknown, so the client must reload.
+    pub fn subscribe(
+        &self,
+        epoch: &str,
+        from_revision: u64,
+    ) -> ServiceResult<(Vec<ChangeEvent>, broadcast::Receiver<ChangeEvent>)> {
+        if from_revision > 0 && epoch != self.epoch {
+            return Err(FailedPrecondition(format!(
+                "Epoch {:?} is unknown, the service restarted since. Reload users and watch from there.",
+                epoch
+            )));
+        }
+        if from_revision > self.revision {
+            return Err(FailedPrecondition(format!(
+                "Revision {} is unknown, the latest revision is {}. Reload users and watch from there.",
+                from_revision, self.revision
+            )));
+        }
+        let oldest = self
+            .events
+            .front()
+            .map(|event| event.revision)
+            .unwrap_or(self.revision + 1);
+        if from_revision + 1 < oldest {
+            return Err(FailedPrecondition(format!(
+                "Revision {} is expired, the oldest retained revision is {}. Reload users and watch from there.",
+                from_revision, oldest
+            )));
+        }
+        let backlog = self
+            .events
+            .iter()
+            .filter(|event| event.revision > from_revision)
+            .cloned()
+            .collect();
+        Ok((backlog, self.sender.subscribe()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user() -> User {
+        User::new(
+            "demo".into(),
+            "user".into(),
+            "demo@user.com".into(),
+            "".into(),
+            "".into(),
+            None,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_revision() {
+        let mut log = ChangeLog::new(10);
+        assert_eq!(log.get_revision(), 0);
+        assert_eq!(log.publish(ChangeKind::Created, &user()), 1);
+        assert_eq!(log.publish(ChangeKind::Updated, &user()), 2);
+        assert_eq!(log.get_revision(), 2);
+    }
+
+    #[test]
+    fn test_resume() {
+        let mut log = ChangeLog::new(3);
+        for _ in 0..5 {
+            log.publish(ChangeKind::Updated, &user());
+        }
+        let epoch = log.get_epoch().to_string();
+        // Revisions 3, 4 and 5 are retained
+        let (backlog, _) = log.subscribe(&epoch, 3).unwrap();
+        assert_eq!(
+            backlog.iter().map(|e| e.revision).collect::<Vec<u64>>(),
+            vec![4, 5]
+        );
+        assert_eq!(log.subscribe(&epoch, 2).unwrap().0.len(), 3);
+        assert_eq!(log.subscribe(&epoch, 5).unwrap().0.len(), 0);
+        // Expired
+        assert_eq!(log.subscribe(&epoch, 1).is_err(), true);
+        // Unknown revision
+        assert_eq!(log.subscribe(&epoch, 6).is_err(), true);
+        // Revision of an earlier service start
+        assert_eq!(log.subscribe("", 3).is_err(), true);
+        let restarted = ChangeLog::new(3);
+        assert_eq!(restarted.subscribe(&epoch, 0).is_ok(), true); // should be ok
+        assert_eq!(restarted.subscribe(&epoch, 3).is_err(), true); // should be err
+    }
+
+    #[test]
+    fn test_broadcast() {
+        let mut log = ChangeLog::new(3);
+        let (_, mut receiver) = log.subscribe("", 0).unwrap();
+        log.publish(ChangeKind::Deleted, &user());
+        let event = receiver.try_recv().unwrap();
+        assert_eq!(event.revision, 1);
+        assert_eq!(event.epoch, log.get_epoch());
+        assert_eq!(event.kind, ChangeKind::Deleted);
+    }
+}
@@ -0,0 +1,735 @@
+// Copyright (C) 2020 Peter Mezei
+//
+// This file is part of Gardenzilla.
+//
+// Gardenzilla is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 2 of the License, or
+// (at your option) any later version.
+//
+// Gardenzilla is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Gardenzilla.  If not, see <http://www.gnu.org/licenses/>.
+
+use crate::auth::generate_token;
+use crate::outbox::{retry_delay, MAX_ATTEMPTS};
+use crate::prelude::ServiceError::*;
+use crate::prelude::*;
+use chrono::prelude::*;
+use hmac::{Hmac, Mac, NewMac};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::sync::{Arc, Mutex};
+use storaget::*;
+
+pub const USER_CREATED: &str = "user.created";
+pub const USER_UPDATED: &str = "user.updated";
+pub const USER_DELETED: &str = "user.deleted";
+pub const PASSWORD_CHANGED: &str = "password.changed";
+// Events a webhook can subscribe to
+pub const EVENTS: &[&str] = &[USER_CREATED, USER_UPDATED, USER_DELETED, PASSWORD_CHANGED];
+
+// Request headers of a delivery
+pub const EVENT_HEADER: &str = "x-gardenzilla-event";
+pub const DELIVERY_HEADER: &str = "x-gardenzilla-delivery";
+pub const TIMESTAMP_HEADER: &str = "x-gardenzilla-timestamp";
+// sha256=<hex HMAC of "<timestamp>.<body>" keyed by the webhook secret>
+pub const SIGNATURE_HEADER: &str = "x-gardenzilla-signature";
+// Timeout of a delivery request, in seconds
+const DELIVERY_TIMEOUT_SECONDS: u64 = 10;
+// How often the delivery task looks for due deliveries, in seconds
+const POLL_INTERVAL_SECONDS: u64 = 5;
+// Deliveries sent to one endpoint in one round at most
+const MAX_DELIVERIES_PER_ROUND: usize = 20;
+
+/// # Webhook
+/// HTTP endpoint notified about user lifecycle events.
+/// The secret is kept, as every delivery is signed by it;
+/// it is shown to the admin only once, at creation.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Webhook {
+    id: String,
+    url: String,
+    events: Vec<String>,
+    secret: String,
+    date_created: DateTime<Utc>,
+    created_by: String,
+    // Deleted webhooks are kept for their delivery log
+    deleted: bool,
+}
+
+impl Default for Webhook {
+    fn default() -> Self {
+        Webhook {
+            id: String::default(),
+            url: String::default(),
+            events: Vec::new(),
+            secret: String::default(),
+            date_created: Utc::now(),
+            created_by: String::default(),
+            deleted: false,
+        }
+    }
+}
+
+impl TryFrom for Webhook {
+    type TryFrom = Webhook;
+}
+
+impl VecPackMember for Webhook {
+    fn get_id(&self) -> &str {
+        &self.id
+    }
+}
+
+impl Webhook {
+    pub fn new(url: String, mut events: Vec<String>, created_by: String) -> ServiceResult<Self> {
+        let url = url.trim().to_string();
+        if !(url.starts_with("http://") || url.starts_with("https://"))
+            || url.parse::<hyper::Uri>().is_err()
+        {
+            return Err(BadRequest(
+                "A webhook címe http:// vagy https:// kezdetű URL kell legyen".into(),
+            ));
+        }
+        if events.is_empty() {
+            return Err(BadRequest("A webhooknak legalább egy esemény kell".into()));
+        }
+        if let Some(event) = events.iter().find(|e| !EVENTS.contains(&e.as_str())) {
+            return Err(BadRequest(format!(
+                "Ismeretlen esemény: {}. Lehetséges: {}",
+                event,
+                EVENTS.join(", ")
+            )));
+        }
+        events.sort();
+        events.dedup();
+        Ok(Webhook {
+            id: hex::encode(rand::thread_rng().gen::<[u8; 8]>()),
+            url,
+            events,
+            secret: generate_token(),
+            date_created: Utc::now(),
+            created_by,
+            deleted: false,
+        })
+    }
+    pub fn get_id(&self) -> &str {
+        &self.id
+    }
+    pub fn get_url(&self) -> &str {
+        &self.url
+    }
+    pub fn get_events(&self) -> &Vec<String> {
+        &self.events
+    }
+    pub fn get_secret(&self) -> &str {
+        &self.secret
+    }
+    pub fn get_date_created(&self) -> DateTime<Utc> {
+        self.date_created
+    }
+    pub fn get_created_by(&self) -> &str {
+        &self.created_by
+    }
+    pub fn is_deleted(&self) -> bool {
+        self.deleted
+    }
+    pub fn subscribes(&self, event: &str) -> bool {
+        !self.deleted && self.events.iter().any(|e| e == event)
+    }
+    pub fn delete(&mut self) -> ServiceResult<()> {
+        if self.deleted {
+            return Err(NotFound("A webhook már törölve lett".into()));
+        }
+        self.deleted = true;
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum DeliveryStatus {
+    Pending,
+    Delivered,
+    // Gave up after MAX_ATTEMPTS
+    Failed,
+}
+
+/// # Delivery
+/// One event sent to one webhook, with its attempts
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Delivery {
+    id: String,
+    webhook_id: String,
+    event: String,
+    // JSON body sent
+    payload: String,
+    status: DeliveryStatus,
+    // Failed attempts
+    attempts: u32,
+    date_created: DateTime<Utc>,
+    next_attempt_at: DateTime<Utc>,
+    delivered_at: Option<DateTime<Utc>>,
+    // HTTP status of the last attempt, if any response arrived
+    last_status_code: Option<u16>,
+    last_error: Option<String>,
+}
+
+impl Default for Delivery {
+    fn default() -> Self {
+        Delivery {
+            id: String::default(),
+            webhook_id: String::default(),
+            event: String::default(),
+            payload: String::default(),
+            status: DeliveryStatus::Pending,
+            attempts: 0,
+            date_created: Utc::now(),
+            next_attempt_at: Utc::now(),
+            delivered_at: None,
+            last_status_code: None,
+            last_error: None,
+        }
+    }
+}
+
+impl TryFrom for Delivery {
+    type TryFrom = Delivery;
+}
+
+impl VecPackMember for Delivery {
+    fn get_id(&self) -> &str {
+        &self.id
+    }
+}
+
+impl Delivery {
+    pub fn new(webhook_id: &str, event: &str, data: serde_json::Value) -> Self {
+        let id = hex::encode(rand::thread_rng().gen::<[u8; 8]>());
+        let now = Utc::now();
+        let payload = serde_json::json!({
+            "id": id,
+            "event": event,
+            "created_at": now.to_rfc3339(),
+            "data": data,
+        })
+        .to_string();
+        Delivery {
+            id,
+            webhook_id: webhook_id.to_string(),
+            event: event.to_string(),
+            payload,
+            status: DeliveryStatus::Pending,
+            attempts: 0,
+            date_created: now,
+            next_attempt_at: now,
+            delivered_at: None,
+            last_status_code: None,
+            last_error: None,
+        }
+    }
+    pub fn get_id(&self) -> &str {
+        &self.id
+    }
+    pub fn get_webhook_id(&self) -> &str {
+        &self.webhook_id
+    }
+    pub fn get_event(&self) -> &str {
+        &self.event
+    }
+    pub fn get_payload(&self) -> &str {
+        &self.payload
+    }
+    pub fn get_status(&self) -> DeliveryStatus {
+        self.status
+    }
+    pub fn get_attempts(&self) -> u32 {
+        self.attempts
+    }
+    pub fn get_date_created(&self) -> DateTime<Utc> {
+        self.date_created
+    }
+    pub fn get_next_attempt_at(&self) -> DateTime<Utc> {
+        self.next_attempt_at
+    }
+    pub fn get_delivered_at(&self) -> Option<DateTime<Utc>> {
+        self.delivered_at
+    }
+    pub fn get_last_status_code(&self) -> Option<u16> {
+        self.last_status_code
+    }
+    pub fn get_last_error(&self) -> Option<&str> {
+        self.last_error.as_deref()
+    }
+    pub fn is_due(&self, now: DateTime<Utc>) -> bool {
+        self.status == DeliveryStatus::Pending && self.next_attempt_at <= now
+    }
+    pub fn delivered(&mut self, status_code: u16, now: DateTime<Utc>) {
+        self.status = DeliveryStatus::Delivered;
+        self.delivered_at = Some(now);
+        self.last_status_code = Some(status_code);
+        self.last_error = None;
+    }
+    /// Schedule the next attempt, or give up after MAX_ATTEMPTS
+    pub fn failed(&mut self, status_code: Option<u16>, error: String, now: DateTime<Utc>) {
+        self.attempts += 1;
+        self.last_status_code = status_code;
+        self.last_error = Some(error);
+        if self.attempts >= MAX_ATTEMPTS {
+            self.status = DeliveryStatus::Failed;
+        } else {
+            self.next_attempt_at = now + retry_delay(self.attempts);
+        }
+    }
+}
+
+/// Hex HMAC-SHA256 of the message
+pub fn hmac_hex(secret: &str, message: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_varkey(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(message.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// Signature header value of a delivery body sent at the timestamp
+pub fn sign(secret: &str, timestamp: i64, body: &str) -> String {
+    format!(
+        "sha256={}",
+        hmac_hex(secret, &format!("{}.{}", timestamp, body))
+    )
+}
+
+// (delivery ID, event, url, secret, payload) of a due delivery
+type DueDelivery = (String, String, String, String, String);
+
+/// # Webhooks
+/// Registered webhooks and their delivery log.
+/// Deliveries are stored when the event happens,
+/// and sent by a background task. Storage is only
+/// touched from the blocking thread pool.
+pub struct Webhooks {
+    hooks: Arc<Mutex<VecPack<Webhook>>>,
+    deliveries: Arc<Mutex<VecPack<Delivery>>>,
+    client: hyper::Client<hyper_rustls::HttpsConnector<hyper::client::HttpConnector>>,
+}
+
+impl Webhooks {
+    pub fn new(hooks: VecPack<Webhook>, deliveries: VecPack<Delivery>) -> Self {
+        Self {
+            hooks: Arc::new(Mutex::new(hooks)),
+            deliveries: Arc::new(Mutex::new(deliveries)),
+            client: hyper::Client::builder().build(hyper_rustls::HttpsConnector::new()),
+        }
+    }
+    pub async fn create(&self, webhook: Webhook) -> ServiceResult<()> {
+        blocking(&self.hooks, move |hooks| {
+            hooks.insert(webhook)?;
+            Ok(())
+        })
+        .await
+    }
+    /// Webhooks not deleted
+    pub async fn list(&self) -> ServiceResult<Vec<Webhook>> {
+        blocking(&self.hooks, |hooks| {
+            Ok(hooks
+                .into_iter()
+                .filter(|h: &&mut Pack<Webhook>| !h.unpack().is_deleted())
+                .map(|h: &mut Pack<Webhook>| h.unpack().clone())
+                .collect())
+        })
+        .await
+    }
+    pub async fn delete(&self, id: &str) -> ServiceResult<()> {
+        let id = id.to_string();
+        blocking(&self.hooks, move |hooks| {
+            let hook = hooks
+                .find_id_mut(&id)
+                .map_err(|_| ServiceError::not_found("Webhook not found"))?;
+            let mut deleted = hook.unpack().clone();
+            deleted.delete()?;
+            hook.update(|h| *h = deleted.clone())?;
+            Ok(())
+        })
+        .await
+    }
+    /// Store a delivery of the event for every subscribed webhook
+    pub async fn emit(&self, event: &str, data: serde_json::Value) -> ServiceResult<()> {
+        let event = event.to_string();
+        let deliveries = self.deliveries.clone();
+        blocking(&self.hooks, move |hooks| {
+            let webhook_ids = hooks
+                .into_iter()
+                .filter(|h: &&mut Pack<Webhook>| h.unpack().subscribes(&event))
+                .map(|h: &mut Pack<Webhook>| h.unpack().get_id().to_string())
+                .collect::<Vec<String>>();
+            let mut deliveries = deliveries
+                .lock()
+                .map_err(|_| ServiceError::internal_error("Lock error"))?;
+            for webhook_id in webhook_ids {
+                deliveries.insert(Delivery::new(&webhook_id, &event, data.clone()))?;
+            }
+            Ok(())
+        })
+        .await
+    }
+    /// Delivery log of the webhook, or of every webhook
+    /// if empty, latest first
+    pub async fn deliveries(&self, webhook_id: &str, limit: usize) -> ServiceResult<Vec<Delivery>> {
+        let webhook_id = webhook_id.to_string();
+        let mut deliveries = blocking(&self.deliveries, move |deliveries| {
+            Ok(deliveries
+                .into_iter()
+                .filter(|d: &&mut Pack<Delivery>| {
+                    webhook_id.is_empty() || d.unpack().get_webhook_id() == webhook_id
+                })
+                .map(|d: &mut Pack<Delivery>| d.unpack().clone())
+                .collect::<Vec<Delivery>>())
+        })
+        .await?;
+        deliveries.sort_by_key(|d| std::cmp::Reverse(d.get_date_created()));
+        deliveries.truncate(limit);
+        Ok(deliveries)
+    }
+    async fn due(&self, now: DateTime<Utc>) -> ServiceResult<Vec<DueDelivery>> {
+        let deliveries = self.deliveries.clone();
+        blocking(&self.hooks, move |hooks| {
+            let mut deliveries = deliveries
+                .lock()
+                .map_err(|_| ServiceError::internal_error("Lock error"))?;
+            let mut due = Vec::new();
+            for delivery in deliveries.into_iter() {
+                let delivery = delivery.unpack();
+                if !delivery.is_due(now) {
+                    continue;
+                }
+                // Deliveries of a deleted webhook are still sent,
+                // as the event happened while it was registered
+                if let Ok(hook) = hooks.find_id(delivery.get_webhook_id()) {
+                    due.push((
+                        delivery.get_id().to_string(),
+                        delivery.get_event().to_string(),
+                        hook.unpack().get_url().to_string(),
+                        hook.unpack().get_secret().to_string(),
+                        delivery.get_payload().to_string(),
+                    ));
+                }
+            }
+            Ok(due)
+        })
+        .await
+    }
+    // POST the payload, returns the response status
+    async fn post(
+        &self,
+        (id, event, url, secret, payload): DueDelivery,
+    ) -> Result<u16, (Option<u16>, String)> {
+        let timestamp = Utc::now().timestamp();
+        let request = hyper::Request::post(url)
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .header(EVENT_HEADER, event)
+            .header(DELIVERY_HEADER, id)
+            .header(TIMESTAMP_HEADER, timestamp)
+            .header(SIGNATURE_HEADER, sign(&secret, timestamp, &payload))
+            .body(hyper::Body::from(payload))
+            .map_err(|e| (None, e.to_string()))?;
+        let response = tokio::time::timeout(
+            std::time::Duration::from_secs(DELIVERY_TIMEOUT_SECONDS),
+            self.client.request(request),
+        )
+        .await
+        .map_err(|_| (None, "Timeout".to_string()))?
+        .map_err(|e| (None, e.to_string()))?;
+        let status = response.status();
+        match status.is_success() {
+            true => Ok(status.as_u16()),
+            false => Err((Some(status.as_u16()), format!("HTTP {}", status))),
+        }
+    }
+    /// # Deliver due
+    /// Try the due deliveries once, returns the number of
+    /// successful ones. Locks are not held while sending.
+    /// Retries are scheduled from now.
+    /// Endpoints are sent to at the same time, so a slow or dead
+    /// one does not hold up the others. The deliveries of an endpoint
+    /// are sent one by one, and its round ends at the first failure
+    /// or after MAX_DELIVERIES_PER_ROUND; the rest is left for the
+    /// next round.
+    pub async fn deliver_due(&self, now: DateTime<Utc>) -> ServiceResult<usize> {
+        let mut by_endpoint: Vec<(String, Vec<DueDelivery>)> = Vec::new();
+        for due in self.due(now).await? {
+            match by_endpoint.iter_mut().find(|(url, _)| *url == due.2) {
+                Some((_, queue)) => queue.push(due),
+                None => by_endpoint.push((due.2.clone(), vec![due])),
+            }
+        }
+        futures::future::join_all(
+            by_endpoint
+                .into_iter()
+                .map(|(_, queue)| self.deliver_to_endpoint(queue, now)),
+        )
+        .await
+        .into_iter()
+        .sum()
+    }
+    async fn deliver_to_endpoint(
+        &self,
+        queue: Vec<DueDelivery>,
+        now: DateTime<Utc>,
+    ) -> ServiceResult<usize> {
+        let mut delivered = 0;
+        for due in queue.into_iter().take(MAX_DELIVERIES_PER_ROUND) {
+            let id = due.0.clone();
+            let result = self.post(due).await;
+            let is_delivered = result.is_ok();
+            blocking(&self.deliveries, move |deliveries| {
+                let stored = deliveries
+                    .find_id_mut(&id)
+                    .map_err(|_| ServiceError::not_found("Delivery not found"))?;
+                match result {
+                    Ok(status_code) => stored.update(|d| d.delivered(status_code, now))?,
+                    Err((status_code, error)) => {
+                        stored.update(|d| d.failed(status_code, error.clone(), now))?
+                    }
+                }
+                Ok(())
+            })
+            .await?;
+            if !is_delivered {
+                break;
+            }
+            delivered += 1;
+        }
+        Ok(delivered)
+    }
+}
+
+/// # Run
+/// Background delivery task, runs till the process exits
+pub async fn run(webhooks: Arc<Webhooks>) {
+    loop {
+        if let Err(err) = webhooks.deliver_due(Utc::now()).await {
+            eprintln!("Error while delivering webhooks: {}", err);
+        }
+        tokio::time::delay_for(std::time::Duration::from_secs(POLL_INTERVAL_SECONDS)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::path::PathBuf;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "user_microservice_{}_{}",
+            name,
+            hex::encode(rand::thread_rng().gen::<[u8; 4]>())
+        ))
+    }
+
+    fn webhooks() -> (Webhooks, Vec<PathBuf>) {
+        let dirs = vec![temp_dir("webhooks"), temp_dir("deliveries")];
+        let webhooks = Webhooks::new(
+            VecPack::try_load_or_init(dirs[0].clone()).unwrap(),
+            VecPack::try_load_or_init(dirs[1].clone()).unwrap(),
+        );
+        (webhooks, dirs)
+    }
+
+    // Received headers (lowercase names) and body
+    type Received = (Vec<(String, String)>, String);
+
+    // Local HTTP stand-in answering one request with the status line,
+    // returns what it has received
+    fn http_server(status_line: &'static str) -> (String, std::thread::JoinHandle<Received>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let handle = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut headers = Vec::new();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let line = line.trim_end();
+                if line.is_empty() {
+                    break;
+                }
+                if let Some(index) = line.find(':') {
+                    headers.push((
+                        line[..index].to_lowercase(),
+                        line[index + 1..].trim().to_string(),
+                    ));
+                }
+            }
+            let length = headers
+                .iter()
+                .find(|(name, _)| name == "content-length")
+                .map(|(_, value)| value.parse::<usize>().unwrap())
+                .unwrap_or(0);
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            let mut writer = stream;
+            write!(
+                writer,
+                "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                status_line
+            )
+            .unwrap();
+            (headers, String::from_utf8(body).unwrap())
+        });
+        (url, handle)
+    }
+
+    #[test]
+    fn test_hmac() {
+        // RFC 4231 style known answer
+        assert_eq!(
+            hmac_hex("key", "The quick brown fox jumps over the lazy dog"),
+            "f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8"
+        );
+    }
+
+    #[test]
+    fn test_new_webhook() {
+        let events = vec![USER_CREATED.to_string()];
+        assert_eq!(
+            Webhook::new("ftp://host".into(), events.clone(), "".into()).is_err(),
+            true
+        ); // should be err
+        assert_eq!(
+            Webhook::new("http://host".into(), vec![], "".into()).is_err(),
+            true
+        ); // should be err
+        assert_eq!(
+            Webhook::new("http://host".into(), vec!["user.read".into()], "".into()).is_err(),
+            true
+        ); // should be err
+        let mut hook = Webhook::new("http://host/hook".into(), events, "admin".into()).unwrap();
+        assert_eq!(hook.subscribes(USER_CREATED), true);
+        assert_eq!(hook.subscribes(USER_DELETED), false);
+        assert_eq!(hook.delete().is_ok(), true); // should be ok
+        assert_eq!(hook.subscribes(USER_CREATED), false);
+    }
+
+    #[tokio::test]
+    async fn test_signed_delivery() {
+        let (url, handle) = http_server("200 OK");
+        let (webhooks, dirs) = webhooks();
+        let hook = Webhook::new(url, vec![USER_CREATED.into()], "admin".into()).unwrap();
+        let secret = hook.get_secret().to_string();
+        webhooks.create(hook).await.unwrap();
+        webhooks
+            .emit(USER_UPDATED, serde_json::json!({"user_id": "demo"}))
+            .await
+            .unwrap();
+        webhooks
+            .emit(USER_CREATED, serde_json::json!({"user_id": "demo"}))
+            .await
+            .unwrap();
+        // Only the subscribed event is delivered
+        assert_eq!(webhooks.deliveries("", 10).await.unwrap().len(), 1);
+        assert_eq!(webhooks.deliver_due(Utc::now()).await.unwrap(), 1);
+        let (headers, body) = handle.join().unwrap();
+        let header = |name: &str| {
+            headers
+                .iter()
+                .find(|(n, _)| n == name)
+                .map(|(_, v)| v.clone())
+                .unwrap()
+        };
+        let timestamp = header(TIMESTAMP_HEADER).parse::<i64>().unwrap();
+        assert_eq!(header(SIGNATURE_HEADER), sign(&secret, timestamp, &body));
+        assert_eq!(header(EVENT_HEADER), USER_CREATED);
+        assert_eq!(body.contains("\"user_id\":\"demo\""), true);
+        let delivery = webhooks.deliveries("", 10).await.unwrap().remove(0);
+        assert_eq!(delivery.get_status(), DeliveryStatus::Delivered);
+        assert_eq!(delivery.get_last_status_code(), Some(200));
+        for dir in dirs {
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failed_delivery() {
+        let (url, handle) = http_server("500 Internal Server Error");
+        let (webhooks, dirs) = webhooks();
+        let hook = Webhook::new(url, vec![USER_DELETED.into()], "admin".into()).unwrap();
+        let webhook_id = hook.get_id().to_string();
+        webhooks.create(hook).await.unwrap();
+        webhooks
+            .emit(USER_DELETED, serde_json::json!({"user_id": "demo"}))
+            .await
+            .unwrap();
+        let now = Utc::now();
+        assert_eq!(webhooks.deliver_due(now).await.unwrap(), 0);
+        handle.join().unwrap();
+        let delivery = webhooks
+            .deliveries(&webhook_id, 10)
+            .await
+            .unwrap()
+            .remove(0);
+        assert_eq!(delivery.get_status(), DeliveryStatus::Pending);
+        assert_eq!(delivery.get_attempts(), 1);
+        assert_eq!(delivery.get_last_status_code(), Some(500));
+        // Retried only after the backoff
+        assert_eq!(delivery.get_next_attempt_at(), now + retry_delay(1));
+        for dir in dirs {
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failing_endpoint() {
+        let (failing_url, failing) = http_server("500 Internal Server Error");
+        let (url, handle) = http_server("200 OK");
+        let (webhooks, dirs) = webhooks();
+        let failing_hook =
+            Webhook::new(failing_url, vec![USER_DELETED.into()], "admin".into()).unwrap();
+        let failing_id = failing_hook.get_id().to_string();
+        webhooks.create(failing_hook).await.unwrap();
+        let hook = Webhook::new(url, vec![USER_CREATED.into()], "admin".into()).unwrap();
+        let webhook_id = hook.get_id().to_string();
+        webhooks.create(hook).await.unwrap();
+        for user_id in &["demo", "other"] {
+            webhooks
+                .emit(USER_DELETED, serde_json::json!({ "user_id": user_id }))
+                .await
+                .unwrap();
+        }
+        webhooks
+            .emit(USER_CREATED, serde_json::json!({"user_id": "demo"}))
+            .await
+            .unwrap();
+        // The other endpoint is delivered to, the failing one
+        // is not tried again in the same round
+        assert_eq!(webhooks.deliver_due(Utc::now()).await.unwrap(), 1);
+        failing.join().unwrap();
+        handle.join().unwrap();
+        let delivery = webhooks
+            .deliveries(&webhook_id, 10)
+            .await
+            .unwrap()
+            .remove(0);
+        assert_eq!(delivery.get_status(), DeliveryStatus::Delivered);
+        let mut attempts = webhooks
+            .deliveries(&failing_id, 10)
+            .await
+            .unwrap()
+            .iter()
+            .map(|d| d.get_attempts())
+            .collect::<Vec<u32>>();
+        attempts.sort();
+        assert_eq!(attempts, vec![0, 1]);
+        for dir in dirs {
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+    }
+}